@@ -1,14 +1,21 @@
-#![feature(async_closure)]
-
-use actix_web::{web, App, HttpResponse, HttpServer, Responder, middleware};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder, middleware};
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use actix_web::http::header::{ContentType, HeaderValue};
+use actix_web::web::Bytes;
+use futures::stream;
 use clap::Parser;
 use std::path::PathBuf;
 use log::{error, info};
 use loki_federation_core::federated_loki::{Direction, FederatedLoki};
-use loki_federation_core::config::Config;
+use loki_federation_core::config::{Config, PartialResponseStrategy};
 use loki_federation_core::datasources_provider::DataSourcesProvider;
+use loki_federation_core::health::BackendsHealth;
 use serde::{Deserialize, Serialize};
 use display_json::{DisplayAsJson};
+use generic_loki_client::{ErrorResponse, LokiError, Response};
+
+mod websocket;
 
 #[derive(Serialize, Deserialize, Debug, DisplayAsJson)]
 struct Query {
@@ -16,6 +23,9 @@ struct Query {
     limit: Option<i32>,
     time: Option<i64>,
     direction: Option<Direction>,
+    timeout: Option<String>,
+    dedup: Option<bool>,
+    partial_response_strategy: Option<PartialResponseStrategy>,
 }
 
 #[derive(Serialize, Deserialize, Debug, DisplayAsJson)]
@@ -26,13 +36,19 @@ struct QueryRange {
     limit: Option<i32>,
     direction: Option<Direction>,
     step: Option<String>,
-    interval: Option<String>
+    interval: Option<String>,
+    timeout: Option<String>,
+    dedup: Option<bool>,
+    partial_response_strategy: Option<PartialResponseStrategy>,
 }
 
 #[derive(Serialize, Deserialize, Debug, DisplayAsJson)]
 struct Labels {
     start: Option<i64>,
     end: Option<i64>,
+    timeout: Option<String>,
+    dedup: Option<bool>,
+    partial_response_strategy: Option<PartialResponseStrategy>,
 }
 
 #[derive(Serialize, Deserialize, Debug, DisplayAsJson)]
@@ -40,6 +56,19 @@ struct Series {
     matches: Option<Vec<String>>,
     start: Option<i64>,
     end: Option<i64>,
+    timeout: Option<String>,
+    dedup: Option<bool>,
+    partial_response_strategy: Option<PartialResponseStrategy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, DisplayAsJson)]
+struct Tail {
+    query: String,
+    delay_for: Option<u32>,
+    limit: Option<i32>,
+    start: Option<i64>,
+    dedup: Option<bool>,
+    partial_response_strategy: Option<PartialResponseStrategy>,
 }
 
 #[derive(Serialize, Deserialize, Debug, DisplayAsJson)]
@@ -56,74 +85,121 @@ struct Args {
 }
 
 
+const WARNINGS_HEADER: &str = "X-Loki-Federation-Warnings";
+
 struct AppState {
     federated_loki: FederatedLoki,
+    backends_health: BackendsHealth,
+}
+
+/// Failures of backends are reported in a header as well, so they are visible without parsing the body
+fn success_response(warnings: &[String]) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    if !warnings.is_empty() {
+        //header values can't hold control characters
+        let warnings = warnings.join("; ").replace(|c: char| c.is_control(), " ");
+        if let Ok(value) = HeaderValue::from_str(&warnings) {
+            response.insert_header((WARNINGS_HEADER, value));
+        }
+    }
+    response
+}
+
+/// Results are serialized one at a time as the body is sent, the response is never held serialized in memory
+fn streaming_response(response: Response) -> HttpResponse {
+    success_response(&response.warnings)
+        .content_type(ContentType::json())
+        .streaming(stream::iter(response.into_json_chunks().map(|chunk| chunk.map(Bytes::from))))
+}
+
+/// Answer an error with the status code and the error envelope of Loki
+fn error_response(request: &str, err: LokiError) -> HttpResponse {
+    error!("An error occured while responding to {} request: {}", request, err);
+    let status_code = StatusCode::from_u16(err.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    HttpResponse::build(status_code).json(ErrorResponse::from(&err))
+}
+
+/// Answer invalid request parameters with the error envelope of Loki
+fn invalid_parameters(err: impl std::fmt::Display + std::fmt::Debug + 'static) -> actix_web::Error {
+    let response = error_response("invalid parameters", LokiError::InvalidQuery(err.to_string()));
+    InternalError::from_response(err, response).into()
 }
 
 
 async fn query(data: web::Data<AppState>, query: web::Query<Query>) -> impl Responder {
     info!("Starting to handle query request with params: {}", query.0);
-    let query_result = data.federated_loki.query(query.query.to_string(), query.limit, query.time, query.direction).await;
+    let query_result = data.federated_loki.query(query.query.to_string(), query.limit, query.time, query.direction, query.timeout.clone(), query.dedup, query.partial_response_strategy).await;
     match query_result {
-        Ok(result) => HttpResponse::Ok().json(result),
-        Err(err) => {
-            error!("An error occured while responding to query request: {}", err);
-            HttpResponse::InternalServerError().json("{\"error\": \"Internal Server Error\"}") },
+        Ok(result) => streaming_response(result),
+        Err(err) => error_response("query", err),
     }
 }
 async fn query_range(data: web::Data<AppState>, query: web::Query<QueryRange>) -> impl Responder {
     info!("Starting to handle query_range request with params: {}", query.0);
-    let query_result = data.federated_loki.query_range(query.query.to_string(), query.start, query.end, query.limit, query.direction, query.step.clone(), query.interval.clone()).await;
+    let query_result = data.federated_loki.query_range(query.query.to_string(), query.start, query.end, query.limit, query.direction, query.step.clone(), query.interval.clone(), query.timeout.clone(), query.dedup, query.partial_response_strategy).await;
     match query_result {
-        Ok(result) => HttpResponse::Ok().json(result),
-        Err(err) => {
-            error!("An error occured while responding to query_range request: {}", err);
-            HttpResponse::InternalServerError().json("{\"error\": \"Internal Server Error\"}") },
+        Ok(result) => streaming_response(result),
+        Err(err) => error_response("query_range", err),
     }
 }
 
 async fn labels(data: web::Data<AppState>, query: web::Query<Labels>) -> impl Responder {
     info!("Starting to handle labels request with params: {}", query.0);
-    let result = data.federated_loki.labels(query.start, query.end).await;
+    let result = data.federated_loki.labels(query.start, query.end, query.timeout.clone(), query.dedup, query.partial_response_strategy).await;
     match result {
-        Ok(labels) => HttpResponse::Ok().json(labels),
-        Err(err) => {
-            error!("An error occured while responding to labels request: {}", err);
-            HttpResponse::InternalServerError().json("{\"error\": \"Internal Server Error\"}") },
+        Ok(labels) => success_response(&labels.warnings).json(labels),
+        Err(err) => error_response("labels", err),
     }
 }
 
 async fn label_values(path: web::Path<LabelPath>, data: web::Data<AppState>, query: web::Query<Labels>) -> impl Responder {
     info!("Starting to handle label_values({}) request with params: {}", path.label.to_string(), query.0);
-    let result = data.federated_loki.label_values(path.label.to_string(), query.start, query.end).await;
+    let result = data.federated_loki.label_values(path.label.to_string(), query.start, query.end, query.timeout.clone(), query.dedup, query.partial_response_strategy).await;
     match result {
-        Ok(labels) => HttpResponse::Ok().json(labels),
-        Err(err) => {
-            error!("An error occured while responding to label_values request: {}", err);
-            HttpResponse::InternalServerError().json("{\"error\": \"Internal Server Error\"}") },
+        Ok(labels) => success_response(&labels.warnings).json(labels),
+        Err(err) => error_response("label_values", err),
     }
 }
 
 async fn retrieve_series_get_handler(data: web::Data<AppState>, query: web::Query<Series>) -> impl Responder {
     info!("Starting to handle retrieve_series_get_handler request with params: {}", query.0);
-    let result = data.federated_loki.series(query.matches.clone(), query.start, query.end).await;
+    let result = data.federated_loki.series(query.matches.clone(), query.start, query.end, query.timeout.clone(), query.dedup, query.partial_response_strategy).await;
     match result {
-        Ok(series) => HttpResponse::Ok().json(series),
-        Err(err) => {
-            error!("An error occured while responding to retrieve_series_get_handler request: {}", err);
-            HttpResponse::InternalServerError().json("{\"error\": \"Internal Server Error\"}") },
+        Ok(series) => success_response(&series.warnings).json(series),
+        Err(err) => error_response("retrieve_series_get_handler", err),
     }
 }
 
 async fn retrieve_series_post_handler(data: web::Data<AppState>, query: web::Form<Series>) -> impl Responder {
     info!("Starting to handle retrieve_series_post_handler request with params: {}", query.0);
-    let result = data.federated_loki.series(query.matches.clone(), query.start, query.end).await;
+    let result = data.federated_loki.series(query.matches.clone(), query.start, query.end, query.timeout.clone(), query.dedup, query.partial_response_strategy).await;
     match result {
-        Ok(series) => HttpResponse::Ok().json(series),
-        Err(err) => {
-            error!("An error occured while responding to retrieve_series_post_handler request: {}", err);
-            HttpResponse::InternalServerError().json("{\"error\": \"Internal Server Error\"}") },
+        Ok(series) => success_response(&series.warnings).json(series),
+        Err(err) => error_response("retrieve_series_post_handler", err),
+    }
+}
+
+async fn tail(request: HttpRequest, payload: web::Payload, data: web::Data<AppState>, query: web::Query<Tail>) -> impl Responder {
+    info!("Starting to handle tail request with params: {}", query.0);
+    if let Err(err) = websocket::verify_handshake(&request) {
+        return error_response("tail", LokiError::InvalidQuery(err.to_string()));
     }
+    let result = data.federated_loki.tail(query.query.to_string(), query.delay_for, query.limit, query.start, query.dedup, query.partial_response_strategy).await;
+    match result {
+        Ok(tail) => websocket::tail_response(&request, tail, payload),
+        Err(err) => error_response("tail", err),
+    }
+}
+
+/// State of the circuit breakers of the backends
+async fn backends(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.backends_health.statuses())
+}
+
+async fn metrics(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(data.backends_health.metrics())
 }
 
 #[actix_web::main]
@@ -143,15 +219,25 @@ async fn main() -> std::io::Result<()> {
     let server_bind_address = format!("{}:{}", config.server.bind_address, config.server.port);
     info!("Starting loki-federation on {}", server_bind_address);
 
-    let federated_loki = FederatedLoki::new(DataSourcesProvider::new(config.datasources.clone()));
+    let data_sources_provider = DataSourcesProvider::new(config.datasources.clone())
+        .expect("invalid datasources config");
+    let backends_health = data_sources_provider.health().clone();
+    actix_web::rt::spawn(data_sources_provider.clone().run_health_checks());
+    let federated_loki = FederatedLoki::new(data_sources_provider, config.query.clone());
 
     HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
             .app_data(web::Data::new(AppState {
                 federated_loki: federated_loki.clone(),
+                backends_health: backends_health.clone(),
             }))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| invalid_parameters(err)))
+            .app_data(web::FormConfig::default().error_handler(|err, _| invalid_parameters(err)))
+            .app_data(web::PathConfig::default().error_handler(|err, _| invalid_parameters(err)))
             .route("/ready", web::get().to(|| HttpResponse::Ok().body("ready")))
+            .route("/metrics", web::get().to(metrics))
+            .route("/admin/backends", web::get().to(backends))
             .route("/loki/api/v1/query", web::get().to(query))
             .route("/loki/api/v1/query_range", web::get().to(query_range))
             .route("/loki/api/v1/labels", web::get().to(labels))
@@ -159,6 +245,7 @@ async fn main() -> std::io::Result<()> {
             .route("/loki/api/v1/label/{label}/values", web::get().to(label_values))
             .route("/loki/api/v1/series", web::get().to(retrieve_series_get_handler))
             .route("/loki/api/v1/series", web::post().to(retrieve_series_post_handler))
+            .route("/loki/api/v1/tail", web::get().to(tail))
     })
         .bind(server_bind_address)?
         .run()
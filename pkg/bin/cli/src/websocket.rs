@@ -0,0 +1,84 @@
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{hash_key, CloseCode, CloseReason, Codec, Frame, HandshakeError, Message, ProtocolError};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{HeaderValue, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY};
+use actix_web::web::{Bytes, BytesMut};
+use bytestring::ByteString;
+use futures::{future, stream, Stream, StreamExt};
+use generic_loki_client::{LokiError, TailResponse, TailStream};
+
+/// Events of a tail websocket, forwarded to the client as messages
+enum TailEvent {
+    /// None once every backend ended its tail
+    Tail(Option<Result<TailResponse, LokiError>>),
+    Client(Result<Frame, ProtocolError>),
+}
+
+pub fn verify_handshake(request: &HttpRequest) -> Result<(), HandshakeError> {
+    actix_http::ws::verify_handshake(request.head())
+}
+
+/// Frames sent by the client of a websocket
+fn client_frames(payload: web::Payload) -> impl Stream<Item = Result<Frame, ProtocolError>> {
+    stream::unfold(Some((payload, BytesMut::new(), Codec::new())), |state| async move {
+        let (mut payload, mut buffer, mut codec) = state?;
+        loop {
+            match codec.decode(&mut buffer) {
+                Ok(Some(frame)) => return Some((Ok(frame), Some((payload, buffer, codec)))),
+                Ok(None) => {}
+                Err(error) => return Some((Err(error), None)),
+            }
+            match payload.next().await? {
+                Ok(bytes) => buffer.extend_from_slice(&bytes),
+                Err(error) => return Some((Err(ProtocolError::Io(std::io::Error::other(error.to_string()))), None)),
+            }
+        }
+    })
+}
+
+/// Message answering an event, and whether the websocket is closed by it
+fn tail_message(event: TailEvent) -> (Option<Message>, bool) {
+    let close = |code: CloseCode, description: String| Message::Close(Some(CloseReason { code, description: Some(description) }));
+    match event {
+        TailEvent::Tail(Some(Ok(response))) => match serde_json::to_string(&response) {
+            Ok(text) => (Some(Message::Text(ByteString::from(text))), false),
+            Err(error) => (Some(close(CloseCode::Error, error.to_string())), true),
+        },
+        //the error which ended the tail is the reason of the closure, as in Loki
+        TailEvent::Tail(Some(Err(error))) => (Some(close(CloseCode::Error, error.to_string())), true),
+        TailEvent::Tail(None) => (Some(Message::Close(Some(CloseCode::Normal.into()))), true),
+        TailEvent::Client(Ok(Frame::Ping(bytes))) => (Some(Message::Pong(bytes)), false),
+        TailEvent::Client(Ok(Frame::Close(reason))) => (Some(Message::Close(reason)), true),
+        TailEvent::Client(Ok(_)) => (None, false),
+        TailEvent::Client(Err(error)) => (Some(close(CloseCode::Protocol, error.to_string())), true),
+    }
+}
+
+/// Accept the websocket handshake, responses of the tail are sent as text messages until the tail or the client ends it
+pub fn tail_response(request: &HttpRequest, tail: TailStream, payload: web::Payload) -> HttpResponse {
+    let key = request.headers().get(SEC_WEBSOCKET_KEY).map(|key| hash_key(key.as_bytes()));
+    let accept = key.and_then(|key| HeaderValue::from_bytes(&key).ok());
+    let mut response = HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS);
+    response.upgrade("websocket");
+    if let Some(accept) = accept {
+        response.insert_header((SEC_WEBSOCKET_ACCEPT, accept));
+    }
+
+    let events = stream::select(
+        tail.map(Some).chain(stream::once(future::ready(None))).map(TailEvent::Tail),
+        Box::pin(client_frames(payload)).map(TailEvent::Client),
+    );
+    response.streaming(stream::unfold(Some((events, Codec::new())), |state| async move {
+        let (mut events, mut codec) = state?;
+        loop {
+            if let (Some(message), closed) = tail_message(events.next().await?) {
+                let mut buffer = BytesMut::new();
+                if let Err(error) = codec.encode(message, &mut buffer) {
+                    return Some((Err(error), None));
+                }
+                return Some((Ok::<Bytes, ProtocolError>(buffer.freeze()), if closed { None } else { Some((events, codec)) }));
+            }
+        }
+    }))
+}
@@ -0,0 +1,73 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use loki_federation_core::aggregate::merge_entries;
+use loki_federation_core::federated_loki::Direction;
+
+/// Entries of a stream as answered by a backend, newest first
+fn backend_entries(entries: usize, offset: i64, step: i64) -> Vec<(i64, String)> {
+    (0..entries as i64).rev()
+        .map(|index| {
+            let timestamp = 1_650_000_000_000_000_000 + index * step + offset;
+            (timestamp, format!("level=info ts={} msg=\"request served\" status=200", timestamp))
+        })
+        .collect()
+}
+
+/// Replicas answer the same entries, shards answer distinct entries
+fn sources(backends: usize, entries_per_backend: usize, replicated: bool) -> Vec<Vec<(i64, String)>> {
+    (0..backends)
+        .map(|backend| if replicated {
+            backend_entries(entries_per_backend, 0, 1000)
+        } else {
+            backend_entries(entries_per_backend, backend as i64, 1000)
+        })
+        .collect()
+}
+
+/// Pairwise insertion merge used before the k-way merge, kept as a baseline
+fn insertion_merge(sources: Vec<Vec<(i64, String)>>) -> Vec<(i64, String)> {
+    sources.into_iter().fold(Vec::new(), |mut result, entries| {
+        for item in entries {
+            if !result.contains(&item) {
+                match result.iter().position(|&(timestamp, _)| timestamp <= item.0) {
+                    Some(index) => result.insert(index, item),
+                    None => result.push(item),
+                }
+            }
+        }
+        result
+    })
+}
+
+fn bench_merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge_entries");
+    group.sample_size(10);
+    for (backends, entries_per_backend) in [(2, 50_000), (3, 50_000), (4, 100_000)] {
+        for replicated in [true, false] {
+            let name = if replicated { "replicas" } else { "shards" };
+            let entries = backends * entries_per_backend;
+            group.bench_with_input(BenchmarkId::new(name, entries), &sources(backends, entries_per_backend, replicated), |b, sources| {
+                b.iter_batched(|| sources.clone(), |sources| black_box(merge_entries(sources, Direction::Backward)), BatchSize::LargeInput)
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_baseline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("baseline");
+    group.sample_size(10);
+    //the insertion merge is quadratic, larger inputs take minutes
+    for entries_per_backend in [1_000, 5_000] {
+        let sources = sources(2, entries_per_backend, false);
+        group.bench_with_input(BenchmarkId::new("insertion_merge", 2 * entries_per_backend), &sources, |b, sources| {
+            b.iter_batched(|| sources.clone(), |sources| black_box(insertion_merge(sources)), BatchSize::LargeInput)
+        });
+        group.bench_with_input(BenchmarkId::new("merge_entries", 2 * entries_per_backend), &sources, |b, sources| {
+            b.iter_batched(|| sources.clone(), |sources| black_box(merge_entries(sources, Direction::Backward)), BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_merge, bench_baseline);
+criterion_main!(benches);
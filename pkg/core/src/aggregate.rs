@@ -1,50 +1,170 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use anyhow::anyhow;
+use generic_loki_client::LokiError;
+use crate::config::MergeStrategy;
 use crate::federated_loki::Direction;
 
-pub fn aggregate(set_a: Vec<(i64, String)>, set_b: Vec<(i64, String)>, direction: Direction) -> Vec<(i64, String)> {
-    let mut result = set_a.clone();
-    for item in set_b {
-        if !result.contains(&item) {
-            match direction {
-                Direction::Forward =>
-                    match result.iter().rposition(|&(timestamp, _)| timestamp < item.0) {
-                        Some(index) => {
-                            result.insert(index+1, item)
-                        }
-                        None =>
-                            match result.iter().position(|&(timestamp, _)| timestamp <= item.0) {
-                                Some(index) => {
-                                    result.insert(index + 1, item)
-                                }
-                                None => {
-                                    result.insert(0, item)
-                                }
-                            },
-                    },
-
-                Direction::Backward =>
-                    match result.iter().position(|&(timestamp, _)| timestamp <= item.0) {
-                        Some(index) => {
-                            result.insert(index, item)
-                        }
-                        None => match result.iter().rposition(|&(timestamp, _)| timestamp > item.0) {
-                            Some(index) => {
-                                result.insert(index + 1, item)
-                            }
-                            None => {
-                                result.insert(0, item)
-                            }
-                        },
-                    },
-            };
+/// Head of a source in the merge, the heap pops the next entry in the direction of the query.
+/// Entries sharing a timestamp are ordered by source, in the reverse order when backward,
+/// entries of a source sharing a timestamp keep their order.
+struct Head {
+    timestamp: i64,
+    line: String,
+    source: usize,
+    position: usize,
+    direction: Direction,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let order = (self.timestamp, self.source).cmp(&(other.timestamp, other.source));
+        let order = match self.direction {
+            Direction::Forward => order.reverse(),
+            Direction::Backward => order,
+        };
+        order.then(other.position.cmp(&self.position))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+/// Backends answer entries sorted in the direction of the query, they are only sorted when they aren't
+fn sort_entries(entries: &mut [(i64, String)], direction: Direction) {
+    let sorted = entries.windows(2).all(|pair| match direction {
+        Direction::Forward => pair[0].0 <= pair[1].0,
+        Direction::Backward => pair[0].0 >= pair[1].0,
+    });
+    if !sorted {
+        match direction {
+            Direction::Forward => entries.sort_by_key(|(timestamp, _)| *timestamp),
+            Direction::Backward => entries.sort_by_key(|(timestamp, _)| Reverse(*timestamp)),
+        }
+    }
+}
+
+/// K-way merge of the entries of a stream received from several sources, sorted in the direction of the query.
+/// Duplicated entries, sharing a timestamp and a line, are kept once.
+pub fn merge_entries(sources: Vec<Vec<(i64, String)>>, direction: Direction) -> Vec<(i64, String)> {
+    let capacity = sources.iter().map(|entries| entries.len()).sum();
+    let mut sources: Vec<std::vec::IntoIter<(i64, String)>> = sources.into_iter()
+        .map(|mut entries| {
+            sort_entries(&mut entries, direction);
+            entries.into_iter()
+        })
+        .collect();
+
+    let mut heads: BinaryHeap<Head> = BinaryHeap::with_capacity(sources.len());
+    for (source, entries) in sources.iter_mut().enumerate() {
+        if let Some((timestamp, line)) = entries.next() {
+            heads.push(Head { timestamp, line, source, position: 0, direction });
+        }
+    }
+
+    let mut merged = Vec::with_capacity(capacity);
+    //duplicates share a timestamp, only the lines of the current timestamp are remembered.
+    //Lines are compared as is, distinct lines must never be dropped on a hash collision.
+    let mut current_timestamp = None;
+    let mut current_lines: HashSet<String> = HashSet::new();
+    while let Some(head) = heads.pop() {
+        if let Some((timestamp, line)) = sources[head.source].next() {
+            heads.push(Head { timestamp, line, source: head.source, position: head.position + 1, direction });
+        }
+        if current_timestamp != Some(head.timestamp) {
+            current_timestamp = Some(head.timestamp);
+            current_lines.clear();
+        }
+        if !current_lines.contains(&head.line) {
+            current_lines.insert(head.line.clone());
+            merged.push((head.timestamp, head.line));
         }
     }
-    result
+    merged
+}
+
+pub fn parse_sample_value(value: &str) -> Result<f64, LokiError> {
+    match value {
+        "+Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        _ => value.parse::<f64>().map_err(|e| LokiError::Other(anyhow!("Invalid sample value {}: {}", value, e))),
+    }
+}
+
+pub fn format_sample_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Combine two sample values of the same serie at the same timestamp
+pub fn merge_sample_values(value_a: String, value_b: &str, merge_strategy: MergeStrategy) -> Result<String, LokiError> {
+    let merge: fn(f64, f64) -> f64 = match merge_strategy {
+        MergeStrategy::KeepOne => return Ok(value_a),
+        MergeStrategy::Sum => |a, b| a + b,
+        MergeStrategy::Max => f64::max,
+        MergeStrategy::Min => f64::min,
+    };
+    Ok(format_sample_value(merge(parse_sample_value(&value_a)?, parse_sample_value(value_b)?)))
+}
+
+/// Join the samples of two series on their timestamps, samples sharing the same timestamp are combined using the merge strategy
+pub fn aggregate_samples(set_a: Vec<(f64, String)>, set_b: Vec<(f64, String)>, merge_strategy: MergeStrategy) -> Result<Vec<(f64, String)>, LokiError> {
+    let mut samples: BTreeMap<i64, (f64, String)> = BTreeMap::new();
+    for (timestamp, value) in set_a {
+        //matrix timestamps are expressed in seconds with at most a millisecond precision
+        samples.insert((timestamp * 1000.0).round() as i64, (timestamp, value));
+    }
+    for (timestamp, value) in set_b {
+        let key = (timestamp * 1000.0).round() as i64;
+        let sample = match samples.remove(&key) {
+            Some((aggregated_timestamp, aggregated_value)) => (aggregated_timestamp, merge_sample_values(aggregated_value, &value, merge_strategy)?),
+            None => (timestamp, value),
+        };
+        samples.insert(key, sample);
+    }
+    Ok(samples.into_values().collect())
+}
+
+/// Number of entries kept from each stream so that `limit` entries are kept across all the streams,
+/// the first ones in the direction of the query. Timestamps of each stream are sorted in that direction,
+/// entries sharing a timestamp are kept in the order of the streams.
+pub fn limit_entries(streams: &[Vec<i64>], limit: usize, direction: Direction) -> Vec<usize> {
+    let mut entries: Vec<(i64, usize)> = streams.iter().enumerate()
+        .flat_map(|(index, timestamps)| timestamps.iter().map(move |timestamp| (*timestamp, index)))
+        .collect();
+    match direction {
+        Direction::Forward => entries.sort_unstable(),
+        Direction::Backward => entries.sort_unstable_by(|(timestamp_a, index_a), (timestamp_b, index_b)| timestamp_b.cmp(timestamp_a).then(index_a.cmp(index_b))),
+    }
+    let mut kept = vec![0; streams.len()];
+    entries.iter().take(limit).for_each(|(_, index)| kept[*index] += 1);
+    kept
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn aggregate(set_a: Vec<(i64, String)>, set_b: Vec<(i64, String)>, direction: Direction) -> Vec<(i64, String)> {
+        merge_entries(vec![set_a, set_b], direction)
+    }
+
     #[test]
     fn it_should_aggregate_when_forward_and_set_a_contains_less_items_than_set_b() {
         assert_eq!(aggregate(vec![(1, "A".to_string())], vec![(1, "A".to_string()), (2, "B".to_string())], Direction::Forward), vec![(1, "A".to_string()), (2, "B".to_string())]);
@@ -89,4 +209,73 @@ mod tests {
     fn it_should_aggregate_when_backward_and_set_a_contains_less_items_than_set_b() {
         assert_eq!(aggregate(vec![(1, "A".to_string())], vec![(1, "A".to_string()), (2, "B".to_string())], Direction::Backward), vec![(2, "B".to_string()), (1, "A".to_string())]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn it_should_aggregate_samples_on_aligned_timestamps() {
+        assert_eq!(aggregate_samples(vec![(1.0, "1".to_string()), (3.0, "3".to_string())], vec![(1.0, "1".to_string()), (2.0, "2".to_string())], MergeStrategy::KeepOne).unwrap(), vec![(1.0, "1".to_string()), (2.0, "2".to_string()), (3.0, "3".to_string())]);
+    }
+
+    #[test]
+    fn it_should_keep_sample_of_set_a_when_timestamps_are_equal() {
+        assert_eq!(aggregate_samples(vec![(1.5, "A".to_string())], vec![(1.5, "B".to_string())], MergeStrategy::KeepOne).unwrap(), vec![(1.5, "A".to_string())]);
+    }
+
+    #[test]
+    fn it_should_aggregate_samples_when_set_a_is_empty() {
+        assert_eq!(aggregate_samples(vec![], vec![(2.0, "2".to_string()), (1.0, "1".to_string())], MergeStrategy::KeepOne).unwrap(), vec![(1.0, "1".to_string()), (2.0, "2".to_string())]);
+    }
+
+    #[test]
+    fn it_should_sum_samples_when_timestamps_are_equal() {
+        assert_eq!(aggregate_samples(vec![(1.0, "1.5".to_string()), (2.0, "2".to_string())], vec![(1.0, "2".to_string())], MergeStrategy::Sum).unwrap(), vec![(1.0, "3.5".to_string()), (2.0, "2".to_string())]);
+    }
+
+    #[test]
+    fn it_should_merge_sample_values_with_max_and_min() {
+        assert_eq!(merge_sample_values("1".to_string(), "+Inf", MergeStrategy::Max).unwrap(), "+Inf".to_string());
+        assert_eq!(merge_sample_values("1".to_string(), "0.5", MergeStrategy::Min).unwrap(), "0.5".to_string());
+    }
+
+    #[test]
+    fn it_should_fail_to_merge_invalid_sample_values() {
+        assert!(merge_sample_values("1".to_string(), "not a number", MergeStrategy::Sum).is_err());
+    }
+
+    #[test]
+    fn it_should_limit_entries_across_streams_when_backward() {
+        assert_eq!(limit_entries(&[vec![5, 3, 1], vec![4, 2], vec![]], 3, Direction::Backward), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn it_should_limit_entries_across_streams_when_forward() {
+        assert_eq!(limit_entries(&[vec![1, 3, 5], vec![2, 4]], 3, Direction::Forward), vec![2, 1]);
+    }
+
+    #[test]
+    fn it_should_limit_entries_sharing_a_timestamp_in_the_order_of_the_streams() {
+        assert_eq!(limit_entries(&[vec![2, 1], vec![2, 1]], 3, Direction::Backward), vec![2, 1]);
+        assert_eq!(limit_entries(&[vec![1], vec![1]], 10, Direction::Forward), vec![1, 1]);
+    }
+
+    #[test]
+    fn it_should_merge_entries_of_several_sources() {
+        let sources = vec![
+            vec![(5, "E".to_string()), (3, "C".to_string()), (1, "A".to_string())],
+            vec![(4, "D".to_string()), (3, "C".to_string())],
+            vec![(6, "F".to_string()), (2, "B".to_string())],
+        ];
+        assert_eq!(merge_entries(sources, Direction::Backward).into_iter().map(|(_, line)| line).collect::<Vec<String>>(), vec!["F", "E", "D", "C", "B", "A"]);
+    }
+
+    #[test]
+    fn it_should_sort_unsorted_sources_before_merging() {
+        let sources = vec![vec![(3, "C".to_string()), (1, "A".to_string())], vec![(2, "B".to_string())]];
+        assert_eq!(merge_entries(sources, Direction::Forward), vec![(1, "A".to_string()), (2, "B".to_string()), (3, "C".to_string())]);
+    }
+
+    #[test]
+    fn it_should_keep_the_order_of_entries_of_a_source_sharing_a_timestamp() {
+        let sources = vec![vec![(2, "B1".to_string()), (2, "B2".to_string()), (1, "A".to_string())], vec![(2, "B3".to_string())]];
+        assert_eq!(merge_entries(sources, Direction::Backward).into_iter().map(|(_, line)| line).collect::<Vec<String>>(), vec!["B3", "B1", "B2", "A"]);
+    }
+}
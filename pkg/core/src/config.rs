@@ -1,4 +1,4 @@
-use serde::{Deserialize};
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Debug)]
 pub struct ServerConfig {
@@ -10,7 +10,135 @@ pub struct ServerConfig {
 #[derive(Debug, Clone)]
 pub struct Datasources {
     pub name: String,
-    pub urls: Option<Vec<String>>
+    pub urls: Option<Vec<String>>,
+    /// Seconds before the evaluation time searched by instant log queries sent over grpc
+    #[cfg_attr(not(test), serde(default))]
+    pub instant_query_lookback_seconds: Option<u64>,
+    /// Timeout of the connections to a backend, over http and grpc
+    #[cfg_attr(not(test), serde(default))]
+    pub connect_timeout_seconds: Option<u64>,
+    /// Timeout of a request to a backend, 0 disables it. Tails are not bounded by it.
+    #[cfg_attr(not(test), serde(default))]
+    pub request_timeout_seconds: Option<u64>,
+    //transport settings of the grpc channels, kept open and shared by every request to a backend
+    /// Interval of the http2 keepalive pings, 0 disables them
+    #[cfg_attr(not(test), serde(default))]
+    pub keep_alive_interval_seconds: Option<u64>,
+    #[cfg_attr(not(test), serde(default))]
+    pub keep_alive_timeout_seconds: Option<u64>,
+    #[cfg_attr(not(test), serde(default))]
+    pub max_message_size_bytes: Option<usize>,
+    /// Interval of the tcp keepalive probes of both http and grpc connections, 0 disables them
+    #[cfg_attr(not(test), serde(default))]
+    pub tcp_keepalive_seconds: Option<u64>,
+    /// Connection pool of the http clients, kept for the whole process with one client per backend
+    #[cfg_attr(not(test), serde(default))]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long an idle connection is kept in the pool, 0 keeps it forever
+    #[cfg_attr(not(test), serde(default))]
+    pub pool_idle_timeout_seconds: Option<u64>,
+    #[cfg_attr(not(test), serde(default))]
+    pub http2_prior_knowledge: Option<bool>,
+    /// Attempts of a request to a backend including the first one, 1 disables the retries
+    #[cfg_attr(not(test), serde(default))]
+    pub retry_max_attempts: Option<u32>,
+    /// Backoff before the first retry, doubled at each retry up to `retry_max_backoff_milliseconds`
+    #[cfg_attr(not(test), serde(default))]
+    pub retry_backoff_milliseconds: Option<u64>,
+    #[cfg_attr(not(test), serde(default))]
+    pub retry_max_backoff_milliseconds: Option<u64>,
+    /// Errors worth retrying, the other ones are answered at once
+    #[cfg_attr(not(test), serde(default))]
+    pub retry_on: Option<Vec<RetryableError>>,
+    /// Interval of the probes of the backends, 0 disables the health checks and the circuit breakers
+    #[cfg_attr(not(test), serde(default))]
+    pub health_check_interval_seconds: Option<u64>,
+    #[cfg_attr(not(test), serde(default))]
+    pub health_check_timeout_seconds: Option<u64>,
+    /// Consecutive failed probes leaving a backend out of the queries
+    #[cfg_attr(not(test), serde(default))]
+    pub circuit_breaker_consecutive_failures: Option<u32>,
+    /// Ratio of failed probes, among the latest 20, leaving a backend out of the queries
+    #[cfg_attr(not(test), serde(default))]
+    pub circuit_breaker_error_rate: Option<f64>,
+    /// How long a backend is left out of the queries before it is probed again
+    #[cfg_attr(not(test), serde(default))]
+    pub circuit_breaker_open_seconds: Option<u64>,
+    /// Groups of urls holding the same data, each group is queried as one backend answered by one of its replicas.
+    /// Replicas are preferred in the order they are listed, the urls left out of the groups are queried alone.
+    #[cfg_attr(not(test), serde(default))]
+    pub replica_sets: Option<Vec<Vec<String>>>,
+    /// Delay before a request to a replica set is sent to the next replica as well, 0 hedges at once
+    #[cfg_attr(not(test), serde(default))]
+    pub hedge_delay_milliseconds: Option<u64>,
+    /// Percentile of the latencies of a replica set used as the hedge delay once enough of them were observed,
+    /// between 0 and 1
+    #[cfg_attr(not(test), serde(default))]
+    pub hedge_latency_percentile: Option<f64>,
+}
+
+/// Errors of a backend which may not happen again on a retry
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryableError {
+    /// Connection refused or reset, 502 and 503 statuses, grpc `UNAVAILABLE`
+    Unavailable,
+    /// Other 5xx statuses, grpc `INTERNAL` and `UNKNOWN`
+    ServerError,
+    /// 429 status, grpc `RESOURCE_EXHAUSTED`
+    TooManyRequests,
+    /// 408 and 504 statuses, grpc `DEADLINE_EXCEEDED` and the request timeout of the datasource
+    Timeout,
+}
+
+/// How samples of the same serie returned by several backends at the same timestamp are combined
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+    /// Keep the first sample received, backends are replicas of each other
+    #[default]
+    KeepOne,
+    /// Sum the samples, backends are holding distinct shards of the data
+    Sum,
+    Max,
+    Min,
+}
+
+/// What to answer when some of the backends failed
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PartialResponseStrategy {
+    /// Fail the request as soon as one backend failed
+    Abort,
+    /// Answer with the results of the other backends and report the failures as warnings
+    #[default]
+    Warn,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct QueryConfig {
+    /// Labels identifying the replica a stream comes from (cf thanos `--query.replica-label`).
+    /// They are stripped from streams and series before merging so replicated data collapses into one stream.
+    #[serde(default)]
+    pub replica_labels: Vec<String>,
+    /// Strategy used to combine samples of vector and matrix results
+    #[serde(default)]
+    pub merge_strategy: MergeStrategy,
+    /// Rewrite metric queries into partial aggregations evaluated by each backend and recombined by the federation,
+    /// backends are then expected to hold distinct shards of the data
+    #[serde(default)]
+    pub aggregation_pushdown: bool,
+    /// Strategy applied when some of the backends failed, it can be overridden by each request.
+    /// A request is failed anyway when all the backends failed.
+    #[serde(default)]
+    pub partial_response_strategy: PartialResponseStrategy,
+    /// Bytes of backend responses buffered by a query before it is failed, 0 disables the limit
+    #[serde(default)]
+    pub max_buffered_bytes: Option<usize>,
+    /// Deadline of a query in seconds, the backends which haven't answered by then are reported as timed out.
+    /// It caps the `timeout` requested by the queries, disabled when missing or 0.
+    #[serde(default)]
+    pub query_timeout_seconds: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -23,5 +151,7 @@ pub struct DebugConfig {
 pub struct Config {
     pub server: ServerConfig,
     pub datasources: Datasources,
+    #[cfg_attr(not(test), serde(default))]
+    pub query: QueryConfig,
     pub debug: DebugConfig,
-}
\ No newline at end of file
+}
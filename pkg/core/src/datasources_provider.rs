@@ -1,23 +1,47 @@
-use generic_loki_client::{LokiError, LokiClient};
-use http_loki_client::HttpLokiClient;
+use generic_loki_client::{BufferBudget, LokiError, LokiClient};
+use http_loki_client::{ClientCache, HttpLokiClient};
+#[cfg(not(test))]
+use http_loki_client::ClientConfig;
 #[cfg(not(test))]
 use anyhow::Error;
 #[cfg(not(test))]
 use log::{error, info};
-use grpc_loki_client::GrpcLokiClient;
+use std::time::Duration;
+use grpc_loki_client::{ChannelCache, GrpcLokiClient};
+#[cfg(not(test))]
+use grpc_loki_client::{ChannelConfig, DEFAULT_INSTANT_QUERY_LOOKBACK};
 #[cfg(test)]
 use mockall::{automock, predicate::*};
 #[cfg(not(test))]
 use crate::config::Datasources;
+use crate::retry::{RetryingLokiClient, RetryPolicy};
+use crate::health::BackendsHealth;
+use crate::hedge::{HedgedLokiClient, HedgePolicy};
+#[cfg(not(test))]
+use crate::hedge::DEFAULT_HEDGE_DELAY;
+#[cfg(not(test))]
+use crate::health::HealthConfig;
+#[cfg(not(test))]
+use futures::future;
+#[cfg(not(test))]
+use std::collections::HashSet;
+use tokio::time::Instant;
 
 #[derive(Debug, Clone)]
 pub struct HttpDataSource {
     pub url: String,
+    pub clients: ClientCache,
+    pub retry_policy: RetryPolicy,
+    pub health: BackendsHealth,
 }
 
 #[derive(Debug, Clone)]
 pub struct GrpcDataSource {
     pub url: String,
+    pub instant_query_lookback: Duration,
+    pub channels: ChannelCache,
+    pub retry_policy: RetryPolicy,
+    pub health: BackendsHealth,
 }
 
 #[derive(Debug, Clone)]
@@ -29,92 +53,334 @@ pub enum DataSource {
 #[derive(Debug, Clone)]
 pub struct DataSourceInstance {
     data_source: DataSource,
+    /// Other replicas of the backend, in order of preference, the query is hedged across them
+    replicas: Vec<DataSource>,
+    hedge_policy: HedgePolicy,
 }
 
 #[cfg_attr(test, automock)]
 impl DataSourceInstance {
     pub fn new(data_source: DataSource) -> Self {
         Self {
-            data_source
+            data_source,
+            replicas: vec![],
+            hedge_policy: HedgePolicy::default(),
+        }
+    }
+
+    /// Data source of a replica set, queried through its preferred replica
+    pub fn replica_set(data_source: DataSource, replicas: Vec<DataSource>, hedge_policy: HedgePolicy) -> Self {
+        Self {
+            data_source,
+            replicas,
+            hedge_policy,
+        }
+    }
+
+    /// Url of the backend of the data source, the urls of all the replicas for a replica set
+    pub fn url(&self) -> String {
+        std::iter::once(&self.data_source).chain(&self.replicas)
+            .map(data_source_url)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Client of the data source for a query, accounting the responses in the budget of the query
+    /// and retrying failed requests until the deadline of the query.
+    /// A backend whose circuit breaker is tripped fails at once, the query doesn't wait on it.
+    /// The requests to a replica set are hedged across the replicas whose circuit breaker is closed.
+    pub fn get_client(&self, budget: &BufferBudget, deadline: Option<Instant>) -> Result<Box<dyn LokiClient>, LokiError> {
+        if self.replicas.is_empty() {
+            return backend_client(&self.data_source, budget, deadline);
+        }
+        let mut replicas = vec![];
+        let mut first_error = None;
+        for data_source in std::iter::once(&self.data_source).chain(&self.replicas) {
+            match backend_client(data_source, budget, deadline) {
+                Ok(client) => replicas.push((data_source_url(data_source), client)),
+                Err(loki_error) => { first_error.get_or_insert(loki_error); }
+            }
+        }
+        match first_error {
+            Some(loki_error) if replicas.is_empty() => Err(loki_error),
+            _ => Ok(Box::new(HedgedLokiClient::new(replicas, self.hedge_policy.clone()))),
         }
     }
-    pub fn get_client(&self) -> Result<Box<dyn LokiClient>, LokiError> {
-        match self.data_source {
+
+    /// Probe the backend of the data source, bypassing its circuit breaker and the retries
+    pub async fn probe(&self) -> Result<(), LokiError> {
+        let budget = BufferBudget::unlimited();
+        let client: Box<dyn LokiClient> = match self.data_source {
             DataSource::HttpDataSource(ref http_data_source) => {
-                let client = HttpLokiClient::new(http_data_source.url.clone());
-                Ok(Box::new(client))
+                Box::new(HttpLokiClient::new(http_data_source.url.clone(), http_data_source.clients.get(&http_data_source.url)?, budget))
             }
             DataSource::GrpcDataSource(ref grpc_data_source) => {
-                let client = GrpcLokiClient::new(grpc_data_source.url.clone());
-                Ok(Box::new(client))
-            },
+                Box::new(GrpcLokiClient::new(grpc_data_source.url.clone(), grpc_data_source.instant_query_lookback, grpc_data_source.channels.clone(), budget))
+            }
+        };
+        let timeout = data_source_health(&self.data_source).config().timeout;
+        tokio::time::timeout(timeout, client.ready()).await.unwrap_or_else(|_| Err(LokiError::Timeout {
+            backend: self.url(),
+            message: "the health check expired".to_string(),
+        }))
+    }
+}
+
+/// Client of a single backend, failing at once when its circuit breaker is tripped
+fn backend_client(data_source: &DataSource, budget: &BufferBudget, deadline: Option<Instant>) -> Result<Box<dyn LokiClient>, LokiError> {
+    let url = data_source_url(data_source);
+    if !data_source_health(data_source).is_available(&url) {
+        return Err(LokiError::Unavailable { backend: url, message: "left out of the queries by its circuit breaker".to_string(), retry_after: None });
+    }
+    match data_source {
+        DataSource::HttpDataSource(http_data_source) => {
+            let client = HttpLokiClient::new(http_data_source.url.clone(), http_data_source.clients.get(&http_data_source.url)?, budget.clone());
+            Ok(Box::new(RetryingLokiClient::new(http_data_source.url.clone(), client, http_data_source.retry_policy.clone(), deadline)))
         }
+        DataSource::GrpcDataSource(grpc_data_source) => {
+            let client = GrpcLokiClient::new(grpc_data_source.url.clone(), grpc_data_source.instant_query_lookback, grpc_data_source.channels.clone(), budget.clone());
+            Ok(Box::new(RetryingLokiClient::new(grpc_data_source.url.clone(), client, grpc_data_source.retry_policy.clone(), deadline)))
+        },
+    }
+}
+
+fn data_source_url(data_source: &DataSource) -> String {
+    match data_source {
+        DataSource::HttpDataSource(http_data_source) => http_data_source.url.clone(),
+        DataSource::GrpcDataSource(grpc_data_source) => grpc_data_source.url.clone(),
+    }
+}
+
+fn data_source_health(data_source: &DataSource) -> &BackendsHealth {
+    match data_source {
+        DataSource::HttpDataSource(http_data_source) => &http_data_source.health,
+        DataSource::GrpcDataSource(grpc_data_source) => &grpc_data_source.health,
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct DataSourcesProvider {
+    /// Every backend of the datasource, replicas included, they share the http clients and grpc channels
+    #[cfg(not(test))]
+    backends: Vec<DataSource>,
+    /// Data sources of the queries, checked once when the configuration is loaded
     #[cfg(not(test))]
-    data_sources_config: Datasources
+    data_sources: Vec<DataSourceInstance>,
+    #[cfg(not(test))]
+    health: BackendsHealth,
+}
+
+/// Durations are configured in seconds, 0 disables the setting
+#[cfg(not(test))]
+fn optional_duration(seconds: Option<u64>, default: Option<Duration>) -> Option<Duration> {
+    match seconds {
+        Some(0) => None,
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None => default,
+    }
+}
+
+/// Settings left out of the configuration keep their default value
+#[cfg(not(test))]
+fn client_config(data_sources_config: &Datasources) -> ClientConfig {
+    let default = ClientConfig::default();
+    ClientConfig {
+        connect_timeout: data_sources_config.connect_timeout_seconds.map_or(default.connect_timeout, Duration::from_secs),
+        request_timeout: optional_duration(data_sources_config.request_timeout_seconds, default.request_timeout),
+        pool_max_idle_per_host: data_sources_config.pool_max_idle_per_host.unwrap_or(default.pool_max_idle_per_host),
+        pool_idle_timeout: optional_duration(data_sources_config.pool_idle_timeout_seconds, default.pool_idle_timeout),
+        tcp_keepalive: optional_duration(data_sources_config.tcp_keepalive_seconds, default.tcp_keepalive),
+        http2_prior_knowledge: data_sources_config.http2_prior_knowledge.unwrap_or(default.http2_prior_knowledge),
+    }
+}
+
+/// Settings left out of the configuration keep their default value
+#[cfg(not(test))]
+fn channel_config(data_sources_config: &Datasources) -> ChannelConfig {
+    let default = ChannelConfig::default();
+    ChannelConfig {
+        connect_timeout: data_sources_config.connect_timeout_seconds.map_or(default.connect_timeout, Duration::from_secs),
+        request_timeout: optional_duration(data_sources_config.request_timeout_seconds, default.request_timeout),
+        keep_alive_interval: optional_duration(data_sources_config.keep_alive_interval_seconds, default.keep_alive_interval),
+        keep_alive_timeout: data_sources_config.keep_alive_timeout_seconds.map_or(default.keep_alive_timeout, Duration::from_secs),
+        tcp_keepalive: optional_duration(data_sources_config.tcp_keepalive_seconds, default.tcp_keepalive),
+        max_message_size: data_sources_config.max_message_size_bytes.unwrap_or(default.max_message_size),
+    }
+}
+
+/// Settings left out of the configuration keep their default value
+#[cfg(not(test))]
+fn retry_policy(data_sources_config: &Datasources) -> RetryPolicy {
+    let default = RetryPolicy::default();
+    RetryPolicy {
+        max_attempts: data_sources_config.retry_max_attempts.unwrap_or(default.max_attempts).max(1),
+        backoff: data_sources_config.retry_backoff_milliseconds.map_or(default.backoff, Duration::from_millis),
+        max_backoff: data_sources_config.retry_max_backoff_milliseconds.map_or(default.max_backoff, Duration::from_millis),
+        retry_on: data_sources_config.retry_on.clone().unwrap_or(default.retry_on),
+    }
+}
+
+/// Settings left out of the configuration keep their default value
+#[cfg(not(test))]
+fn health_config(data_sources_config: &Datasources) -> HealthConfig {
+    let default = HealthConfig::default();
+    HealthConfig {
+        interval: optional_duration(data_sources_config.health_check_interval_seconds, default.interval),
+        timeout: data_sources_config.health_check_timeout_seconds.map_or(default.timeout, Duration::from_secs),
+        consecutive_failures: data_sources_config.circuit_breaker_consecutive_failures.unwrap_or(default.consecutive_failures),
+        error_rate: data_sources_config.circuit_breaker_error_rate.unwrap_or(default.error_rate),
+        open_duration: data_sources_config.circuit_breaker_open_seconds.map_or(default.open_duration, Duration::from_secs),
+    }
+}
+
+#[cfg(not(test))]
+fn hedge_policy(data_sources_config: &Datasources) -> HedgePolicy {
+    HedgePolicy::new(
+        data_sources_config.hedge_delay_milliseconds.map_or(DEFAULT_HEDGE_DELAY, Duration::from_millis),
+        data_sources_config.hedge_latency_percentile,
+    )
+}
+
+/// Every backend of the datasource, replicas included
+#[cfg(not(test))]
+fn backends(data_sources_config: &Datasources, health: &BackendsHealth) -> Result<Vec<DataSource>, LokiError> {
+    let retry_policy = retry_policy(data_sources_config);
+    match data_sources_config.name.as_str() {
+        "static-http" => {
+            let urls_option = data_sources_config.urls.clone();
+            if urls_option.is_none() {
+                return Err(LokiError::Other(Error::msg("static-http requires urls")));
+            }
+
+            let urls = urls_option.unwrap();
+
+            info!("Using static urls {}", urls.join(", "));
+            let clients = ClientCache::new(client_config(data_sources_config));
+            Ok(urls.iter().map(|url| {
+                DataSource::HttpDataSource(HttpDataSource {
+                    url: url.clone(),
+                    clients: clients.clone(),
+                    retry_policy: retry_policy.clone(),
+                    health: health.clone(),
+                })
+            }).collect())
+        }
+        "static-grpc-alpha" => {
+            let urls_option = data_sources_config.urls.clone();
+            if urls_option.is_none() {
+                return Err(LokiError::Other(Error::msg("static-grpc requires urls")));
+            }
+
+            let urls = urls_option.unwrap();
+
+            info!("Using static urls {}", urls.join(", "));
+            let channels = ChannelCache::new(channel_config(data_sources_config));
+            Ok(urls.iter().map(|url| {
+                DataSource::GrpcDataSource(GrpcDataSource {
+                    url: url.clone(),
+                    instant_query_lookback: data_sources_config.instant_query_lookback_seconds
+                        .map_or(DEFAULT_INSTANT_QUERY_LOOKBACK, Duration::from_secs),
+                    channels: channels.clone(),
+                    retry_policy: retry_policy.clone(),
+                    health: health.clone(),
+                })
+            }).collect())
+        }
+        _ => {
+            error!("Unsupported datasource {}", data_sources_config.name);
+            Err(LokiError::Other(Error::msg("Unsupported datasource")))
+        }
+    }
+}
+
+/// Group the backends of each replica set into one data source, hedged with a policy of its own
+/// as the latencies observed by the hedges outlive the requests
+#[cfg(not(test))]
+fn group_replica_sets(data_sources_config: &Datasources, mut backends: Vec<DataSource>) -> Result<Vec<DataSourceInstance>, LokiError> {
+    let replica_sets = match data_sources_config.replica_sets {
+        Some(ref replica_sets) => replica_sets,
+        None => return Ok(backends.into_iter().map(DataSourceInstance::new).collect()),
+    };
+    let mut grouped = HashSet::new();
+    let mut data_sources = vec![];
+    for replica_set in replica_sets {
+        let mut replicas = vec![];
+        for url in replica_set {
+            let replica = backends.iter().find(|backend| &data_source_url(backend) == url);
+            match replica {
+                Some(replica) if grouped.insert(url) => replicas.push(replica.clone()),
+                _ => return Err(LokiError::Other(Error::msg(format!("{} of a replica set must be listed once in the urls", url)))),
+            }
+        }
+        if replicas.is_empty() {
+            return Err(LokiError::Other(Error::msg("replica sets must not be empty")));
+        }
+        let preferred = replicas.remove(0);
+        data_sources.push(DataSourceInstance::replica_set(preferred, replicas, hedge_policy(data_sources_config)));
+    }
+    backends.retain(|backend| !grouped.contains(&data_source_url(backend)));
+    data_sources.extend(backends.into_iter().map(DataSourceInstance::new));
+    Ok(data_sources)
 }
 
 #[cfg_attr(test, automock)]
 impl DataSourcesProvider {
     #[cfg(test)]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
         }
     }
 
+    /// Provider of the configured data sources, failing on an invalid configuration
     #[cfg(not(test))]
-    pub fn new(data_sources_config: Datasources) -> Self {
-        Self {
-            data_sources_config
-        }
+    pub fn new(data_sources_config: Datasources) -> Result<Self, LokiError> {
+        let health = BackendsHealth::new(health_config(&data_sources_config));
+        let backends = backends(&data_sources_config, &health)?;
+        let data_sources = group_replica_sets(&data_sources_config, backends.clone())?;
+        Ok(Self {
+            backends,
+            data_sources,
+            health,
+        })
     }
 
     #[cfg(test)]
-    pub fn get_data_sources(&self) -> Result<Vec<MockDataSourceInstance>, LokiError> {
+    pub fn get_data_sources(&self) -> Vec<MockDataSourceInstance> {
         todo!("Deliberately no implemenented, only for testing")
     }
 
+    /// Data sources of the query, the backends of a replica set are grouped into one data source
     #[cfg(not(test))]
-    pub fn get_data_sources(&self) -> Result<Vec<DataSourceInstance>, LokiError> {
-        match self.data_sources_config.name.as_str() {
-            "static-http" => {
-                let urls_option = self.data_sources_config.urls.clone();
-                if urls_option.is_none() {
-                    return Err(LokiError::Other(Error::msg("static-http requires urls")));
-                }
-
-                let urls = urls_option.unwrap();
-
-                info!("Using static urls {}", urls.join(", "));
-                return Ok(urls.iter().map(|url| {
-                    DataSourceInstance::new(DataSource::HttpDataSource(HttpDataSource {
-                        url: url.clone(),
-                    }))
-                }).collect());
-            }
-            "static-grpc-alpha" => {
-                let urls_option = self.data_sources_config.urls.clone();
-                if urls_option.is_none() {
-                    return Err(LokiError::Other(Error::msg("static-grpc requires urls")));
-                }
-
-                let urls = urls_option.unwrap();
-
-                info!("Using static urls {}", urls.join(", "));
-                return Ok(urls.iter().map(|url| {
-                    DataSourceInstance::new(DataSource::GrpcDataSource(GrpcDataSource {
-                        url: url.clone(),
-                    }))
-                }).collect());
-            }
-            _ => {
-                error!("Unsupported datasource {}", self.data_sources_config.name);
-                return Err(LokiError::Other(Error::msg("Unsupported datasource")));
-            }
+    pub fn get_data_sources(&self) -> Vec<DataSourceInstance> {
+        self.data_sources.clone()
+    }
+
+    /// Circuit breakers of the backends, shared with the data sources
+    #[cfg(not(test))]
+    pub fn health(&self) -> &BackendsHealth {
+        &self.health
+    }
+
+    /// Probe the backends at the interval of the health checks until the process ends,
+    /// a tripped backend is probed again once its circuit breaker is half open
+    #[cfg(not(test))]
+    pub async fn run_health_checks(self) {
+        let interval = match self.health.config().interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let health = &self.health;
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let data_sources = self.backends.iter().cloned().map(DataSourceInstance::new).collect::<Vec<_>>();
+            future::join_all(data_sources.iter()
+                .filter(|data_source| health.should_probe(&data_source.url(), Instant::now()))
+                .map(|data_source| async move {
+                    let result = data_source.probe().await;
+                    health.record(&data_source.url(), result, Instant::now());
+                })).await;
         }
     }
-}
\ No newline at end of file
+}
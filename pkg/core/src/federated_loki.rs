@@ -1,9 +1,22 @@
-use std::collections::{HashMap, HashSet};
-use generic_loki_client::{LokiError, Response, VectorOrStream, Data, ResultType, LabelResponse, SerieResponse};
-use futures::{stream, StreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::future::Future;
+use std::time::Duration;
+use generic_loki_client::{BufferBudget, LokiClient, LokiError, QueryType, Response, ResponsePart, ResponseStream, VectorOrStream, Data, ResultType, LabelResponse, SerieResponse, Values, Stats, TailStream};
+use generic_loki_client::stats::FederationStats;
+use futures::{future, stream, FutureExt, StreamExt};
+use futures::future::LocalBoxFuture;
+use futures::stream::LocalBoxStream;
 use anyhow::Error;
 use log::{warn};
-use crate::aggregate::aggregate;
+use tokio::time::Instant;
+use crate::aggregate::limit_entries;
+use crate::config::{PartialResponseStrategy, QueryConfig};
+use crate::merge::ResponseMerger;
+use crate::pushdown::Pushdown;
+use crate::tail::{merge_tails, TailMerger};
+use logql_parser::ast::Expr;
 #[cfg(not(test))]
 use crate::datasources_provider::DataSourcesProvider;
 #[cfg(test)]
@@ -22,13 +35,16 @@ pub enum Direction {
     Backward
 }
 
-impl Direction {
-    pub fn to_string(&self) -> String {
-        match self {
-            Direction::Forward => "forward".to_string(),
-            Direction::Backward => "backward".to_string(),
-        }
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            Direction::Forward => "forward",
+            Direction::Backward => "backward",
+        })
     }
+}
+
+impl Direction {
     pub fn to_generic_loki_direction(&self) -> generic_loki_client::Direction {
         match self {
             Direction::Forward => generic_loki_client::Direction::Forward,
@@ -38,6 +54,61 @@ impl Direction {
 }
 
 const MAX_CONCURRENT_REQUESTS: usize = 8;
+/// Limit of log queries when none is requested, as in Loki
+const DEFAULT_LIMIT: i32 = 100;
+const DEFAULT_MAX_BUFFERED_BYTES: usize = 1024 * 1024 * 1024;
+/// Longest delay of tails in seconds, as in Loki
+const MAX_DELAY_FOR: u32 = 5;
+
+/// Parse the `timeout` of a request, in seconds or as a duration such as `1m30s`, as Loki does
+pub(crate) fn parse_timeout(timeout: &str) -> Result<Duration, LokiError> {
+    let invalid = || LokiError::InvalidQuery(format!("invalid timeout {}", timeout));
+    if let Ok(seconds) = timeout.parse::<f64>() {
+        return if seconds.is_finite() && seconds >= 0.0 { Ok(Duration::from_secs_f64(seconds)) } else { Err(invalid()) };
+    }
+    let mut milliseconds: u64 = 0;
+    let mut rest = timeout;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let value: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let unit_milliseconds: u64 = match &rest[..unit] {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            "w" => 7 * 24 * 60 * 60 * 1000,
+            "y" => 365 * 24 * 60 * 60 * 1000,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit..];
+        milliseconds = value.checked_mul(unit_milliseconds).and_then(|value| milliseconds.checked_add(value)).ok_or_else(invalid)?;
+    }
+    if timeout.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_millis(milliseconds))
+}
+
+/// Wait for the response of a backend until the deadline of the query, a backend missing it is reported as timed out
+async fn within_deadline<T>(deadline: Option<Instant>, request: impl Future<Output = Result<T, LokiError>>, backend: impl FnOnce() -> String) -> Result<T, LokiError> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, request).await.unwrap_or_else(|_| Err(deadline_expired(backend()))),
+        None => request.await,
+    }
+}
+
+fn deadline_expired(backend: String) -> LokiError {
+    LokiError::Timeout { backend, message: "the deadline of the query expired".to_string() }
+}
+
+/// Response of a backend to a query, received part by part
+struct BackendResponse {
+    backend: String,
+    parts: LocalBoxStream<'static, Result<ResponsePart, LokiError>>,
+}
 
 #[cfg_attr(not(test), derive(Debug, Clone))]
 #[cfg_attr(test, derive(Debug))]
@@ -46,129 +117,308 @@ pub struct FederatedLoki {
     data_sources_provider: MockDataSourcesProvider,
     #[cfg(not(test))]
     data_sources_provider: DataSourcesProvider,
+    query_config: QueryConfig,
 }
 
 impl FederatedLoki {
     #[cfg(test)]
-    pub fn new(data_sources_provider: MockDataSourcesProvider) -> Self {
+    pub fn new(data_sources_provider: MockDataSourcesProvider, query_config: QueryConfig) -> Self {
         FederatedLoki {
             data_sources_provider,
+            query_config,
         }
     }
 
     #[cfg(not(test))]
-    pub fn new(data_sources_provider: DataSourcesProvider) -> Self {
+    pub fn new(data_sources_provider: DataSourcesProvider, query_config: QueryConfig) -> Self {
         FederatedLoki {
             data_sources_provider,
+            query_config,
         }
     }
 
-    pub async fn query(&self, query: String, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<Response, LokiError> {
-        let data_sources_result = self.data_sources_provider.get_data_sources();
-        if let Err(loki_error) = data_sources_result {
-            return Err(loki_error);
+    /// Replica labels to strip from the results, none when deduplication is disabled for the request
+    fn replica_labels(&self, dedup: Option<bool>) -> &[String] {
+        if dedup.unwrap_or(true) {
+            &self.query_config.replica_labels
+        } else {
+            &[]
         }
+    }
 
-        let data_sources = data_sources_result.unwrap();
-
-        let buffered_jobs = stream::iter(data_sources)
-            .map(|data_source| {
-                let client_result = data_source.get_client();
+    /// Validate the query before fanning it out and plan its evaluation, backends would reject it anyway
+    fn parse_query(query: &str) -> Result<Expr, LokiError> {
+        logql_parser::parse(query).map_err(|error| LokiError::InvalidQuery(error.to_string()))
+    }
 
-                let query = &query;
-                async move {
-                    if let Err(loki_error) = client_result {
-                        return Err(loki_error);
-                    }
-                    let client = client_result.unwrap();
-                    let result = client.query(query.to_string(), limit, time, Some(direction.map_or(generic_loki_client::Direction::Backward, |direction| direction.to_generic_loki_direction()))).await;
-                    result
-                }
-            }).buffer_unordered(MAX_CONCURRENT_REQUESTS).collect::<Vec<Result<Response, LokiError>>>();
+    fn query_type(expr: &Expr) -> QueryType {
+        if expr.is_metric() {
+            QueryType::Metric
+        } else {
+            QueryType::Logs
+        }
+    }
 
-        let direction = direction.unwrap_or(Direction::Backward);
+    fn plan_pushdown(&self, expr: &Expr, replica_labels: &[String]) -> Result<Option<Pushdown>, LokiError> {
+        if self.query_config.aggregation_pushdown {
+            Pushdown::plan(expr, replica_labels)
+        } else {
+            Ok(None)
+        }
+    }
 
-        let responses = buffered_jobs.await;
+    /// Budget of the bytes buffered by a query, shared by all the requests sent to the backends
+    fn buffer_budget(&self) -> BufferBudget {
+        match self.query_config.max_buffered_bytes {
+            Some(0) => BufferBudget::unlimited(),
+            max_bytes => BufferBudget::new(Some(max_bytes.unwrap_or(DEFAULT_MAX_BUFFERED_BYTES))),
+        }
+    }
 
-        let aggregated_response = Self::aggregate_responses(direction, responses);
+    /// Deadline of a query, the earliest of the requested timeout and of the configured one, 0 disables them
+    fn deadline(&self, timeout: Option<String>) -> Result<Option<Instant>, LokiError> {
+        let requested = timeout.map(|timeout| parse_timeout(&timeout)).transpose()?;
+        let configured = self.query_config.query_timeout_seconds.map(Duration::from_secs);
+        let timeout = requested.into_iter().chain(configured).filter(|timeout| !timeout.is_zero()).min();
+        Ok(timeout.and_then(|timeout| Instant::now().checked_add(timeout)))
+    }
 
-        Ok(aggregated_response)
+    /// A query rejected by a backend is invalid whatever the other backends answered,
+    /// as is a query exceeding its budget
+    fn reject_bad_requests<T>(responses: Vec<Result<T, LokiError>>) -> Result<Vec<Result<T, LokiError>>, LokiError> {
+        let (bad_requests, responses): (Vec<_>, Vec<_>) = responses.into_iter()
+            .partition(|response| matches!(response, Err(LokiError::BadRequest { .. }) | Err(LokiError::LimitExceeded { .. })));
+        match bad_requests.into_iter().next() {
+            Some(Err(error)) => Err(error),
+            _ => Ok(responses),
+        }
     }
 
-    pub async fn query_range(&self, query: String, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<Response, LokiError> {
-        let data_sources_result = self.data_sources_provider.get_data_sources();
-        if let Err(loki_error) = data_sources_result {
-            return Err(loki_error);
+    /// Split the responses of the backends into the successful ones and the warnings describing the failures,
+    /// the request fails when all the backends failed or when some failed and partial responses are not allowed
+    fn partial_responses<T>(&self, responses: Vec<Result<T, LokiError>>, strategy: Option<PartialResponseStrategy>) -> Result<(Vec<T>, Vec<String>), LokiError> {
+        let (successes, failures): (Vec<_>, Vec<_>) = responses.into_iter().partition(|response| response.is_ok());
+        let successes: Vec<T> = successes.into_iter().filter_map(Result::ok).collect();
+        let mut failures = failures.into_iter().filter_map(Result::err);
+        let strategy = strategy.unwrap_or(self.query_config.partial_response_strategy);
+        match failures.next() {
+            None => Ok((successes, vec![])),
+            Some(error) if successes.is_empty() || strategy == PartialResponseStrategy::Abort => Err(error),
+            Some(error) => {
+                let warnings: Vec<String> = std::iter::once(error).chain(failures).map(|error| error.to_string()).collect();
+                warnings.iter().for_each(|warning| warn!("Answering a partial response: {}", warning));
+                Ok((successes, warnings))
+            }
         }
+    }
 
-        let data_sources = data_sources_result.unwrap();
+    /// Partial responses of every query of a pushdown, warnings of a backend are reported once
+    fn pushdown_responses(&self, responses: Vec<Vec<Result<Response, LokiError>>>, strategy: Option<PartialResponseStrategy>) -> Result<(Vec<Vec<Response>>, Vec<String>), LokiError> {
+        let mut all_warnings: Vec<String> = Vec::new();
+        let responses = responses.into_iter().map(|responses| {
+            let (responses, warnings) = self.partial_responses(responses, strategy)?;
+            warnings.into_iter().for_each(|warning| if !all_warnings.contains(&warning) { all_warnings.push(warning) });
+            Ok(responses)
+        }).collect::<Result<Vec<Vec<Response>>, LokiError>>()?;
+        Ok((responses, all_warnings))
+    }
 
-        let buffered_jobs = stream::iter(data_sources)
-            .map(|data_source| {
-                let client_result = data_source.get_client();
+    /// Send a query to every backend, their responses are received part by part once they are read
+    fn fan_out(&self, budget: &BufferBudget, deadline: Option<Instant>, request: impl Fn(Box<dyn LokiClient>) -> LocalBoxFuture<'static, Result<ResponseStream, LokiError>>) -> Vec<BackendResponse> {
+        let data_sources = self.data_sources_provider.get_data_sources();
+        data_sources.into_iter().map(|data_source| {
+            let response = match data_source.get_client(budget, deadline) {
+                Ok(client) => request(client),
+                Err(loki_error) => future::ready(Err(loki_error)).boxed_local(),
+            };
+            let parts = stream::once(response).flat_map(|response| match response {
+                Ok(parts) => parts,
+                Err(loki_error) => stream::once(future::ready(Err(loki_error))).boxed(),
+            });
+            BackendResponse { backend: data_source.url(), parts: parts.boxed_local() }
+        }).collect()
+    }
 
-                let query = &query;
-                let step = step.clone();
-                let interval = interval.clone();
-                async move {
-                    if let Err(loki_error) = client_result {
-                        return Err(loki_error);
+    #[allow(clippy::too_many_arguments)]
+    fn fan_out_query(&self, query: &str, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>, budget: &BufferBudget, deadline: Option<Instant>) -> Vec<BackendResponse> {
+        let direction = Some(direction.map_or(generic_loki_client::Direction::Backward, |direction| direction.to_generic_loki_direction()));
+        self.fan_out(budget, deadline, |client| {
+            let query = query.to_string();
+            async move { client.query_stream(query, query_type, limit, time, direction).await }.boxed_local()
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn fan_out_query_range(&self, query: &str, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: &Option<String>, interval: &Option<String>, budget: &BufferBudget, deadline: Option<Instant>) -> Vec<BackendResponse> {
+        let direction = Some(direction.map_or(generic_loki_client::Direction::Backward, |direction| direction.to_generic_loki_direction()));
+        self.fan_out(budget, deadline, |client| {
+            let query = query.to_string();
+            let step = step.clone();
+            let interval = interval.clone();
+            async move { client.query_range_stream(query, query_type, start, end, limit, direction, step, interval).await }.boxed_local()
+        })
+    }
+
+    /// Receive the whole responses of the backends until the deadline of the query, for queries which are combined once received.
+    /// The responses are held in the budget of the query until the reservation is dropped.
+    async fn receive_responses(backends: Vec<BackendResponse>, budget: &BufferBudget, deadline: Option<Instant>) -> Result<(Vec<Result<Response, LokiError>>, generic_loki_client::Reservation), LokiError> {
+        let received = future::join_all(backends.into_iter().map(|BackendResponse { backend, parts }| {
+            within_deadline(deadline, Response::from_stream(parts), move || backend)
+        })).await;
+        let mut reservation = budget.reservation();
+        let responses = received.into_iter().map(|received| received.map(|(response, response_reservation)| {
+            reservation.absorb(response_reservation);
+            response
+        })).collect();
+        Ok((Self::reject_bad_requests(responses)?, reservation))
+    }
+
+    /// Merge the responses of the backends as their parts are received, see [ResponseMerger].
+    /// Every backend is read at once. A backend failing or missing the deadline while its response is received
+    /// is handled as a failed backend, the entries it answered so far are kept.
+    async fn merge_responses(&self, backends: Vec<BackendResponse>, mut merger: ResponseMerger, deadline: Option<Instant>, strategy: Option<PartialResponseStrategy>) -> Result<(Response, Vec<String>), LokiError> {
+        let urls: Vec<String> = backends.iter().map(|backend| backend.backend.clone()).collect();
+        let mut receiving = vec![true; urls.len()];
+        let mut parts = stream::select_all(backends.into_iter().enumerate().map(|(index, backend)| backend.parts.map(move |part| (index, part))));
+        let mut outcomes: Vec<Result<(), LokiError>> = Vec::new();
+        loop {
+            let next = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, parts.next()).await.ok(),
+                None => Some(parts.next().await),
+            };
+            match next {
+                None => {
+                    outcomes.extend(urls.iter().zip(&receiving).filter(|(_, receiving)| **receiving).map(|(url, _)| Err(deadline_expired(url.clone()))));
+                    break;
+                }
+                Some(None) => break,
+                Some(Some((index, Ok(part)))) => {
+                    if let ResponsePart::End(_) = part {
+                        receiving[index] = false;
+                        outcomes.push(Ok(()));
                     }
-                    let client = client_result.unwrap();
-                    let result = client.query_range(query.to_string(), start, end, limit, Some(direction.map_or(generic_loki_client::Direction::Backward, |direction| direction.to_generic_loki_direction())), step, interval).await;
-                    result
+                    merger.push(&urls[index], part)?;
+                }
+                //the other backends would reject the query too, and a query exceeding its budget is failed at once
+                Some(Some((_, Err(error @ (LokiError::BadRequest { .. } | LokiError::LimitExceeded { .. }))))) => return Err(error),
+                Some(Some((index, Err(error)))) => {
+                    receiving[index] = false;
+                    outcomes.push(Err(error));
                 }
-            }).buffer_unordered(MAX_CONCURRENT_REQUESTS).collect::<Vec<Result<Response, LokiError>>>();
+            }
+        }
+        let (_, warnings) = self.partial_responses(outcomes, strategy)?;
+        Ok((merger.finish(), warnings))
+    }
 
-        let direction = direction.unwrap_or(Direction::Backward);
+    #[allow(clippy::too_many_arguments)]
+    pub async fn query(&self, query: String, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>, timeout: Option<String>, dedup: Option<bool>, partial_response_strategy: Option<PartialResponseStrategy>) -> Result<Response, LokiError> {
+        let expr = Self::parse_query(&query)?;
+        let replica_labels = self.replica_labels(dedup);
+        let budget = self.buffer_budget();
+        let deadline = self.deadline(timeout)?;
+
+        if let Some(pushdown) = self.plan_pushdown(&expr, replica_labels)? {
+            let backends = pushdown.queries.iter()
+                .map(|query| self.fan_out_query(query, QueryType::Metric, limit, time, direction, &budget, deadline))
+                .collect();
+            return self.combine_pushdown(pushdown, backends, &budget, deadline, partial_response_strategy).await;
+        }
 
-        let responses = buffered_jobs.await;
+        let backends = self.fan_out_query(&query, Self::query_type(&expr), limit, time, direction, &budget, deadline);
+        let merger = self.response_merger(limit, direction, replica_labels, &budget);
+        let (mut response, warnings) = self.merge_responses(backends, merger, deadline, partial_response_strategy).await?;
+        Self::limit_streams(&mut response, limit.unwrap_or(DEFAULT_LIMIT), direction.unwrap_or(Direction::Backward))?;
+        Ok(Self::with_warnings(response, warnings))
+    }
 
-        let aggregated_response = Self::aggregate_responses(direction, responses);
+    #[allow(clippy::too_many_arguments)]
+    pub async fn query_range(&self, query: String, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>, timeout: Option<String>, dedup: Option<bool>, partial_response_strategy: Option<PartialResponseStrategy>) -> Result<Response, LokiError> {
+        let expr = Self::parse_query(&query)?;
+        let replica_labels = self.replica_labels(dedup);
+        let budget = self.buffer_budget();
+        let deadline = self.deadline(timeout)?;
+
+        if let Some(pushdown) = self.plan_pushdown(&expr, replica_labels)? {
+            let backends = pushdown.queries.iter()
+                .map(|query| self.fan_out_query_range(query, QueryType::Metric, start, end, limit, direction, &step, &interval, &budget, deadline))
+                .collect();
+            return self.combine_pushdown(pushdown, backends, &budget, deadline, partial_response_strategy).await;
+        }
 
-        Ok(aggregated_response)
+        let backends = self.fan_out_query_range(&query, Self::query_type(&expr), start, end, limit, direction, &step, &interval, &budget, deadline);
+        let merger = self.response_merger(limit, direction, replica_labels, &budget);
+        let (mut response, warnings) = self.merge_responses(backends, merger, deadline, partial_response_strategy).await?;
+        Self::limit_streams(&mut response, limit.unwrap_or(DEFAULT_LIMIT), direction.unwrap_or(Direction::Backward))?;
+        Ok(Self::with_warnings(response, warnings))
     }
 
-    pub async fn labels(&self, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
-        let data_sources_result = self.data_sources_provider.get_data_sources();
-        if let Err(loki_error) = data_sources_result {
-            return Err(loki_error);
-        }
+    fn response_merger(&self, limit: Option<i32>, direction: Option<Direction>, replica_labels: &[String], budget: &BufferBudget) -> ResponseMerger {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).max(0) as usize;
+        ResponseMerger::new(direction.unwrap_or(Direction::Backward), limit, replica_labels.to_vec(), self.query_config.merge_strategy, budget.reservation())
+    }
 
-        let data_sources = data_sources_result.unwrap();
+    /// The queries of a pushdown are combined once every response is received
+    async fn combine_pushdown(&self, pushdown: Pushdown, backends: Vec<Vec<BackendResponse>>, budget: &BufferBudget, deadline: Option<Instant>, strategy: Option<PartialResponseStrategy>) -> Result<Response, LokiError> {
+        let received = future::try_join_all(backends.into_iter().map(|backends| Self::receive_responses(backends, budget, deadline))).await?;
+        let (responses, _reservations): (Vec<_>, Vec<_>) = received.into_iter().unzip();
+        let (responses, warnings) = self.pushdown_responses(responses, strategy)?;
+        let stats = Self::merge_stats(responses.iter().flatten());
+        let mut response = pushdown.combine(responses)?;
+        response.data.stats = Some(stats);
+        Ok(Self::with_warnings(response, warnings))
+    }
+
+    pub async fn labels(&self, start: Option<i64>, end: Option<i64>, timeout: Option<String>, dedup: Option<bool>, partial_response_strategy: Option<PartialResponseStrategy>) -> Result<LabelResponse, LokiError> {
+        let budget = self.buffer_budget();
+        let deadline = self.deadline(timeout)?;
+        let data_sources = self.data_sources_provider.get_data_sources();
 
         let buffered_jobs = stream::iter(data_sources)
             .map(|data_source| {
-                let client_result = data_source.get_client();
+                let client_result = data_source.get_client(&budget, deadline);
 
                 async move {
                     if let Err(loki_error) = client_result {
                         return Err(loki_error);
                     }
                     let client = client_result.unwrap();
-                    let result = client.labels(start, end).await;
+                    let result = within_deadline(deadline, client.labels(start, end), || data_source.url()).await;
                     result
                 }
             }).buffer_unordered(MAX_CONCURRENT_REQUESTS).collect::<Vec<Result<LabelResponse, LokiError>>>();
 
-        let responses: Vec<Result<LabelResponse, LokiError>> = buffered_jobs.await;
+        let responses: Vec<Result<LabelResponse, LokiError>> = Self::reject_bad_requests(buffered_jobs.await)?;
+        let (responses, warnings) = self.partial_responses(responses, partial_response_strategy)?;
 
-        let aggregated_label_response = Self::merge_label_responses(responses);
+        let mut aggregated_label_response = Self::merge_label_responses(responses, warnings);
+
+        let replica_labels = self.replica_labels(dedup);
+        if let Some(data) = aggregated_label_response.data.as_mut() {
+            data.retain(|label| !replica_labels.contains(label));
+        }
 
         Ok(aggregated_label_response)
     }
 
-    pub async fn label_values(&self, label: String, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
-        let data_sources_result = self.data_sources_provider.get_data_sources();
-        if let Err(loki_error) = data_sources_result {
-            return Err(loki_error);
+    #[allow(clippy::too_many_arguments)]
+    pub async fn label_values(&self, label: String, start: Option<i64>, end: Option<i64>, timeout: Option<String>, dedup: Option<bool>, partial_response_strategy: Option<PartialResponseStrategy>) -> Result<LabelResponse, LokiError> {
+        if self.replica_labels(dedup).contains(&label) {
+            //replica labels are stripped from every result, they have no value from the federation point of view
+            return Ok(LabelResponse {
+                status: "success".to_string(),
+                data: Some(vec![]),
+                warnings: vec![],
+            });
         }
 
-        let data_sources = data_sources_result.unwrap();
+        let budget = self.buffer_budget();
+        let deadline = self.deadline(timeout)?;
+        let data_sources = self.data_sources_provider.get_data_sources();
 
         let buffered_jobs = stream::iter(data_sources)
             .map(|data_source| {
-                let client_result = data_source.get_client();
+                let client_result = data_source.get_client(&budget, deadline);
 
                 let label = &label;
                 async move {
@@ -176,29 +426,28 @@ impl FederatedLoki {
                         return Err(loki_error);
                     }
                     let client = client_result.unwrap();
-                    let result = client.label_values(label.to_string(), start, end).await;
+                    let result = within_deadline(deadline, client.label_values(label.to_string(), start, end), || data_source.url()).await;
                     result
                 }
             }).buffer_unordered(MAX_CONCURRENT_REQUESTS).collect::<Vec<Result<LabelResponse, LokiError>>>();
 
-        let responses: Vec<Result<LabelResponse, LokiError>> = buffered_jobs.await;
+        let responses: Vec<Result<LabelResponse, LokiError>> = Self::reject_bad_requests(buffered_jobs.await)?;
+        let (responses, warnings) = self.partial_responses(responses, partial_response_strategy)?;
 
-        let aggregated_label_response = Self::merge_label_responses(responses);
+        let aggregated_label_response = Self::merge_label_responses(responses, warnings);
 
         Ok(aggregated_label_response)
     }
 
-    pub async fn series(&self, matches: Option<Vec<String>>, start: Option<i64>, end: Option<i64>) -> Result<SerieResponse, LokiError> {
-        let data_sources_result = self.data_sources_provider.get_data_sources();
-        if let Err(loki_error) = data_sources_result {
-            return Err(loki_error);
-        }
-
-        let data_sources = data_sources_result.unwrap();
+    #[allow(clippy::too_many_arguments)]
+    pub async fn series(&self, matches: Option<Vec<String>>, start: Option<i64>, end: Option<i64>, timeout: Option<String>, dedup: Option<bool>, partial_response_strategy: Option<PartialResponseStrategy>) -> Result<SerieResponse, LokiError> {
+        let budget = self.buffer_budget();
+        let deadline = self.deadline(timeout)?;
+        let data_sources = self.data_sources_provider.get_data_sources();
 
         let buffered_jobs = stream::iter(data_sources)
             .map(|data_source| {
-                let client_result = data_source.get_client();
+                let client_result = data_source.get_client(&budget, deadline);
 
                 let matches = matches.clone();
                 async move {
@@ -206,32 +455,69 @@ impl FederatedLoki {
                         return Err(loki_error);
                     }
                     let client = client_result.unwrap();
-                    let result = client.series(matches, start, end).await;
+                    let result = within_deadline(deadline, client.series(matches, start, end), || data_source.url()).await;
                     result
                 }
             }).buffer_unordered(MAX_CONCURRENT_REQUESTS).collect::<Vec<Result<SerieResponse, LokiError>>>();
 
-        let responses: Vec<Result<SerieResponse, LokiError>> = buffered_jobs.await;
+        let responses: Vec<Result<SerieResponse, LokiError>> = Self::reject_bad_requests(buffered_jobs.await)?;
+        let (responses, warnings) = self.partial_responses(responses, partial_response_strategy)?;
 
-        let aggregated_serie_response = Self::merge_serie_responses(responses);
+        let aggregated_serie_response = Self::merge_serie_responses(responses, self.replica_labels(dedup), warnings);
 
         Ok(aggregated_serie_response)
     }
 
-    fn merge_serie_responses(responses: Vec<Result<SerieResponse, LokiError>>) -> SerieResponse {
+    /// Tail every backend, entries are merged as they are received, see [TailMerger].
+    /// Backends failing to start the tail are handled as failed backends of a query.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn tail(&self, query: String, delay_for: Option<u32>, limit: Option<i32>, start: Option<i64>, dedup: Option<bool>, partial_response_strategy: Option<PartialResponseStrategy>) -> Result<TailStream, LokiError> {
+        if Self::parse_query(&query)?.is_metric() {
+            return Err(LokiError::InvalidQuery("tailing metric queries is not supported".to_string()));
+        }
+        if delay_for.unwrap_or(0) > MAX_DELAY_FOR {
+            return Err(LokiError::InvalidQuery(format!("delay_for can't be greater than {}", MAX_DELAY_FOR)));
+        }
+        let limit = limit.unwrap_or(DEFAULT_LIMIT);
+        if limit <= 0 {
+            return Err(LokiError::InvalidQuery("limit must be a positive value".to_string()));
+        }
+
+        //tails are unbounded, they are not accounted in a budget
+        let budget = BufferBudget::unlimited();
+        let data_sources = self.data_sources_provider.get_data_sources();
+
+        let buffered_jobs = stream::iter(data_sources)
+            .map(|data_source| {
+                let client_result = data_source.get_client(&budget, None);
+
+                let query = &query;
+                async move {
+                    client_result?.tail(query.to_string(), delay_for, Some(limit), start).await
+                }
+            }).buffer_unordered(MAX_CONCURRENT_REQUESTS).collect::<Vec<Result<TailStream, LokiError>>>();
+
+        let responses = Self::reject_bad_requests(buffered_jobs.await)?;
+        let (tails, _) = self.partial_responses(responses, partial_response_strategy)?;
+
+        let merger = TailMerger::new(self.replica_labels(dedup).to_vec(), delay_for.unwrap_or(0));
+        Ok(merge_tails(tails, merger, partial_response_strategy.unwrap_or(self.query_config.partial_response_strategy)))
+    }
+
+    pub(crate) fn strip_replica_labels(labels: &HashMap<String, String>, replica_labels: &[String]) -> HashMap<String, String> {
+        labels.iter()
+            .filter(|(key, _)| !replica_labels.contains(key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn merge_serie_responses(responses: Vec<SerieResponse>, replica_labels: &[String], warnings: Vec<String>) -> SerieResponse {
         let mut series_data: Vec<HashMap<String, String>> = Vec::new();
 
         for response in responses {
-            match response {
-                Ok(response) => {
-                    response.data.iter().for_each(|serie| {
-                        series_data.push(serie.clone());
-                    });
-                },
-                Err(error) => {
-                    warn!("One of the result contains an error: {}", error);
-                }
-            }
+            response.data.iter().for_each(|serie| {
+                series_data.push(Self::strip_replica_labels(serie, replica_labels));
+            });
         }
 
         //remove duplicates in series data
@@ -247,38 +533,27 @@ impl FederatedLoki {
         SerieResponse {
             data: already_seen_series,
             status: "success".to_string(),
+            warnings,
         }
     }
 
-    fn merge_label_responses(responses: Vec<Result<LabelResponse, LokiError>>) -> LabelResponse {
-        responses.into_iter().fold(LabelResponse { status: "success".to_string(), data: None }, |mut acc, response| {
-            match response {
-                Ok(response) => {
-                    match response.data {
-                        Some(data) => {
-                            let some_acc_data = acc.data.unwrap_or(Vec::new());
-                            let merged_data = some_acc_data.into_iter().chain(data.into_iter()).collect::<HashSet<String>>();
-                            acc.data = Some(merged_data.into_iter().collect::<Vec<String>>());
-                        }
-                        None => {}
-                    }
-                    acc
-                },
-                Err(error) => {
-                    warn!("One of the result contains an error: {}", error);
-                    acc
-                }
+    fn merge_label_responses(responses: Vec<LabelResponse>, warnings: Vec<String>) -> LabelResponse {
+        responses.into_iter().fold(LabelResponse { status: "success".to_string(), data: None, warnings }, |mut acc, response| {
+            if let Some(data) = response.data {
+                let some_acc_data = acc.data.unwrap_or_default();
+                let merged_data = some_acc_data.into_iter().chain(data).collect::<HashSet<String>>();
+                acc.data = Some(merged_data.into_iter().collect::<Vec<String>>());
             }
+            acc
         })
     }
 
-    fn get_stream_data(stream: &VectorOrStream) -> Result<Vec<(i64, String)>, LokiError> {
-        match &stream.values {
-            Some(values) => {
-                let mut result = Vec::new();
-                for value in values {
-                    let timestamp = value.0.clone();
-                    let value = value.1.clone();
+    /// Entries are moved out of the stream, lines are never copied while merging
+    pub(crate) fn take_stream_data(stream: &mut VectorOrStream) -> Result<Vec<(i64, String)>, LokiError> {
+        match stream.values.take() {
+            Some(Values::Streams(values)) => {
+                let mut result = Vec::with_capacity(values.len());
+                for (timestamp, value) in values {
                     match timestamp.parse::<i64>() {
                         Ok(ts) => result.push((ts, value)),
                         Err(e) => return Err(LokiError::Other(Error::new(e)))
@@ -286,49 +561,87 @@ impl FederatedLoki {
                 }
                 Ok(result)
             }
-            None => Err(LokiError::NoData),//TODO: better error handling
+            _ => Err(LokiError::NoData),//TODO: better error handling
         }
     }
 
-    fn replace_stream_data(stream: &mut VectorOrStream, data: Vec<(i64, String)>) -> &mut VectorOrStream {
-        stream.values = Some(data.iter().map(|(timestamp, value)| {
-            (timestamp.to_string(), value.to_string())
-        }).collect::<Vec<(String, String)>>());
+    pub(crate) fn replace_stream_data(stream: &mut VectorOrStream, data: Vec<(i64, String)>) -> &mut VectorOrStream {
+        stream.values = Some(Values::Streams(data.into_iter().map(|(timestamp, value)| {
+            (timestamp.to_string(), value)
+        }).collect::<Vec<(String, String)>>()));
         stream
     }
 
-    fn aggregate_responses(direction: Direction, responses: Vec<Result<Response, LokiError>>) -> Response {
-        let mut aggregated_response: Response = Response {
-            data: Data {
-                result_type: ResultType::Streams,
-                result: vec![],
-            },
-            status: "success".to_string(),
-        };
+    /// Fingerprint of a label set, independent of the order of the labels
+    pub(crate) fn fingerprint(labels: &Option<HashMap<String, String>>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        labels.as_ref().map(|labels| labels.iter().collect::<BTreeMap<&String, &String>>()).hash(&mut hasher);
+        hasher.finish()
+    }
 
-        responses.iter().for_each(|result| {
-            match result {
-                Ok(response) => {
-                    response.data.result.iter().for_each(|stream| {
-                        let aggregated_stream = aggregated_response.data.result.iter_mut().find(|s| s.stream == stream.stream);//TODO: add replica-labels
-                        match aggregated_stream {
-                            Some(mut aggregated_stream) => {
-                                let aggregated_data = Self::get_stream_data(&aggregated_stream).unwrap();
-                                let current_data = Self::get_stream_data(stream).unwrap();
-                                let merged = aggregate(aggregated_data, current_data, direction);
-                                Self::replace_stream_data(&mut aggregated_stream, merged);
-                            },
-                            None => {
-                                aggregated_response.data.result.push(stream.clone());
-                            }
-                        }
-                    });
-                },
-                Err(error) => {
-                    warn!("One of the result contains an error: {}", error);
-                },
+    /// Entries (log lines or samples) of a stream, a serie or a sample
+    pub(crate) fn count_result_entries(result: &VectorOrStream) -> usize {
+        match &result.values {
+            Some(Values::Streams(values)) => values.len(),
+            Some(Values::Matrix(values)) => values.len(),
+            None => result.value.iter().count(),
+        }
+    }
+
+    /// Entries (log lines or samples) of a result
+    fn count_entries(data: &Data) -> usize {
+        data.result.iter().map(Self::count_result_entries).sum()
+    }
+
+    /// Sum the stats of the backends responses and count the entries received
+    fn merge_stats<'a>(responses: impl IntoIterator<Item = &'a Response>) -> Stats {
+        let mut stats = Stats::default();
+        let mut entries_before_dedup = 0;
+        for response in responses {
+            if let Some(response_stats) = &response.data.stats {
+                stats.merge(response_stats);
             }
-        });
-        aggregated_response
+            entries_before_dedup += Self::count_entries(&response.data);
+        }
+        Self::federation_stats(stats, entries_before_dedup)
+    }
+
+    /// Complete the summed stats of the backends with the entries received and the backends queried
+    pub(crate) fn federation_stats(mut stats: Stats, entries_before_dedup: usize) -> Stats {
+        let federation = stats.federation.get_or_insert_with(FederationStats::default);
+        federation.entries_before_dedup = entries_before_dedup;
+        federation.backends_queried = federation.backends.iter().map(|backend| &backend.backend).collect::<HashSet<&String>>().len();
+        stats
+    }
+
+    /// Complete the stats of a merged response with the entries answered and the failures of backends
+    fn with_warnings(mut response: Response, warnings: Vec<String>) -> Response {
+        let entries_after_dedup = Self::count_entries(&response.data);
+        let federation = response.data.stats.get_or_insert_with(Stats::default).federation.get_or_insert_with(FederationStats::default);
+        federation.entries_after_dedup = entries_after_dedup;
+        federation.backends_failed = warnings.len();
+        federation.backends_queried += warnings.len();
+        response.warnings = warnings;
+        response
+    }
+
+    /// Keep the `limit` first entries across all streams in the direction of the query, as Loki does.
+    /// Streams are sorted by labels so entries sharing a timestamp are picked in a stable order.
+    fn limit_streams(response: &mut Response, limit: i32, direction: Direction) -> Result<(), LokiError> {
+        if response.data.result_type != ResultType::Streams {
+            return Ok(());
+        }
+        let streams = &mut response.data.result;
+        streams.sort_by_cached_key(|stream| stream.stream.clone().map(|labels| labels.into_iter().collect::<BTreeMap<String, String>>()));
+        //entries of each stream are sorted in the direction of the query by the merge
+        let streams_data = streams.iter_mut().map(Self::take_stream_data).collect::<Result<Vec<Vec<(i64, String)>>, LokiError>>()?;
+        let timestamps = streams_data.iter().map(|data| data.iter().map(|(timestamp, _)| *timestamp).collect()).collect::<Vec<Vec<i64>>>();
+        let kept = limit_entries(&timestamps, limit.max(0) as usize, direction);
+        for ((stream, mut data), kept) in streams.iter_mut().zip(streams_data).zip(kept) {
+            data.truncate(kept);
+            Self::replace_stream_data(stream, data);
+        }
+        streams.retain(|stream| matches!(&stream.values, Some(Values::Streams(values)) if !values.is_empty()));
+        Ok(())
     }
 }
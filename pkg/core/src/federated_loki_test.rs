@@ -2,45 +2,66 @@
 mod tests {
     use std::collections::HashMap;
     use std::future;
-    use generic_loki_client::{Data, LabelResponse, LokiClient, LokiError, Response, ResultType, SerieResponse, VectorOrStream};
-    use mockall::{automock, predicate};
-    use async_trait::async_trait;
+    use std::time::Duration;
+    use generic_loki_client::{Data, LabelResponse, LokiClient, LokiError, QueryType, Response, ResultType, SerieResponse, TailResponse, TailStream, Values, VectorOrStream};
+    use futures::StreamExt;
+    use mockall::predicate;
+    use http_loki_client::ClientCache;
 
+    use crate::config::{MergeStrategy, PartialResponseStrategy, QueryConfig};
     use crate::datasources_provider::{MockDataSourcesProvider, MockDataSourceInstance, DataSource, HttpDataSource};
     use crate::federated_loki::*;
+    use crate::retry::RetryPolicy;
+    use crate::health::BackendsHealth;
+    use crate::test_loki_client::MockTestLokiClient;
 
 
-    #[derive(Debug, Clone)]
-    struct TestLokiClient {}
-
-    #[async_trait]
-    #[automock]
-    impl LokiClient for TestLokiClient {
-        async fn query(&self, _: String, _: Option<i32>, _: Option<i64>, _: Option<generic_loki_client::Direction>) -> Result<Response, LokiError> { todo!() }
-        async fn query_range(&self, _: String, _: i64, _: i64, _: Option<i32>, _: Option<generic_loki_client::Direction>, _: Option<String>, _: Option<String>) -> Result<Response, LokiError> { todo!() }
-        async fn labels(&self, _: Option<i64>, _: Option<i64>) -> Result<LabelResponse, LokiError> { todo!() }
-        async fn label_values(&self, _: String, _: Option<i64>, _: Option<i64>) -> Result<LabelResponse, LokiError> { todo!() }
-        async fn series(&self, _: Option<Vec<String>>, _: Option<i64>, _: Option<i64>) -> Result<SerieResponse, LokiError> { todo!() }
+    /// Backend which never answers a query
+    fn mock_hanging_client() -> MockTestLokiClient {
+        let mut mock_client: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client.expect_query().returning(|_, _, _, _, _| Box::pin(future::pending()));
+        mock_client.expect_labels().returning(|_, _| Box::pin(future::pending()));
+        mock_client
     }
 
     fn sample_response(result: Vec<(String, String)>) -> Response {
+        sample_response_with_labels(HashMap::from([
+            ("label".to_string(), "value".to_string())
+        ]), result)
+    }
+
+    fn sample_response_with_labels(labels: HashMap<String, String>, result: Vec<(String, String)>) -> Response {
         Response {
             status: "success".to_string(),
             data: Data {
                 result_type: ResultType::Streams,
                 result: vec![VectorOrStream {
-                    stream: Some(HashMap::from([
-                        ("label".to_string(), "value".to_string())
-                    ])),
-                    values: Some(result),
+                    stream: Some(labels),
+                    values: Some(Values::Streams(result)),
                     value: None,
                     metric: None,
                 }],
-            }
+                stats: None,
+            },
+            warnings: vec![],
+        }
+    }
+
+    fn replica_labels(replica: &str) -> HashMap<String, String> {
+        HashMap::from([
+            ("label".to_string(), "value".to_string()),
+            ("replica".to_string(), replica.to_string()),
+        ])
+    }
+
+    fn replica_query_config() -> QueryConfig {
+        QueryConfig {
+            replica_labels: vec!["replica".to_string()],
+            ..QueryConfig::default()
         }
     }
 
-    fn mock_datasource_instance(client: MockTestLokiClient) -> MockDataSourceInstance {
+    fn mock_datasource_instance(client: impl LokiClient + 'static, url: &str) -> MockDataSourceInstance {
         let ds_ctx = MockDataSourceInstance::new_context();
 
         ds_ctx.expect()
@@ -49,20 +70,26 @@ mod tests {
             });
 
         let mut mock_ds = MockDataSourceInstance::new(DataSource::HttpDataSource(HttpDataSource {
-            url: "http://localhost:3100".to_string()
+            url: "http://localhost:3100".to_string(),
+            clients: ClientCache::default(),
+            retry_policy: RetryPolicy::default(),
+            health: BackendsHealth::default(),
         }));
 
+        let url = url.to_string();
+        mock_ds.expect_url()
+            .returning(move || url.clone());
         mock_ds.expect_get_client()
-            .with()
-            .return_once( || {
+            .with(predicate::always(), predicate::always())
+            .return_once(|_, _| {
                 Ok(Box::new(client))
             });
         mock_ds
     }
 
-    fn mock_datasource_provider(client_a: MockTestLokiClient, client_b: MockTestLokiClient) -> MockDataSourcesProvider {
-        let mocked_data_source_a = mock_datasource_instance(client_a);
-        let mocked_data_source_b = mock_datasource_instance(client_b);
+    fn mock_datasource_provider(client_a: impl LokiClient + 'static, client_b: impl LokiClient + 'static) -> MockDataSourcesProvider {
+        let mocked_data_source_a = mock_datasource_instance(client_a, "http://localhost:3100");
+        let mocked_data_source_b = mock_datasource_instance(client_b, "http://localhost:3101");
 
         let provider_ctx = MockDataSourcesProvider::new_context();
 
@@ -74,15 +101,15 @@ mod tests {
         let mut provider = MockDataSourcesProvider::new();
 
         provider.expect_get_data_sources()
-            .return_once(move || Ok(vec![mocked_data_source_a, mocked_data_source_b]));
+            .return_once(move || vec![mocked_data_source_a, mocked_data_source_b]);
         provider
     }
 
     fn get_response_result(response: Response) -> Vec<(String, String)> {
         let mut result: Vec<(String, String)> = vec![];
         for stream in response.data.result {
-            for (key, value) in stream.values.unwrap() {
-                result.push((key, value));
+            if let Some(Values::Streams(values)) = stream.values {
+                result.extend(values);
             }
         }
         result
@@ -93,8 +120,8 @@ mod tests {
 
         let mut mock_client_a: MockTestLokiClient = MockTestLokiClient::new();
         mock_client_a.expect_query()
-            .with(predicate::eq("{job=\"foo\"}[5m]".to_string()), predicate::eq(None), predicate::eq(None), predicate::always())
-            .return_once(|_, _, _, _| {
+            .with(predicate::eq("{job=\"foo\"}".to_string()), predicate::eq(QueryType::Logs), predicate::eq(None), predicate::eq(None), predicate::always())
+            .return_once(|_, _, _, _, _| {
                 Box::pin(future::ready(Ok(sample_response(vec![
                     ("4".to_string(), "d".to_string()),
                     ("2".to_string(), "b".to_string()),
@@ -104,8 +131,8 @@ mod tests {
 
         let mut mock_client_b: MockTestLokiClient = MockTestLokiClient::new();
         mock_client_b.expect_query()
-            .with(predicate::eq("{job=\"foo\"}[5m]".to_string()), predicate::eq(None), predicate::eq(None), predicate::always())
-            .return_once(|_, _, _, _| {
+            .with(predicate::eq("{job=\"foo\"}".to_string()), predicate::eq(QueryType::Logs), predicate::eq(None), predicate::eq(None), predicate::always())
+            .return_once(|_, _, _, _, _| {
                 Box::pin(future::ready(Ok(sample_response(vec![
                     ("4".to_string(), "d".to_string()),
                     ("3".to_string(), "c".to_string()),
@@ -113,9 +140,9 @@ mod tests {
                 ]))))
             });
 
-        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b));
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
 
-        let aggregated_response = loki.query("{job=\"foo\"}[5m]".to_string(), None, None, None).await.unwrap();
+        let aggregated_response = loki.query("{job=\"foo\"}".to_string(), None, None, None, None, None, None).await.unwrap();
         assert_eq!(get_response_result(aggregated_response), vec![
             ("4".to_string(), "d".to_string()),
             ("3".to_string(), "c".to_string()),
@@ -123,4 +150,451 @@ mod tests {
             ("1".to_string(), "a".to_string()),
         ]);
     }
-}
\ No newline at end of file
+
+    fn mock_replica_query_client(replica: &'static str, result: Vec<(String, String)>) -> MockTestLokiClient {
+        let mut mock_client: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client.expect_query()
+            .return_once(move |_, _, _, _, _| {
+                Box::pin(future::ready(Ok(sample_response_with_labels(replica_labels(replica), result))))
+            });
+        mock_client
+    }
+
+    #[tokio::test]
+    async fn it_should_deduplicate_replica_streams() {
+        let mock_client_a = mock_replica_query_client("a", vec![
+            ("2".to_string(), "b".to_string()),
+            ("1".to_string(), "a".to_string()),
+        ]);
+        let mock_client_b = mock_replica_query_client("b", vec![
+            ("3".to_string(), "c".to_string()),
+            ("1".to_string(), "a".to_string()),
+        ]);
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), replica_query_config());
+
+        let aggregated_response = loki.query("{label=\"value\"}".to_string(), None, None, None, None, None, None).await.unwrap();
+        assert_eq!(aggregated_response.data.result.len(), 1);
+        assert_eq!(aggregated_response.data.result[0].stream, Some(HashMap::from([("label".to_string(), "value".to_string())])));
+        assert_eq!(get_response_result(aggregated_response), vec![
+            ("3".to_string(), "c".to_string()),
+            ("2".to_string(), "b".to_string()),
+            ("1".to_string(), "a".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_invalid_queries_before_fanning_out() {
+        let loki = FederatedLoki::new(mock_datasource_provider(MockTestLokiClient::new(), MockTestLokiClient::new()), QueryConfig {
+            aggregation_pushdown: true,
+            ..QueryConfig::default()
+        });
+
+        let error = loki.query("sum(rate({app=\"x\"}))".to_string(), None, None, None, None, None, None).await.unwrap_err();
+        assert!(matches!(error, LokiError::InvalidQuery(_)));
+        assert_eq!((error.status_code(), error.error_type()), (400, "bad_data"));
+        assert!(error.to_string().starts_with("parse error at line 1, col 19: "), "{}", error);
+        let error = loki.query_range("{app=\"x\"} |= \"a\" or".to_string(), 0, 1, None, None, None, None, None, None, None).await.unwrap_err();
+        assert_eq!(error.status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_replica_streams_when_dedup_is_disabled() {
+        let mock_client_a = mock_replica_query_client("a", vec![
+            ("1".to_string(), "a".to_string()),
+        ]);
+        let mock_client_b = mock_replica_query_client("b", vec![
+            ("1".to_string(), "a".to_string()),
+        ]);
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), replica_query_config());
+
+        let aggregated_response = loki.query("{label=\"value\"}".to_string(), None, None, None, None, Some(false), None).await.unwrap();
+        assert_eq!(aggregated_response.data.result.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_deduplicate_replica_series() {
+        let mut mock_client_a: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_a.expect_series()
+            .return_once(|_, _, _| {
+                Box::pin(future::ready(Ok(SerieResponse { status: "success".to_string(), data: vec![replica_labels("a")], warnings: vec![] })))
+            });
+        let mut mock_client_b: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_b.expect_series()
+            .return_once(|_, _, _| {
+                Box::pin(future::ready(Ok(SerieResponse { status: "success".to_string(), data: vec![replica_labels("b")], warnings: vec![] })))
+            });
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), replica_query_config());
+
+        let series = loki.series(None, None, None, None, None, None).await.unwrap();
+        assert_eq!(series.data, vec![HashMap::from([("label".to_string(), "value".to_string())])]);
+    }
+
+    #[tokio::test]
+    async fn it_should_hide_replica_labels() {
+        let mut mock_client_a: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_a.expect_labels()
+            .return_once(|_, _| {
+                Box::pin(future::ready(Ok(LabelResponse { status: "success".to_string(), data: Some(vec!["label".to_string(), "replica".to_string()]), warnings: vec![] })))
+            });
+        let mut mock_client_b: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_b.expect_labels()
+            .return_once(|_, _| {
+                Box::pin(future::ready(Ok(LabelResponse { status: "success".to_string(), data: Some(vec!["replica".to_string()]), warnings: vec![] })))
+            });
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), replica_query_config());
+
+        let labels = loki.labels(None, None, None, None, None).await.unwrap();
+        assert_eq!(labels.data, Some(vec!["label".to_string()]));
+    }
+
+    fn matrix_response(json: &str) -> Response {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_should_aggregate_matrix_query_range() {
+        let mut mock_client_a: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_a.expect_query_range()
+            .return_once(|_, _, _, _, _, _, _, _| {
+                Box::pin(future::ready(Ok(matrix_response(r#"{"status":"success","data":{"resultType":"matrix","result":[{"metric":{"app":"x"},"values":[[1588889221,"1"],[1588889236.5,"2"]]}]}}"#))))
+            });
+        let mut mock_client_b: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_b.expect_query_range()
+            .return_once(|_, _, _, _, _, _, _, _| {
+                Box::pin(future::ready(Ok(matrix_response(r#"{"status":"success","data":{"resultType":"matrix","result":[{"metric":{"app":"x"},"values":[[1588889236.5,"2"],[1588889251,"3"]]},{"metric":{"app":"y"},"values":[[1588889221,"4"]]}]}}"#))))
+            });
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
+
+        let aggregated_response = loki.query_range("rate({app=~\"x|y\"}[5m])".to_string(), 0, 1, None, None, None, None, None, None, None).await.unwrap();
+        assert_eq!(aggregated_response.data.result_type, ResultType::Matrix);
+        assert_eq!(aggregated_response.data.result.len(), 2);
+        assert_eq!(aggregated_response.data.result[0].values, Some(Values::Matrix(vec![
+            (1588889221.0, "1".to_string()),
+            (1588889236.5, "2".to_string()),
+            (1588889251.0, "3".to_string()),
+        ])));
+        assert_eq!(
+            serde_json::to_string(&aggregated_response.data.result[1]).unwrap(),
+            r#"{"metric":{"app":"y"},"values":[[1588889221.0,"4"]]}"#
+        );
+    }
+
+    fn mock_vector_query_client(json: &'static str) -> MockTestLokiClient {
+        let mut mock_client: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client.expect_query()
+            .return_once(move |_, _, _, _, _| {
+                Box::pin(future::ready(Ok(serde_json::from_str(json).unwrap())))
+            });
+        mock_client
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_one_sample_of_replicated_vectors() {
+        let mock_client_a = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x","replica":"a"},"value":[1588889221.123,"1"]}]}}"#);
+        let mock_client_b = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x","replica":"b"},"value":[1588889221.123,"2"]},{"metric":{"app":"y","replica":"b"},"value":[1588889221.123,"3"]}]}}"#);
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), replica_query_config());
+
+        let aggregated_response = loki.query("count_over_time({app=~\"x|y\"}[5m])".to_string(), None, None, None, None, None, None).await.unwrap();
+        assert_eq!(aggregated_response.data.result_type, ResultType::Vector);
+        assert_eq!(aggregated_response.data.result.len(), 2);
+        assert_eq!(aggregated_response.data.result[0].metric, Some(HashMap::from([("app".to_string(), "x".to_string())])));
+        assert_eq!(aggregated_response.data.result[1].metric, Some(HashMap::from([("app".to_string(), "y".to_string())])));
+        assert!(matches!(aggregated_response.data.result[0].value.as_ref().unwrap().1.as_str(), "1" | "2"));
+    }
+
+    #[tokio::test]
+    async fn it_should_sum_sharded_vectors() {
+        let mock_client_a = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x"},"value":[1588889221.123,"1"]}]}}"#);
+        let mock_client_b = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x"},"value":[1588889221.123,"2.5"]}]}}"#);
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig {
+            merge_strategy: MergeStrategy::Sum,
+            ..QueryConfig::default()
+        });
+
+        let aggregated_response = loki.query("count_over_time({app=\"x\"}[5m])".to_string(), None, None, None, None, None, None).await.unwrap();
+        assert_eq!(aggregated_response.data.result.len(), 1);
+        assert_eq!(aggregated_response.data.result[0].value, Some((1588889221.123, "3.5".to_string())));
+    }
+
+    #[tokio::test]
+    async fn it_should_fail_to_merge_different_result_types() {
+        let mock_client_a = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x"},"value":[1588889221.123,"1"]}]}}"#);
+        let mock_client_b = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"streams","result":[{"stream":{"app":"x"},"values":[["1588889221123000000","line"]]}]}}"#);
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
+
+        assert!(loki.query("{app=\"x\"}".to_string(), None, None, None, None, None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_push_down_aggregations() {
+        let mut mock_client_a: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_a.expect_query()
+            .with(predicate::eq("sum by (app) (count_over_time({app=\"x\"}[5m]))".to_string()), predicate::eq(QueryType::Metric), predicate::always(), predicate::always(), predicate::always())
+            .return_once(|_, _, _, _, _| {
+                Box::pin(future::ready(Ok(serde_json::from_str(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x"},"value":[1588889221,"1"]}]}}"#).unwrap())))
+            });
+        let mut mock_client_b: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_b.expect_query()
+            .with(predicate::eq("sum by (app) (count_over_time({app=\"x\"}[5m]))".to_string()), predicate::eq(QueryType::Metric), predicate::always(), predicate::always(), predicate::always())
+            .return_once(|_, _, _, _, _| {
+                Box::pin(future::ready(Ok(serde_json::from_str(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x"},"value":[1588889221,"2"]}]}}"#).unwrap())))
+            });
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig {
+            aggregation_pushdown: true,
+            ..QueryConfig::default()
+        });
+
+        let aggregated_response = loki.query("sum(count_over_time({app=\"x\"}[5m])) by (app)".to_string(), None, None, None, None, None, None).await.unwrap();
+        assert_eq!(aggregated_response.data.result.len(), 1);
+        assert_eq!(aggregated_response.data.result[0].value, Some((1588889221.0, "3".to_string())));
+    }
+
+    fn mock_failing_query_client(error: LokiError) -> MockTestLokiClient {
+        let mut mock_client: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client.expect_query()
+            .return_once(move |_, _, _, _, _| {
+                Box::pin(future::ready(Err(error)))
+            });
+        mock_client
+    }
+
+    fn unavailable(backend: &str) -> LokiError {
+        LokiError::Unavailable { backend: backend.to_string(), message: "connection refused".to_string(), retry_after: None }
+    }
+
+    const EMPTY_VECTOR: &str = r#"{"status":"success","data":{"resultType":"vector","result":[]}}"#;
+
+    #[tokio::test]
+    async fn it_should_fail_when_a_backend_rejects_the_query() {
+        let mock_client_a = mock_vector_query_client(EMPTY_VECTOR);
+        let mock_client_b = mock_failing_query_client(LokiError::BadRequest { backend: "http://localhost:3101".to_string(), message: "parse error at line 1, col 5".to_string() });
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
+
+        let error = loki.query("{app=\"x\"}".to_string(), None, None, None, None, None, None).await.unwrap_err();
+        assert_eq!(error.status_code(), 400);
+        assert_eq!(error.to_string(), "parse error at line 1, col 5");
+    }
+
+    #[tokio::test]
+    async fn it_should_warn_about_failed_backends() {
+        let mock_client_a = mock_vector_query_client(EMPTY_VECTOR);
+        let mock_client_b = mock_failing_query_client(unavailable("http://localhost:3101"));
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
+
+        let response = loki.query("count_over_time({app=\"x\"}[5m])".to_string(), None, None, None, None, None, None).await.unwrap();
+        assert_eq!(response.status, "success");
+        assert_eq!(response.warnings, vec!["http://localhost:3101 is unavailable: connection refused".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn it_should_abort_on_failed_backends() {
+        let mock_client_a = mock_vector_query_client(EMPTY_VECTOR);
+        let mock_client_b = mock_failing_query_client(unavailable("http://localhost:3101"));
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig {
+            partial_response_strategy: PartialResponseStrategy::Abort,
+            ..QueryConfig::default()
+        });
+
+        let error = loki.query("count_over_time({app=\"x\"}[5m])".to_string(), None, None, None, None, None, None).await.unwrap_err();
+        assert_eq!(error.status_code(), 503);
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_the_backends_which_met_the_deadline() {
+        let mock_client_a = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x"},"value":[1588889221.123,"1"]}]}}"#);
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_hanging_client()), QueryConfig::default());
+
+        let response = loki.query("count_over_time({app=\"x\"}[5m])".to_string(), None, None, None, Some("100ms".to_string()), None, None).await.unwrap();
+        assert_eq!(response.data.result.len(), 1);
+        assert_eq!(response.warnings, vec!["http://localhost:3101 timed out: the deadline of the query expired".to_string()]);
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_hanging_client(), mock_hanging_client()), QueryConfig::default());
+        let error = loki.labels(None, None, Some("0.1".to_string()), None, None).await.unwrap_err();
+        assert_eq!(error.status_code(), 504);
+    }
+
+    #[test]
+    fn it_should_parse_timeouts() {
+        assert_eq!(parse_timeout("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_timeout("1.5").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_timeout("1m30s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_timeout("250ms").unwrap(), Duration::from_millis(250));
+        for timeout in ["", "-1", "1x", "m", "1.5m", "inf"] {
+            assert!(matches!(parse_timeout(timeout), Err(LokiError::InvalidQuery(_))), "{}", timeout);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_override_the_partial_response_strategy_per_request() {
+        let mock_client_a = mock_vector_query_client(EMPTY_VECTOR);
+        let mock_client_b = mock_failing_query_client(unavailable("http://localhost:3101"));
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
+
+        let result = loki.query("count_over_time({app=\"x\"}[5m])".to_string(), None, None, None, None, None, Some(PartialResponseStrategy::Abort)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_fail_when_all_backends_failed() {
+        let mock_client_a = mock_failing_query_client(unavailable("http://localhost:3100"));
+        let mock_client_b = mock_failing_query_client(unavailable("http://localhost:3101"));
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
+
+        let error = loki.query("{app=\"x\"}".to_string(), None, None, None, None, None, None).await.unwrap_err();
+        assert_eq!(error.status_code(), 503);
+    }
+
+    #[tokio::test]
+    async fn it_should_merge_stats_of_backends() {
+        let mock_client_a = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x"},"value":[1588889221.123,"1"]}],"stats":{"summary":{"totalBytesProcessed":100,"execTime":0.5},"federation":{"backends":[{"backend":"http://localhost:3100","latencySeconds":0.5,"bytesReceived":200}]}}}}"#);
+        let mock_client_b = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"app":"x"},"value":[1588889221.123,"1"]}],"stats":{"summary":{"totalBytesProcessed":300,"execTime":1.5},"federation":{"backends":[{"backend":"http://localhost:3101","latencySeconds":1.5,"bytesReceived":300}]}}}}"#);
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
+
+        let response = loki.query("count_over_time({app=\"x\"}[5m])".to_string(), None, None, None, None, None, None).await.unwrap();
+        let stats = response.data.stats.unwrap();
+        assert_eq!(stats.summary.total_bytes_processed, 400);
+        assert_eq!(stats.summary.bytes_processed_per_second, 200);
+        let federation = stats.federation.unwrap();
+        assert_eq!(federation.backends_queried, 2);
+        assert_eq!(federation.backends_failed, 0);
+        assert_eq!(federation.entries_before_dedup, 2);
+        assert_eq!(federation.entries_after_dedup, 1);
+        assert_eq!(federation.backends.iter().map(|backend| backend.bytes_received).sum::<usize>(), 500);
+    }
+
+    #[tokio::test]
+    async fn it_should_count_failed_backends_in_stats() {
+        let mock_client_a = mock_vector_query_client(r#"{"status":"success","data":{"resultType":"vector","result":[],"stats":{"federation":{"backends":[{"backend":"http://localhost:3100","latencySeconds":0.5,"bytesReceived":200}]}}}}"#);
+        let mock_client_b = mock_failing_query_client(unavailable("http://localhost:3101"));
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
+
+        let response = loki.query("count_over_time({app=\"x\"}[5m])".to_string(), None, None, None, None, None, None).await.unwrap();
+        let federation = response.data.stats.unwrap().federation.unwrap();
+        assert_eq!(federation.backends_queried, 2);
+        assert_eq!(federation.backends_failed, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_limit_entries_across_backends() {
+        let mut mock_client_a: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_a.expect_query()
+            .return_once(|_, _, _, _, _| {
+                Box::pin(future::ready(Ok(sample_response_with_labels(HashMap::from([("app".to_string(), "a".to_string())]), vec![
+                    ("6".to_string(), "f".to_string()),
+                    ("5".to_string(), "e".to_string()),
+                    ("2".to_string(), "b".to_string()),
+                ]))))
+            });
+        let mut mock_client_b: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client_b.expect_query()
+            .return_once(|_, _, _, _, _| {
+                Box::pin(future::ready(Ok(Response {
+                    status: "success".to_string(),
+                    data: Data {
+                        result_type: ResultType::Streams,
+                        result: vec![
+                            sample_response_with_labels(HashMap::from([("app".to_string(), "b".to_string())]), vec![("4".to_string(), "d".to_string()), ("3".to_string(), "c".to_string())]).data.result.remove(0),
+                            sample_response_with_labels(HashMap::from([("app".to_string(), "c".to_string())]), vec![("1".to_string(), "a".to_string())]).data.result.remove(0),
+                        ],
+                        stats: None,
+                    },
+                    warnings: vec![],
+                })))
+            });
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig::default());
+
+        let response = loki.query("{app=~\"a|b|c\"}".to_string(), Some(3), None, None, None, None, None).await.unwrap();
+        assert_eq!(response.data.result.len(), 2);
+        let mut result = get_response_result(response);
+        result.sort();
+        assert_eq!(result, vec![
+            ("4".to_string(), "d".to_string()),
+            ("5".to_string(), "e".to_string()),
+            ("6".to_string(), "f".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn it_should_fail_queries_exceeding_their_budget() {
+        let mock_client_a = mock_vector_query_client(EMPTY_VECTOR);
+        let mock_client_b = mock_failing_query_client(LokiError::LimitExceeded { backend: "http://localhost:3101".to_string(), message: "the query exceeded the limit of 1024 buffered bytes".to_string() });
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), QueryConfig {
+            max_buffered_bytes: Some(1024),
+            ..QueryConfig::default()
+        });
+
+        let error = loki.query("{app=\"x\"}".to_string(), None, None, None, None, None, Some(PartialResponseStrategy::Warn)).await.unwrap_err();
+        assert_eq!(error.status_code(), 422);
+    }
+
+    fn mock_replica_tail_client(replica: &'static str, entries: Vec<(String, String)>) -> MockTestLokiClient {
+        let mut mock_client: MockTestLokiClient = MockTestLokiClient::new();
+        mock_client.expect_tail()
+            .return_once(move |_, _, _, _| {
+                let response = TailResponse {
+                    streams: sample_response_with_labels(replica_labels(replica), entries).data.result,
+                    dropped_entries: vec![],
+                };
+                let tail: TailStream = Box::pin(futures::stream::iter(vec![Ok(response)]));
+                Box::pin(future::ready(Ok(tail)))
+            });
+        mock_client
+    }
+
+    #[tokio::test]
+    async fn it_should_tail_replicas_once() {
+        let mock_client_a = mock_replica_tail_client("a", vec![("1".to_string(), "a".to_string()), ("2".to_string(), "b".to_string())]);
+        let mock_client_b = mock_replica_tail_client("b", vec![("2".to_string(), "b".to_string()), ("3".to_string(), "c".to_string())]);
+
+        let loki = FederatedLoki::new(mock_datasource_provider(mock_client_a, mock_client_b), replica_query_config());
+
+        let responses = loki.tail("{label=\"value\"}".to_string(), None, None, None, None, None).await.unwrap().collect::<Vec<Result<TailResponse, LokiError>>>().await;
+        let mut entries: Vec<(String, String)> = responses.into_iter()
+            .flat_map(|response| response.unwrap().streams)
+            .flat_map(|stream| {
+                assert_eq!(stream.stream, Some(HashMap::from([("label".to_string(), "value".to_string())])));
+                match stream.values {
+                    Some(Values::Streams(values)) => values,
+                    _ => vec![],
+                }
+            })
+            .collect();
+        entries.sort();
+        assert_eq!(entries, vec![
+            ("1".to_string(), "a".to_string()),
+            ("2".to_string(), "b".to_string()),
+            ("3".to_string(), "c".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_invalid_tails() {
+        let loki = FederatedLoki::new(MockDataSourcesProvider::default(), QueryConfig::default());
+
+        let error = loki.tail("{label=\"value\"}".to_string(), Some(6), None, None, None, None).await.err().unwrap();
+        assert_eq!(error.to_string(), "delay_for can't be greater than 5");
+        let error = loki.tail("{label=\"value\"}".to_string(), None, Some(0), None, None, None).await.err().unwrap();
+        assert_eq!(error.to_string(), "limit must be a positive value");
+        let error = loki.tail("count_over_time({label=\"value\"}[1m])".to_string(), None, None, None, None, None).await.err().unwrap();
+        assert_eq!(error.status_code(), 400);
+    }
+}
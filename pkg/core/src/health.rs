@@ -0,0 +1,305 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use generic_loki_client::LokiError;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::time::Instant;
+
+/// Latest probes of a backend from which its error rate is computed
+const ERROR_RATE_WINDOW: usize = 20;
+/// Probes needed before the error rate of a backend can trip its circuit breaker
+const ERROR_RATE_MIN_PROBES: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// Interval of the probes, None disables the health checks and every backend is queried
+    pub interval: Option<Duration>,
+    pub timeout: Duration,
+    /// Consecutive failed probes tripping the circuit breaker of a backend
+    pub consecutive_failures: u32,
+    /// Ratio of failed probes tripping the circuit breaker of a backend
+    pub error_rate: f64,
+    /// How long a tripped backend is left out of the queries before it is probed again
+    pub open_duration: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            interval: Some(Duration::from_secs(10)),
+            timeout: Duration::from_secs(5),
+            consecutive_failures: 3,
+            error_rate: 0.5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State of the circuit breaker of a backend, only closed circuits are queried
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// The open duration elapsed, the next probe decides whether the backend is queried again
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BackendHealth {
+    state: CircuitState,
+    opened_at: Option<Instant>,
+    consecutive_failures: u32,
+    /// Outcomes of the latest probes, true for a success
+    outcomes: VecDeque<bool>,
+    probes: u64,
+    probe_failures: u64,
+    last_probe: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+impl BackendHealth {
+    fn new() -> Self {
+        BackendHealth {
+            state: CircuitState::Closed,
+            opened_at: None,
+            consecutive_failures: 0,
+            outcomes: VecDeque::new(),
+            probes: 0,
+            probe_failures: 0,
+            last_probe: None,
+            last_error: None,
+        }
+    }
+
+    fn error_rate(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        self.outcomes.iter().filter(|success| !**success).count() as f64 / self.outcomes.len() as f64
+    }
+}
+
+/// Health of a backend as reported by the admin endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendStatus {
+    pub backend: String,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub error_rate: f64,
+    pub probes_total: u64,
+    pub probe_failures_total: u64,
+    /// Unix timestamp of the latest probe, in seconds
+    pub last_probe: Option<f64>,
+    pub last_error: Option<String>,
+}
+
+/// Circuit breakers of the backends, tripped by failing health checks so the queries stop waiting
+/// on a backend which is down. Shared by the data sources of every request and by the health checker.
+#[derive(Debug, Clone, Default)]
+pub struct BackendsHealth {
+    config: HealthConfig,
+    backends: Arc<Mutex<HashMap<String, BackendHealth>>>,
+}
+
+impl BackendsHealth {
+    pub fn new(config: HealthConfig) -> Self {
+        BackendsHealth { config, backends: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn config(&self) -> &HealthConfig {
+        &self.config
+    }
+
+    /// Whether the backend is queried, backends not probed yet are
+    pub fn is_available(&self, backend: &str) -> bool {
+        let backends = self.backends.lock().unwrap_or_else(|e| e.into_inner());
+        backends.get(backend).is_none_or(|health| health.state == CircuitState::Closed)
+    }
+
+    /// Whether the backend is probed at `now`, a tripped backend is probed once the open duration elapsed
+    pub fn should_probe(&self, backend: &str, now: Instant) -> bool {
+        let mut backends = self.backends.lock().unwrap_or_else(|e| e.into_inner());
+        let health = match backends.get_mut(backend) {
+            Some(health) => health,
+            None => return true,
+        };
+        match (health.state, health.opened_at) {
+            (CircuitState::Open, Some(opened_at)) if now < opened_at + self.config.open_duration => false,
+            (CircuitState::Open, _) => {
+                health.state = CircuitState::HalfOpen;
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// Account the result of a probe of the backend, tripping or resetting its circuit breaker
+    pub fn record(&self, backend: &str, result: Result<(), LokiError>, now: Instant) {
+        let mut backends = self.backends.lock().unwrap_or_else(|e| e.into_inner());
+        let health = backends.entry(backend.to_string()).or_insert_with(BackendHealth::new);
+        health.probes += 1;
+        health.last_probe = Some(SystemTime::now());
+        health.outcomes.push_back(result.is_ok());
+        if health.outcomes.len() > ERROR_RATE_WINDOW {
+            health.outcomes.pop_front();
+        }
+        let error = match result {
+            Ok(()) => {
+                health.consecutive_failures = 0;
+                if health.state != CircuitState::Closed {
+                    info!("{} recovered, querying it again", backend);
+                    health.state = CircuitState::Closed;
+                    health.opened_at = None;
+                    //failures before the recovery don't count against the backend anymore
+                    health.outcomes.retain(|success| *success);
+                }
+                return;
+            }
+            Err(error) => error,
+        };
+        health.probe_failures += 1;
+        health.consecutive_failures += 1;
+        let tripped = health.consecutive_failures >= self.config.consecutive_failures
+            || (health.outcomes.len() >= ERROR_RATE_MIN_PROBES && health.error_rate() >= self.config.error_rate);
+        if health.state == CircuitState::HalfOpen || (health.state == CircuitState::Closed && tripped) {
+            warn!("Leaving {} out of the queries for {:?}: {}", backend, self.config.open_duration, error);
+            health.state = CircuitState::Open;
+            health.opened_at = Some(now);
+        }
+        health.last_error = Some(error.to_string());
+    }
+
+    pub fn statuses(&self) -> Vec<BackendStatus> {
+        let backends = self.backends.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statuses: Vec<BackendStatus> = backends.iter().map(|(backend, health)| BackendStatus {
+            backend: backend.clone(),
+            state: health.state,
+            consecutive_failures: health.consecutive_failures,
+            error_rate: health.error_rate(),
+            probes_total: health.probes,
+            probe_failures_total: health.probe_failures,
+            last_probe: health.last_probe.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|time| time.as_secs_f64()),
+            last_error: health.last_error.clone(),
+        }).collect();
+        statuses.sort_by(|a, b| a.backend.cmp(&b.backend));
+        statuses
+    }
+
+    /// Health of the backends in the text format of prometheus
+    pub fn metrics(&self) -> String {
+        let statuses = self.statuses();
+        let mut metrics = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: &mut dyn Iterator<Item = (String, String)>| {
+            let _ = writeln!(metrics, "# HELP {} {}", name, help);
+            let _ = writeln!(metrics, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(metrics, "{}{{{}}} {}", name, labels, value);
+            }
+        };
+        let backend = |status: &BackendStatus| format!("backend=\"{}\"", escape_label_value(&status.backend));
+
+        family("loki_federation_backend_up", "gauge", "Whether the backend is queried, 0 while its circuit breaker is tripped",
+               &mut statuses.iter().map(|status| (backend(status), ((status.state == CircuitState::Closed) as u8).to_string())));
+        family("loki_federation_backend_circuit_state", "gauge", "State of the circuit breaker of the backend",
+               &mut statuses.iter().flat_map(|status| [CircuitState::Closed, CircuitState::Open, CircuitState::HalfOpen].into_iter()
+                   .map(move |state| (format!("{},state=\"{}\"", backend(status), state.as_str()), ((status.state == state) as u8).to_string()))));
+        family("loki_federation_backend_consecutive_failures", "gauge", "Failed probes of the backend since its latest successful one",
+               &mut statuses.iter().map(|status| (backend(status), status.consecutive_failures.to_string())));
+        family("loki_federation_backend_error_rate", "gauge", "Ratio of failed probes among the latest ones of the backend",
+               &mut statuses.iter().map(|status| (backend(status), status.error_rate.to_string())));
+        family("loki_federation_backend_probes_total", "counter", "Probes of the backend",
+               &mut statuses.iter().map(|status| (backend(status), status.probes_total.to_string())));
+        family("loki_federation_backend_probe_failures_total", "counter", "Failed probes of the backend",
+               &mut statuses.iter().map(|status| (backend(status), status.probe_failures_total.to_string())));
+        metrics
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKEND: &str = "http://localhost:3100";
+
+    fn failure() -> Result<(), LokiError> {
+        Err(LokiError::Unavailable { backend: BACKEND.to_string(), message: "connection refused".to_string(), retry_after: None })
+    }
+
+    #[test]
+    fn it_should_trip_after_consecutive_failures() {
+        let health = BackendsHealth::new(HealthConfig::default());
+        let now = Instant::now();
+        health.record(BACKEND, failure(), now);
+        health.record(BACKEND, failure(), now);
+        assert!(health.is_available(BACKEND));
+        health.record(BACKEND, failure(), now);
+        assert!(!health.is_available(BACKEND));
+        assert!(!health.should_probe(BACKEND, now + Duration::from_secs(29)));
+        assert_eq!(health.statuses()[0].state, CircuitState::Open);
+        assert_eq!(health.statuses()[0].last_error.as_deref(), Some("http://localhost:3100 is unavailable: connection refused"));
+    }
+
+    #[test]
+    fn it_should_recover_after_a_half_open_probe() {
+        let health = BackendsHealth::new(HealthConfig { consecutive_failures: 1, ..HealthConfig::default() });
+        let now = Instant::now();
+        health.record(BACKEND, failure(), now);
+
+        //a failed half open probe leaves the backend out for another open duration
+        let now = now + Duration::from_secs(30);
+        assert!(health.should_probe(BACKEND, now));
+        assert_eq!(health.statuses()[0].state, CircuitState::HalfOpen);
+        assert!(!health.is_available(BACKEND));
+        health.record(BACKEND, failure(), now);
+        assert!(!health.should_probe(BACKEND, now + Duration::from_secs(1)));
+
+        let now = now + Duration::from_secs(30);
+        assert!(health.should_probe(BACKEND, now));
+        health.record(BACKEND, Ok(()), now);
+        assert!(health.is_available(BACKEND));
+        assert_eq!(health.statuses()[0].error_rate, 0.0);
+    }
+
+    #[test]
+    fn it_should_trip_on_the_error_rate() {
+        let health = BackendsHealth::new(HealthConfig { error_rate: 0.4, ..HealthConfig::default() });
+        let now = Instant::now();
+        for _ in 0..4 {
+            health.record(BACKEND, Ok(()), now);
+            health.record(BACKEND, failure(), now);
+        }
+        assert!(health.is_available(BACKEND));
+        health.record(BACKEND, Ok(()), now);
+        health.record(BACKEND, failure(), now);
+        assert!(!health.is_available(BACKEND));
+        assert_eq!(health.statuses()[0].error_rate, 0.5);
+    }
+
+    #[test]
+    fn it_should_expose_prometheus_metrics() {
+        let health = BackendsHealth::new(HealthConfig { consecutive_failures: 1, ..HealthConfig::default() });
+        health.record(BACKEND, failure(), Instant::now());
+        let metrics = health.metrics();
+        assert!(metrics.contains("# TYPE loki_federation_backend_up gauge\nloki_federation_backend_up{backend=\"http://localhost:3100\"} 0\n"));
+        assert!(metrics.contains("loki_federation_backend_circuit_state{backend=\"http://localhost:3100\",state=\"open\"} 1\n"));
+        assert!(metrics.contains("loki_federation_backend_probe_failures_total{backend=\"http://localhost:3100\"} 1\n"));
+        assert_eq!(escape_label_value("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use generic_loki_client::{Direction, LabelResponse, LokiClient, LokiError, QueryType, Response, ResponseStream, SerieResponse, TailStream};
+use log::{info, warn};
+use tokio::time::Instant;
+
+pub const DEFAULT_HEDGE_DELAY: Duration = Duration::from_millis(200);
+/// Latest latencies of a replica set from which the percentile is computed
+const LATENCY_WINDOW: usize = 100;
+/// Latencies needed before the percentile is used as the hedge delay
+const LATENCY_MIN_SAMPLES: usize = 20;
+
+/// Delay before a request to a replica set is sent to another replica, shared by the requests to the replica set
+#[derive(Debug, Clone)]
+pub struct HedgePolicy {
+    delay: Duration,
+    /// Percentile of the latencies of the replicas used as the delay, once enough of them were observed
+    percentile: Option<f64>,
+    latencies: Arc<Mutex<VecDeque<Duration>>>,
+}
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        HedgePolicy::new(DEFAULT_HEDGE_DELAY, None)
+    }
+}
+
+impl HedgePolicy {
+    pub fn new(delay: Duration, percentile: Option<f64>) -> Self {
+        HedgePolicy { delay, percentile, latencies: Arc::new(Mutex::new(VecDeque::new())) }
+    }
+
+    fn delay(&self) -> Duration {
+        let percentile = match self.percentile {
+            Some(percentile) => percentile.clamp(0.0, 1.0),
+            None => return self.delay,
+        };
+        let mut latencies: Vec<Duration> = self.latencies.lock().unwrap_or_else(|e| e.into_inner()).iter().copied().collect();
+        if latencies.len() < LATENCY_MIN_SAMPLES {
+            return self.delay;
+        }
+        latencies.sort();
+        let rank = ((percentile * latencies.len() as f64).ceil() as usize).clamp(1, latencies.len());
+        latencies[rank - 1]
+    }
+
+    fn observe(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().unwrap_or_else(|e| e.into_inner());
+        latencies.push_back(latency);
+        if latencies.len() > LATENCY_WINDOW {
+            latencies.pop_front();
+        }
+    }
+}
+
+/// Client of a replica set: the request is sent to the preferred replica, then to the next one when it
+/// is slower than the hedge delay or when it failed. The first successful answer is kept, the other
+/// requests are canceled.
+pub struct HedgedLokiClient {
+    /// Url and client of the replicas, in order of preference
+    replicas: Vec<(String, Box<dyn LokiClient>)>,
+    policy: HedgePolicy,
+}
+
+impl HedgedLokiClient {
+    pub fn new(replicas: Vec<(String, Box<dyn LokiClient>)>, policy: HedgePolicy) -> Self {
+        HedgedLokiClient { replicas, policy }
+    }
+
+    async fn hedge<'a, T>(&'a self, request: impl Fn(&'a dyn LokiClient) -> BoxFuture<'a, Result<T, LokiError>>) -> Result<T, LokiError> {
+        let start = |client: &'a dyn LokiClient| {
+            let started = Instant::now();
+            request(client).map(move |result| (started.elapsed(), result))
+        };
+        let mut replicas = self.replicas.iter();
+        let mut requests = FuturesUnordered::new();
+        let mut last_error = None;
+        if let Some((_, client)) = replicas.next() {
+            requests.push(start(client.as_ref()));
+        }
+        //the timer runs from the start of the latest replica, the failure of another one doesn't restart it
+        let delay = self.policy.delay();
+        let hedge = tokio::time::sleep(delay);
+        tokio::pin!(hedge);
+        loop {
+            tokio::select! {
+                Some((latency, result)) = requests.next() => match result {
+                    Ok(response) => {
+                        self.policy.observe(latency);
+                        return Ok(response);
+                    }
+                    //the other replicas would reject the query too
+                    Err(error @ (LokiError::BadRequest { .. } | LokiError::LimitExceeded { .. })) => return Err(error),
+                    Err(error) => {
+                        warn!("A replica failed, waiting for the other ones: {}", error);
+                        last_error = Some(error);
+                        if requests.is_empty() {
+                            match replicas.next() {
+                                Some((_, client)) => {
+                                    requests.push(start(client.as_ref()));
+                                    hedge.as_mut().reset(Instant::now() + delay);
+                                }
+                                None => break,
+                            }
+                        }
+                    }
+                },
+                _ = &mut hedge, if replicas.len() > 0 => {
+                    if let Some((backend, client)) = replicas.next() {
+                        info!("Hedging the request with {}", backend);
+                        requests.push(start(client.as_ref()));
+                        hedge.as_mut().reset(Instant::now() + delay);
+                    }
+                }
+                else => break,
+            }
+        }
+        Err(last_error.unwrap_or_else(|| LokiError::Other(anyhow::anyhow!("The replica set has no replica"))))
+    }
+}
+
+#[async_trait]
+impl LokiClient for HedgedLokiClient {
+    async fn query(&self, query: String, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<Response, LokiError> {
+        self.hedge(|client| client.query(query.clone(), query_type, limit, time, direction)).await
+    }
+
+    async fn query_range(&self, query: String, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<Response, LokiError> {
+        self.hedge(|client| client.query_range(query.clone(), query_type, start, end, limit, direction, step.clone(), interval.clone())).await
+    }
+
+    //the first replica starting its response answers, the responses of the other ones are dropped with their budget
+    async fn query_stream(&self, query: String, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<ResponseStream, LokiError> {
+        self.hedge(|client| client.query_stream(query.clone(), query_type, limit, time, direction)).await
+    }
+
+    async fn query_range_stream(&self, query: String, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<ResponseStream, LokiError> {
+        self.hedge(|client| client.query_range_stream(query.clone(), query_type, start, end, limit, direction, step.clone(), interval.clone())).await
+    }
+
+    async fn labels(&self, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
+        self.hedge(|client| client.labels(start, end)).await
+    }
+
+    async fn label_values(&self, label: String, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
+        self.hedge(|client| client.label_values(label.clone(), start, end)).await
+    }
+
+    async fn series(&self, matches: Option<Vec<String>>, start: Option<i64>, end: Option<i64>) -> Result<SerieResponse, LokiError> {
+        self.hedge(|client| client.series(matches.clone(), start, end)).await
+    }
+
+    //the replica whose tail starts first is tailed
+    async fn tail(&self, query: String, delay_for: Option<u32>, limit: Option<i32>, start: Option<i64>) -> Result<TailStream, LokiError> {
+        self.hedge(|client| client.tail(query.clone(), delay_for, limit, start)).await
+    }
+
+    async fn ready(&self) -> Result<(), LokiError> {
+        self.hedge(|client| client.ready()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_loki_client::MockTestLokiClient;
+
+    /// Replica answering the labels queries with its name after a delay, or failing, expecting the given requests
+    fn replica(name: &'static str, delay: Duration, error: Option<fn() -> LokiError>, requests: usize) -> (String, Box<dyn LokiClient>) {
+        let mut client = MockTestLokiClient::new();
+        client.expect_labels()
+            .times(requests)
+            .returning(move |_, _| Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                match error {
+                    Some(error) => Err(error()),
+                    None => Ok(LabelResponse { status: "success".to_string(), data: Some(vec![name.to_string()]), warnings: vec![] }),
+                }
+            }));
+        (name.to_string(), Box::new(client))
+    }
+
+    fn unavailable() -> LokiError {
+        LokiError::Unavailable { backend: "a".to_string(), message: "connection refused".to_string(), retry_after: None }
+    }
+
+    fn bad_request() -> LokiError {
+        LokiError::BadRequest { backend: "a".to_string(), message: "parse error".to_string() }
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_with_the_fastest_replica() {
+        let client = HedgedLokiClient::new(vec![
+            replica("a", Duration::from_secs(5), None, 1),
+            replica("b", Duration::from_millis(10), None, 1),
+        ], HedgePolicy::new(Duration::from_millis(10), None));
+        let started = Instant::now();
+        assert_eq!(client.labels(None, None).await.unwrap().data, Some(vec!["b".to_string()]));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        //the preferred replica isn't hedged when it answers in time
+        let client = HedgedLokiClient::new(vec![
+            replica("a", Duration::from_millis(10), None, 1),
+            replica("b", Duration::from_millis(10), None, 0),
+        ], HedgePolicy::new(Duration::from_secs(5), None));
+        assert_eq!(client.labels(None, None).await.unwrap().data, Some(vec!["a".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn it_should_fail_over_to_the_next_replica() {
+        let client = HedgedLokiClient::new(vec![
+            replica("a", Duration::ZERO, Some(unavailable), 1),
+            replica("b", Duration::ZERO, None, 1),
+        ], HedgePolicy::new(Duration::from_secs(5), None));
+        let started = Instant::now();
+        assert_eq!(client.labels(None, None).await.unwrap().data, Some(vec!["b".to_string()]));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let client = HedgedLokiClient::new(vec![
+            replica("a", Duration::ZERO, Some(unavailable), 1),
+            replica("b", Duration::ZERO, Some(unavailable), 1),
+        ], HedgePolicy::new(Duration::from_secs(5), None));
+        assert_eq!(client.labels(None, None).await.unwrap_err().status_code(), 503);
+
+        let client = HedgedLokiClient::new(vec![
+            replica("a", Duration::ZERO, Some(bad_request), 1),
+            replica("b", Duration::ZERO, None, 0),
+        ], HedgePolicy::new(Duration::from_secs(5), None));
+        assert_eq!(client.labels(None, None).await.unwrap_err().status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn it_should_not_restart_the_hedge_timer_on_failures() {
+        //b fails 550ms in while a is still pending, c is hedged 600ms in rather than 850ms
+        let client = HedgedLokiClient::new(vec![
+            replica("a", Duration::from_secs(5), None, 1),
+            replica("b", Duration::from_millis(250), Some(unavailable), 1),
+            replica("c", Duration::ZERO, None, 1),
+        ], HedgePolicy::new(Duration::from_millis(300), None));
+        let started = Instant::now();
+        assert_eq!(client.labels(None, None).await.unwrap().data, Some(vec!["c".to_string()]));
+        assert!(started.elapsed() < Duration::from_millis(750));
+    }
+
+    #[test]
+    fn it_should_hedge_after_the_latency_percentile() {
+        let policy = HedgePolicy::new(Duration::from_millis(200), Some(0.9));
+        for latency in 1..LATENCY_MIN_SAMPLES as u64 {
+            policy.observe(Duration::from_millis(latency * 10));
+        }
+        assert_eq!(policy.delay(), Duration::from_millis(200));
+        policy.observe(Duration::from_millis(200));
+        assert_eq!(policy.delay(), Duration::from_millis(180));
+    }
+}
@@ -1,5 +1,13 @@
-mod aggregate;
+pub mod aggregate;
+mod merge;
+mod pushdown;
+mod tail;
+pub mod retry;
+pub mod health;
+pub mod hedge;
 pub mod federated_loki;
 mod federated_loki_test;
+#[cfg(test)]
+mod test_loki_client;
 pub mod datasources_provider;
 pub mod config;
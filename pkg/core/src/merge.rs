@@ -0,0 +1,240 @@
+use std::collections::{BTreeMap, HashMap};
+use anyhow::anyhow;
+use generic_loki_client::{Data, LokiError, Reservation, Response, ResponsePart, ResultType, Stats, Values, VectorOrStream};
+use crate::aggregate::{aggregate_samples, merge_entries, merge_sample_values};
+use crate::config::MergeStrategy;
+use crate::federated_loki::{Direction, FederatedLoki};
+
+/// Stream, serie or sample merged from the results of the backends
+struct MergedResult {
+    result: VectorOrStream,
+    /// Entries of a stream, sorted in the direction of the query
+    entries: Vec<(i64, String)>,
+    /// Estimated bytes held by the result
+    bytes: usize,
+}
+
+/// Merge the responses of the backends as their results are received. The entries of a stream are merged
+/// with a k-way merge of the entries merged so far and of the entries received, keeping the `limit` first ones
+/// in the direction of the query as no other entry of the stream can be answered.
+/// Samples of series and vectors are combined using the merge strategy.
+/// The merged results are held in the budget of the query, the results received are released once merged.
+pub struct ResponseMerger {
+    direction: Direction,
+    limit: usize,
+    replica_labels: Vec<String>,
+    merge_strategy: MergeStrategy,
+    result_type: Option<ResultType>,
+    results: Vec<MergedResult>,
+    /// Index of the results by their labels, compared as is so that label sets never collide
+    results_index: HashMap<Option<BTreeMap<String, String>>, usize>,
+    stats: Stats,
+    entries_before_dedup: usize,
+    reservation: Reservation,
+    bytes: usize,
+}
+
+impl ResponseMerger {
+    pub fn new(direction: Direction, limit: usize, replica_labels: Vec<String>, merge_strategy: MergeStrategy, reservation: Reservation) -> Self {
+        ResponseMerger {
+            direction,
+            limit,
+            replica_labels,
+            merge_strategy,
+            result_type: None,
+            results: Vec::new(),
+            results_index: HashMap::new(),
+            stats: Stats::default(),
+            entries_before_dedup: 0,
+            reservation,
+            bytes: 0,
+        }
+    }
+
+    /// Merge a part of the response of a backend
+    pub fn push(&mut self, backend: &str, part: ResponsePart) -> Result<(), LokiError> {
+        match part {
+            ResponsePart::Result(result, reservation) => {
+                self.push_result(backend, result)?;
+                drop(reservation);
+                Ok(())
+            }
+            ResponsePart::End(response) => {
+                self.check_result_type(response.data.result_type)?;
+                if let Some(stats) = &response.data.stats {
+                    self.stats.merge(stats);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Merged response, its stats are those of the backends
+    pub fn finish(self) -> Response {
+        let result = self.results.into_iter().map(|mut merged| {
+            if merged.result.stream.is_some() {
+                FederatedLoki::replace_stream_data(&mut merged.result, merged.entries);
+            }
+            merged.result
+        }).collect();
+        Response {
+            status: "success".to_string(),
+            data: Data {
+                result_type: self.result_type.unwrap_or(ResultType::Streams),
+                result,
+                stats: Some(FederatedLoki::federation_stats(self.stats, self.entries_before_dedup)),
+            },
+            warnings: vec![],
+        }
+    }
+
+    fn check_result_type(&mut self, result_type: ResultType) -> Result<(), LokiError> {
+        match &self.result_type {
+            Some(merged_type) if *merged_type != result_type => Err(LokiError::Other(anyhow!("Unable to merge a {:?} result with a {:?} result", result_type, merged_type))),
+            Some(_) => Ok(()),
+            None => {
+                self.result_type = Some(result_type);
+                Ok(())
+            }
+        }
+    }
+
+    fn push_result(&mut self, backend: &str, mut result: VectorOrStream) -> Result<(), LokiError> {
+        let result_type = result.result_type();
+        self.check_result_type(result_type.clone())?;
+        self.entries_before_dedup += FederatedLoki::count_result_entries(&result);
+        let labels = match result_type {
+            ResultType::Streams => &mut result.stream,
+            ResultType::Matrix | ResultType::Vector => &mut result.metric,
+        };
+        *labels = labels.take().map(|labels| FederatedLoki::strip_replica_labels(&labels, &self.replica_labels));
+        let key = labels.as_ref().map(|labels| labels.iter().map(|(name, value)| (name.clone(), value.clone())).collect());
+        let results = &mut self.results;
+        let index = *self.results_index.entry(key).or_insert_with(|| {
+            results.push(MergedResult {
+                result: VectorOrStream { stream: result.stream.clone(), metric: result.metric.clone(), value: None, values: None },
+                entries: Vec::new(),
+                bytes: 0,
+            });
+            results.len() - 1
+        });
+
+        let merged = &mut self.results[index];
+        match result_type {
+            ResultType::Streams => {
+                let entries = FederatedLoki::take_stream_data(&mut result)?;
+                let mut entries = merge_entries(vec![std::mem::take(&mut merged.entries), entries], self.direction);
+                entries.truncate(self.limit);
+                merged.entries = entries;
+            }
+            ResultType::Matrix => {
+                let samples = match result.values {
+                    Some(Values::Matrix(samples)) => samples,
+                    //an empty values array can't be told apart from empty stream values when deserializing
+                    _ => vec![],
+                };
+                merged.result.values = Some(Values::Matrix(match merged.result.values.take() {
+                    Some(Values::Matrix(merged_samples)) => aggregate_samples(merged_samples, samples, self.merge_strategy)?,
+                    _ => samples,
+                }));
+            }
+            ResultType::Vector => {
+                let (timestamp, value) = result.value
+                    .ok_or_else(|| LokiError::Other(anyhow!("Missing value in vector sample {:?}", result.metric)))?;
+                merged.result.value = Some(match merged.result.value.take() {
+                    Some((merged_timestamp, merged_value)) => (merged_timestamp, merge_sample_values(merged_value, &value, self.merge_strategy)?),
+                    None => (timestamp, value),
+                });
+            }
+        }
+
+        let bytes = estimated_bytes(merged);
+        self.bytes = self.bytes - merged.bytes + bytes;
+        merged.bytes = bytes;
+        self.reservation.resize(backend, self.bytes)
+    }
+}
+
+/// Bytes held in memory by a merged result, its strings and the entries holding them
+fn estimated_bytes(merged: &MergedResult) -> usize {
+    let labels = merged.result.stream.iter().chain(merged.result.metric.iter())
+        .flat_map(|labels| labels.iter())
+        .map(|(name, value)| name.len() + value.len())
+        .sum::<usize>();
+    let entries = merged.entries.iter()
+        .map(|(_, line)| line.len() + std::mem::size_of::<(i64, String)>())
+        .sum::<usize>();
+    let samples = match &merged.result.values {
+        Some(Values::Matrix(samples)) => samples.iter().map(|(_, value)| value.len() + std::mem::size_of::<(f64, String)>()).sum(),
+        _ => 0,
+    };
+    let value = merged.result.value.as_ref().map_or(0, |(_, value)| value.len());
+    std::mem::size_of::<MergedResult>() + labels + entries + samples + value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use generic_loki_client::BufferBudget;
+
+    fn stream(replica: &str, entries: &[(i64, &str)]) -> ResponsePart {
+        ResponsePart::Result(VectorOrStream {
+            stream: Some(HashMap::from([("app".to_string(), "a".to_string()), ("replica".to_string(), replica.to_string())])),
+            values: Some(Values::Streams(entries.iter().map(|(timestamp, line)| (timestamp.to_string(), line.to_string())).collect())),
+            metric: None,
+            value: None,
+        }, Reservation::default())
+    }
+
+    fn entries(response: &Response) -> Vec<(String, String)> {
+        match &response.data.result[0].values {
+            Some(Values::Streams(values)) => values.clone(),
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn it_should_merge_streams_as_their_parts_are_received() {
+        let mut merger = ResponseMerger::new(Direction::Backward, 3, vec!["replica".to_string()], MergeStrategy::KeepOne, Reservation::default());
+        merger.push("http://loki-a:3100", stream("a", &[(5, "e"), (3, "c")])).unwrap();
+        merger.push("http://loki-b:3100", stream("b", &[(5, "e"), (4, "d")])).unwrap();
+        merger.push("http://loki-a:3100", stream("a", &[(2, "b"), (1, "a")])).unwrap();
+        let mut other = stream("a", &[(6, "f")]);
+        if let ResponsePart::Result(result, _) = &mut other {
+            result.stream.as_mut().unwrap().insert("app".to_string(), "b".to_string());
+        }
+        merger.push("http://loki-a:3100", other).unwrap();
+        let response = merger.finish();
+        assert_eq!(response.data.result.len(), 2);
+        assert_eq!(entries(&response), vec![("5".to_string(), "e".to_string()), ("4".to_string(), "d".to_string()), ("3".to_string(), "c".to_string())]);
+        assert_eq!(response.data.stats.unwrap().federation.unwrap().entries_before_dedup, 7);
+    }
+
+    #[test]
+    fn it_should_hold_the_merged_results_in_the_budget() {
+        let budget = BufferBudget::new(Some(1024 * 1024));
+        let mut merger = ResponseMerger::new(Direction::Forward, 100, vec![], MergeStrategy::KeepOne, budget.reservation());
+        let part = match stream("a", &[(1, "a")]) {
+            ResponsePart::Result(result, _) => ResponsePart::Result(result, budget.reserve("http://loki-a:3100", 1000).unwrap()),
+            part => part,
+        };
+        merger.push("http://loki-a:3100", part).unwrap();
+        assert!(budget.used() > 0 && budget.used() < 1000);
+        drop(merger);
+        assert_eq!(budget.used(), 0);
+
+        let mut merger = ResponseMerger::new(Direction::Forward, 100, vec![], MergeStrategy::KeepOne, BufferBudget::new(Some(10)).reservation());
+        let error = merger.push("http://loki-a:3100", stream("a", &[(1, "a")])).unwrap_err();
+        assert_eq!(error.backend(), Some("http://loki-a:3100"));
+        assert_eq!(error.status_code(), 422);
+    }
+
+    #[test]
+    fn it_should_not_merge_results_of_different_types() {
+        let mut merger = ResponseMerger::new(Direction::Forward, 100, vec![], MergeStrategy::KeepOne, Reservation::default());
+        merger.push("http://loki-a:3100", stream("a", &[(1, "a")])).unwrap();
+        let end = Response { status: "success".to_string(), data: Data { result_type: ResultType::Matrix, result: vec![], stats: None }, warnings: vec![] };
+        assert!(merger.push("http://loki-b:3100", ResponsePart::End(Box::new(end))).is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::{BTreeMap, HashMap};
+use anyhow::anyhow;
+use generic_loki_client::{Data, LokiError, Response, ResultType, Values, VectorOrStream};
+use log::warn;
+use logql_parser::ast::{Expr, Grouping, MetricExpr, RangeOp, VectorOp};
+use crate::aggregate::{format_sample_value, parse_sample_value};
+use crate::config::MergeStrategy;
+
+type Labels = BTreeMap<String, String>;
+/// Samples of a serie indexed by their timestamp in milliseconds
+type Samples = BTreeMap<i64, (f64, f64)>;
+type GroupKey = Vec<(String, String)>;
+
+/// Group the samples by replica as well, replicas are then deduplicated once the partial results are combined
+fn with_replica_labels(grouping: Option<&Grouping>, replica_labels: &[String]) -> Option<Grouping> {
+    if replica_labels.is_empty() {
+        return grouping.cloned();
+    }
+    match grouping {
+        None => Some(Grouping { without: false, labels: replica_labels.to_vec() }),
+        Some(Grouping { without: false, labels }) => {
+            let mut labels = labels.clone();
+            labels.extend(replica_labels.iter().filter(|label| !labels.contains(label)).cloned().collect::<Vec<String>>());
+            Some(Grouping { without: false, labels })
+        }
+        Some(Grouping { without: true, labels }) => Some(Grouping {
+            without: true,
+            labels: labels.iter().filter(|label| !replica_labels.contains(label)).cloned().collect(),
+        }),
+    }
+}
+
+fn group_key(grouping: Option<&Grouping>, labels: &Labels) -> GroupKey {
+    labels.iter()
+        .filter(|(name, _)| match grouping {
+            None => false,
+            Some(grouping) => grouping.labels.contains(name) != grouping.without,
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Whether the samples computed by an expression may come from streams of several backends
+fn is_aggregating(expr: &MetricExpr) -> bool {
+    match expr {
+        MetricExpr::VectorAggregation { .. } => true,
+        MetricExpr::RangeAggregation { grouping, .. } => grouping.is_some(),
+        MetricExpr::Binary { lhs, rhs, .. } => is_aggregating(lhs) || is_aggregating(rhs),
+        MetricExpr::LabelReplace { expr, .. } | MetricExpr::Parens(expr) => is_aggregating(expr),
+        MetricExpr::Literal(_) | MetricExpr::Vector(_) => false,
+    }
+}
+
+fn unsupported(expr: &MetricExpr, reason: &str) -> LokiError {
+    LokiError::InvalidQuery(format!("Unable to push down the aggregation of query {}: {}", expr, reason))
+}
+
+/// How partial results of the pushed down queries are combined into the final result
+#[derive(Debug, Clone, PartialEq)]
+enum Combination {
+    /// Partial results are merged sample by sample
+    Merge(MergeStrategy),
+    /// Partial results of a sum and a count queries are divided
+    Average,
+    /// Partial results are merged and the k largest (or smallest) samples of each group are kept
+    TopK { k: usize, bottom: bool, grouping: Option<Grouping> },
+}
+
+/// Rewrite of a metric query into partial aggregations evaluated by each backend and recombined by the federation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pushdown {
+    pub queries: Vec<String>,
+    combination: Combination,
+    replica_labels: Vec<String>,
+}
+
+impl Pushdown {
+    /// Plan the pushdown of the aggregation of a query.
+    /// Returns None when the query doesn't need to be rewritten (log queries and queries without aggregation across streams),
+    /// and an error when the aggregation can't be computed from partial aggregations.
+    pub fn plan(expr: &Expr, replica_labels: &[String]) -> Result<Option<Pushdown>, LokiError> {
+        let mut expr = match expr {
+            Expr::Log(_) => return Ok(None),
+            Expr::Metric(expr) => expr,
+        };
+        while let MetricExpr::Parens(inner) = expr {
+            expr = inner;
+        }
+        if !is_aggregating(expr) {
+            //each serie is computed from a single stream, it doesn't have to be recombined
+            return Ok(None);
+        }
+        let (queries, combination) = match expr {
+            MetricExpr::VectorAggregation { op, parameter, expr: inner, grouping } => {
+                if is_aggregating(inner) {
+                    return Err(unsupported(expr, "nested aggregations are not decomposable"));
+                }
+                let partial = |op: VectorOp| MetricExpr::VectorAggregation {
+                    op,
+                    parameter: *parameter,
+                    expr: inner.clone(),
+                    grouping: with_replica_labels(grouping.as_ref(), replica_labels),
+                }.to_string();
+                match op {
+                    VectorOp::Sum | VectorOp::Count => (vec![partial(*op)], Combination::Merge(MergeStrategy::Sum)),
+                    VectorOp::Min => (vec![partial(*op)], Combination::Merge(MergeStrategy::Min)),
+                    VectorOp::Max => (vec![partial(*op)], Combination::Merge(MergeStrategy::Max)),
+                    VectorOp::Avg => (vec![partial(VectorOp::Sum), partial(VectorOp::Count)], Combination::Average),
+                    VectorOp::Topk | VectorOp::Bottomk => {
+                        let k = match parameter {
+                            Some(k) if k.fract() == 0.0 && *k >= 0.0 => *k as usize,
+                            _ => return Err(unsupported(expr, &format!("invalid {} parameter", op))),
+                        };
+                        (vec![partial(*op)], Combination::TopK { k, bottom: *op == VectorOp::Bottomk, grouping: grouping.clone() })
+                    }
+                    _ => return Err(unsupported(expr, &format!("{} is not decomposable", op))),
+                }
+            }
+            MetricExpr::RangeAggregation { op, parameter, range, grouping } => {
+                let merge_strategy = match op {
+                    RangeOp::SumOverTime | RangeOp::Rate | RangeOp::CountOverTime | RangeOp::BytesOverTime | RangeOp::BytesRate => MergeStrategy::Sum,
+                    RangeOp::MinOverTime => MergeStrategy::Min,
+                    RangeOp::MaxOverTime => MergeStrategy::Max,
+                    _ => return Err(unsupported(expr, &format!("{} grouped across backends is not decomposable", op))),
+                };
+                let partial = MetricExpr::RangeAggregation {
+                    op: *op,
+                    parameter: *parameter,
+                    range: range.clone(),
+                    grouping: with_replica_labels(grouping.as_ref(), replica_labels),
+                };
+                (vec![partial.to_string()], Combination::Merge(merge_strategy))
+            }
+            _ => return Err(unsupported(expr, "only a single aggregation is supported")),
+        };
+
+        Ok(Some(Pushdown {
+            queries,
+            combination,
+            replica_labels: replica_labels.to_vec(),
+        }))
+    }
+
+    fn parse_series(response: Response, result_type: &ResultType, merge_strategy: MergeStrategy, series: &mut BTreeMap<Labels, Samples>) -> Result<(), LokiError> {
+        if &response.data.result_type != result_type {
+            return Err(LokiError::Other(anyhow!("Unable to merge a {:?} result with a {:?} result", response.data.result_type, result_type)));
+        }
+        for serie in response.data.result {
+            let labels: Labels = serie.metric.unwrap_or_default().into_iter().collect();
+            let samples = match (serie.value, serie.values) {
+                (Some(value), _) => vec![value],
+                (None, Some(Values::Matrix(values))) => values,
+                _ => vec![],
+            };
+            let aggregated_samples = series.entry(labels).or_default();
+            for (timestamp, value) in samples {
+                let value = parse_sample_value(&value)?;
+                let key = (timestamp * 1000.0).round() as i64;
+                let sample = match aggregated_samples.get(&key) {
+                    Some(&(_, aggregated_value)) => match merge_strategy {
+                        MergeStrategy::KeepOne => aggregated_value,
+                        MergeStrategy::Sum => aggregated_value + value,
+                        MergeStrategy::Max => aggregated_value.max(value),
+                        MergeStrategy::Min => aggregated_value.min(value),
+                    },
+                    None => value,
+                };
+                aggregated_samples.insert(key, (timestamp, sample));
+            }
+        }
+        Ok(())
+    }
+
+    /// Combine the partial results of one query, replicas are deduplicated by keeping the samples of the first replica having one
+    fn combine_partials(&self, responses: Vec<Response>, result_type: &ResultType, merge_strategy: MergeStrategy) -> Result<BTreeMap<Labels, Samples>, LokiError> {
+        let mut partials = BTreeMap::new();
+        for response in responses {
+            Self::parse_series(response, result_type, merge_strategy, &mut partials)?;
+        }
+        let mut series: BTreeMap<Labels, Samples> = BTreeMap::new();
+        for (mut labels, samples) in partials {
+            labels.retain(|name, _| !self.replica_labels.contains(name));
+            let deduplicated_samples = series.entry(labels).or_default();
+            for (key, sample) in samples {
+                deduplicated_samples.entry(key).or_insert(sample);
+            }
+        }
+        Ok(series)
+    }
+
+    fn select_top_k(series: BTreeMap<Labels, Samples>, k: usize, bottom: bool, grouping: Option<&Grouping>) -> BTreeMap<Labels, Samples> {
+        //samples are ranked by timestamp and group
+        let mut groups: HashMap<(i64, GroupKey), Vec<(f64, &Labels)>> = HashMap::new();
+        for (labels, samples) in series.iter() {
+            let group_key = group_key(grouping, labels);
+            for (key, (_, value)) in samples {
+                groups.entry((*key, group_key.clone())).or_default().push((*value, labels));
+            }
+        }
+        let mut selected: BTreeMap<Labels, Samples> = BTreeMap::new();
+        for ((key, _), mut values) in groups {
+            values.sort_by(|(a, _), (b, _)| if bottom { a.total_cmp(b) } else { b.total_cmp(a) });
+            for (_, labels) in values.into_iter().take(k) {
+                selected.entry(labels.clone()).or_default().insert(key, series[labels][&key]);
+            }
+        }
+        selected
+    }
+
+    /// Combine the responses of each pushed down query (in the same order as `queries`) into the final result
+    pub fn combine(&self, responses: Vec<Vec<Response>>) -> Result<Response, LokiError> {
+        let result_type = responses.iter().flatten().next()
+            .map(|response| response.data.result_type.clone())
+            .unwrap_or(ResultType::Vector);
+        if result_type == ResultType::Streams {
+            return Err(LokiError::Other(anyhow!("Unable to push down the aggregation of a log query")));
+        }
+        let mut responses = responses.into_iter();
+        let mut next_partials = |merge_strategy| self.combine_partials(responses.next().unwrap_or_default(), &result_type, merge_strategy);
+
+        let series = match &self.combination {
+            Combination::Merge(merge_strategy) => next_partials(*merge_strategy)?,
+            Combination::Average => {
+                let mut sums = next_partials(MergeStrategy::Sum)?;
+                let counts = next_partials(MergeStrategy::Sum)?;
+                for (labels, samples) in sums.iter_mut() {
+                    samples.retain(|key, (_, sum)| match counts.get(labels).and_then(|count_samples| count_samples.get(key)) {
+                        Some((_, count)) => {
+                            *sum /= count;
+                            true
+                        }
+                        None => {
+                            warn!("Missing count for {:?} at {}, dropping the sample", labels, key);
+                            false
+                        }
+                    });
+                }
+                sums
+            }
+            Combination::TopK { k, bottom, grouping } => Self::select_top_k(next_partials(MergeStrategy::KeepOne)?, *k, *bottom, grouping.as_ref()),
+        };
+
+        let result = series.into_iter()
+            .filter(|(_, samples)| !samples.is_empty())
+            .map(|(labels, samples)| {
+                let mut samples = samples.into_values().map(|(timestamp, value)| (timestamp, format_sample_value(value)));
+                let metric = Some(labels.into_iter().collect::<HashMap<String, String>>());
+                match result_type {
+                    ResultType::Vector => VectorOrStream { metric, value: samples.next(), stream: None, values: None },
+                    _ => VectorOrStream { metric, value: None, stream: None, values: Some(Values::Matrix(samples.collect())) },
+                }
+            })
+            .collect();
+
+        Ok(Response {
+            status: "success".to_string(),
+            data: Data {
+                result_type,
+                result,
+                stats: None,
+            },
+            warnings: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(query: &str, replica_labels: &[String]) -> Result<Option<Pushdown>, LokiError> {
+        Pushdown::plan(&logql_parser::parse(query).unwrap(), replica_labels)
+    }
+
+    fn queries(query: &str, replica_labels: &[&str]) -> Vec<String> {
+        let replica_labels = replica_labels.iter().map(|label| label.to_string()).collect::<Vec<String>>();
+        plan(query, &replica_labels).unwrap().unwrap().queries
+    }
+
+    fn vector(json: &str) -> Response {
+        serde_json::from_str(&format!(r#"{{"status":"success","data":{{"resultType":"vector","result":{}}}}}"#, json)).unwrap()
+    }
+
+    fn vector_values(response: Response) -> Vec<(Vec<(String, String)>, String)> {
+        response.data.result.into_iter().map(|sample| {
+            let mut labels = sample.metric.unwrap().into_iter().collect::<Vec<(String, String)>>();
+            labels.sort();
+            (labels, sample.value.unwrap().1)
+        }).collect()
+    }
+
+    #[test]
+    fn it_should_not_push_down_log_queries() {
+        assert_eq!(plan("{app=\"foo\"} |= \"sum(\"", &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn it_should_not_push_down_ungrouped_range_aggregations() {
+        assert_eq!(plan("quantile_over_time(0.99, {app=\"foo\"} | unwrap latency [5m])", &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn it_should_push_down_sum() {
+        assert_eq!(queries("sum by (app) (rate({app=\"foo\"}[5m]))", &[]), vec!["sum by (app) (rate({app=\"foo\"}[5m]))"]);
+        assert_eq!(queries("sum(rate({app=\"foo\"}[5m])) by (app)", &[]), vec!["sum by (app) (rate({app=\"foo\"}[5m]))"]);
+    }
+
+    #[test]
+    fn it_should_push_down_avg_as_sum_and_count() {
+        assert_eq!(queries("avg without (pod) (rate({app=\"foo\"}[5m]))", &[]), vec![
+            "sum without (pod) (rate({app=\"foo\"}[5m]))",
+            "count without (pod) (rate({app=\"foo\"}[5m]))",
+        ]);
+    }
+
+    #[test]
+    fn it_should_group_by_replica_labels() {
+        assert_eq!(queries("sum(rate({app=\"foo\"}[5m]))", &["replica"]), vec!["sum by (replica) (rate({app=\"foo\"}[5m]))"]);
+        assert_eq!(queries("topk by (app) (5, rate({app=\"foo\"}[5m]))", &["replica"]), vec!["topk by (app, replica) (5, rate({app=\"foo\"}[5m]))"]);
+        assert_eq!(queries("max without (replica, pod) (rate({app=\"foo\"}[5m]))", &["replica"]), vec!["max without (pod) (rate({app=\"foo\"}[5m]))"]);
+    }
+
+    #[test]
+    fn it_should_fail_on_non_decomposable_aggregations() {
+        for query in [
+            "stddev(rate({app=\"foo\"}[5m]))",
+            "quantile_over_time(0.99, {app=\"foo\"} | unwrap latency [5m]) by (app)",
+            "sum(rate({app=\"foo\"}[5m])) / sum(rate({app=\"bar\"}[5m]))",
+            "topk(5, sum by (app) (rate({app=\"foo\"}[5m])))",
+        ] {
+            let error = plan(query, &[]).unwrap_err();
+            assert_eq!((error.status_code(), error.error_type()), (400, "bad_data"), "{}", query);
+        }
+    }
+
+    #[test]
+    fn it_should_not_push_down_queries_without_aggregation() {
+        assert_eq!(plan("rate({app=\"foo\"}[5m]) * 2", &[]).unwrap(), None);
+        assert_eq!(plan("(count_over_time({app=\"foo\"}[5m]))", &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn it_should_sum_partial_sums() {
+        let pushdown = plan("sum by (app) (rate({app=~\"foo|bar\"}[5m]))", &[]).unwrap().unwrap();
+        let response = pushdown.combine(vec![vec![
+            vector(r#"[{"metric":{"app":"foo"},"value":[1,"1"]},{"metric":{"app":"bar"},"value":[1,"2"]}]"#),
+            vector(r#"[{"metric":{"app":"foo"},"value":[1,"3"]}]"#),
+        ]]).unwrap();
+        assert_eq!(vector_values(response), vec![
+            (vec![("app".to_string(), "bar".to_string())], "2".to_string()),
+            (vec![("app".to_string(), "foo".to_string())], "4".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn it_should_deduplicate_replicas_before_summing() {
+        let pushdown = plan("sum(rate({app=\"foo\"}[5m]))", &["replica".to_string()]).unwrap().unwrap();
+        let response = pushdown.combine(vec![vec![
+            vector(r#"[{"metric":{"replica":"a"},"value":[1,"1"]}]"#),
+            vector(r#"[{"metric":{"replica":"a"},"value":[1,"3"]}]"#),
+            vector(r#"[{"metric":{"replica":"b"},"value":[1,"1"]}]"#),
+            vector(r#"[{"metric":{"replica":"b"},"value":[1,"3"]}]"#),
+        ]]).unwrap();
+        assert_eq!(vector_values(response), vec![(vec![], "4".to_string())]);
+    }
+
+    #[test]
+    fn it_should_divide_sums_by_counts() {
+        let pushdown = plan("avg(rate({app=\"foo\"}[5m]))", &[]).unwrap().unwrap();
+        let response = pushdown.combine(vec![
+            vec![vector(r#"[{"metric":{},"value":[1,"3"]}]"#), vector(r#"[{"metric":{},"value":[1,"6"]}]"#)],
+            vec![vector(r#"[{"metric":{},"value":[1,"1"]}]"#), vector(r#"[{"metric":{},"value":[1,"2"]}]"#)],
+        ]).unwrap();
+        assert_eq!(vector_values(response), vec![(vec![], "3".to_string())]);
+    }
+
+    #[test]
+    fn it_should_select_top_k_across_backends() {
+        let pushdown = plan("topk(2, rate({app=~\".+\"}[5m]))", &[]).unwrap().unwrap();
+        let response = pushdown.combine(vec![vec![
+            vector(r#"[{"metric":{"app":"a"},"value":[1,"5"]},{"metric":{"app":"b"},"value":[1,"1"]}]"#),
+            vector(r#"[{"metric":{"app":"c"},"value":[1,"3"]},{"metric":{"app":"d"},"value":[1,"2"]}]"#),
+        ]]).unwrap();
+        assert_eq!(vector_values(response), vec![
+            (vec![("app".to_string(), "a".to_string())], "5".to_string()),
+            (vec![("app".to_string(), "c".to_string())], "3".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn it_should_combine_matrix_results() {
+        let pushdown = plan("max by (app) (rate({app=\"foo\"}[5m]))", &[]).unwrap().unwrap();
+        let matrix = |json: &str| -> Response {
+            serde_json::from_str(&format!(r#"{{"status":"success","data":{{"resultType":"matrix","result":{}}}}}"#, json)).unwrap()
+        };
+        let response = pushdown.combine(vec![vec![
+            matrix(r#"[{"metric":{"app":"foo"},"values":[[1,"1"],[2,"5"]]}]"#),
+            matrix(r#"[{"metric":{"app":"foo"},"values":[[1,"3"],[3,"1"]]}]"#),
+        ]]).unwrap();
+        assert_eq!(response.data.result[0].values, Some(Values::Matrix(vec![
+            (1.0, "3".to_string()),
+            (2.0, "5".to_string()),
+            (3.0, "1".to_string()),
+        ])));
+    }
+}
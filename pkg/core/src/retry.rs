@@ -0,0 +1,189 @@
+use std::future::Future;
+use std::time::Duration;
+use async_trait::async_trait;
+use generic_loki_client::{Direction, LabelResponse, LokiClient, LokiError, QueryType, Response, ResponseStream, SerieResponse, TailStream};
+use log::warn;
+use rand::Rng;
+use tokio::time::Instant;
+use crate::config::RetryableError;
+
+/// Retries of the requests sent to the backends of a datasource
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts of a request including the first one
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub retry_on: Vec<RetryableError>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            retry_on: vec![RetryableError::Unavailable, RetryableError::ServerError, RetryableError::TooManyRequests],
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn is_retryable(&self, error: &LokiError) -> bool {
+        let class = match error {
+            LokiError::Unavailable { .. } => RetryableError::Unavailable,
+            LokiError::Internal { .. } => RetryableError::ServerError,
+            LokiError::TooManyRequests { .. } => RetryableError::TooManyRequests,
+            LokiError::Timeout { .. } => RetryableError::Timeout,
+            _ => return false,
+        };
+        self.retry_on.contains(&class)
+    }
+
+    /// Delay before the given retry, an exponential backoff with full jitter so the retries of
+    /// concurrent queries are spread. The delay requested by the backend is waited anyway.
+    fn backoff(&self, retry: u32, error: &LokiError) -> Duration {
+        let ceiling = self.backoff.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))).min(self.max_backoff);
+        let jittered = Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling.as_millis() as u64));
+        error.retry_after().map_or(jittered, |retry_after| retry_after.max(jittered))
+    }
+}
+
+/// Client retrying the requests which failed with a retryable error, as long as the retry
+/// can happen before the deadline of the query
+pub struct RetryingLokiClient<C> {
+    backend: String,
+    client: C,
+    policy: RetryPolicy,
+    deadline: Option<Instant>,
+}
+
+impl<C: LokiClient> RetryingLokiClient<C> {
+    pub fn new(backend: String, client: C, policy: RetryPolicy, deadline: Option<Instant>) -> Self {
+        RetryingLokiClient { backend, client, policy, deadline }
+    }
+
+    async fn retry<T, F: Future<Output = Result<T, LokiError>>>(&self, request: impl Fn() -> F) -> Result<T, LokiError> {
+        let mut attempt = 1;
+        loop {
+            let error = match request().await {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+            if attempt >= self.policy.max_attempts || !self.policy.is_retryable(&error) {
+                return Err(error);
+            }
+            let backoff = self.policy.backoff(attempt, &error);
+            //the error is answered rather than the expiration of the deadline while waiting
+            if self.deadline.is_some_and(|deadline| Instant::now() + backoff >= deadline) {
+                return Err(error);
+            }
+            warn!("Retrying the request to {} in {:?}: {}", self.backend, backoff, error);
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl<C: LokiClient> LokiClient for RetryingLokiClient<C> {
+    async fn query(&self, query: String, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<Response, LokiError> {
+        self.retry(|| self.client.query(query.clone(), query_type, limit, time, direction)).await
+    }
+
+    async fn query_range(&self, query: String, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<Response, LokiError> {
+        self.retry(|| self.client.query_range(query.clone(), query_type, start, end, limit, direction, step.clone(), interval.clone())).await
+    }
+
+    //only the start of the response is retried, a response failing while it is received fails the request
+    async fn query_stream(&self, query: String, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<ResponseStream, LokiError> {
+        self.retry(|| self.client.query_stream(query.clone(), query_type, limit, time, direction)).await
+    }
+
+    async fn query_range_stream(&self, query: String, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<ResponseStream, LokiError> {
+        self.retry(|| self.client.query_range_stream(query.clone(), query_type, start, end, limit, direction, step.clone(), interval.clone())).await
+    }
+
+    async fn labels(&self, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
+        self.retry(|| self.client.labels(start, end)).await
+    }
+
+    async fn label_values(&self, label: String, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
+        self.retry(|| self.client.label_values(label.clone(), start, end)).await
+    }
+
+    async fn series(&self, matches: Option<Vec<String>>, start: Option<i64>, end: Option<i64>) -> Result<SerieResponse, LokiError> {
+        self.retry(|| self.client.series(matches.clone(), start, end)).await
+    }
+
+    //only the start of the tail is retried, a tail failing afterwards is ended
+    async fn tail(&self, query: String, delay_for: Option<u32>, limit: Option<i32>, start: Option<i64>) -> Result<TailStream, LokiError> {
+        self.retry(|| self.client.tail(query.clone(), delay_for, limit, start)).await
+    }
+
+    //probes report the state of the backend as is
+    async fn ready(&self) -> Result<(), LokiError> {
+        self.client.ready().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future;
+    use crate::test_loki_client::MockTestLokiClient;
+
+    /// Backend answering the labels queries with the given errors first, expecting the given attempts
+    fn flaky_client(mut errors: Vec<LokiError>, attempts: usize) -> MockTestLokiClient {
+        let mut client = MockTestLokiClient::new();
+        client.expect_labels()
+            .times(attempts)
+            .returning(move |_, _| {
+                let result = match errors.pop() {
+                    Some(error) => Err(error),
+                    None => Ok(LabelResponse { status: "success".to_string(), data: Some(vec!["app".to_string()]), warnings: vec![] }),
+                };
+                Box::pin(future::ready(result))
+            });
+        client
+    }
+
+    fn unavailable(retry_after: Option<Duration>) -> LokiError {
+        LokiError::Unavailable { backend: "http://localhost:3100".to_string(), message: "connection reset".to_string(), retry_after }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy { backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(5), ..RetryPolicy::default() }
+    }
+
+    #[tokio::test]
+    async fn it_should_retry_retryable_errors() {
+        let client = RetryingLokiClient::new("http://localhost:3100".to_string(), flaky_client(vec![unavailable(None), unavailable(None)], 3), policy(), None);
+        assert!(client.labels(None, None).await.is_ok());
+
+        let client = RetryingLokiClient::new("http://localhost:3100".to_string(), flaky_client((0..3).map(|_| unavailable(None)).collect(), 3), policy(), None);
+        assert_eq!(client.labels(None, None).await.unwrap_err().status_code(), 503);
+
+        let bad_request = LokiError::BadRequest { backend: "http://localhost:3100".to_string(), message: "parse error".to_string() };
+        let client = RetryingLokiClient::new("http://localhost:3100".to_string(), flaky_client(vec![bad_request], 1), policy(), None);
+        assert_eq!(client.labels(None, None).await.unwrap_err().status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn it_should_not_retry_past_the_deadline() {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let client = RetryingLokiClient::new("http://localhost:3100".to_string(), flaky_client(vec![unavailable(Some(Duration::from_secs(5)))], 1), policy(), Some(deadline));
+        assert_eq!(client.labels(None, None).await.unwrap_err().status_code(), 503);
+    }
+
+    #[test]
+    fn it_should_back_off_exponentially() {
+        let policy = RetryPolicy { backoff: Duration::from_millis(100), max_backoff: Duration::from_millis(300), ..RetryPolicy::default() };
+        for _ in 0..100 {
+            assert!(policy.backoff(1, &unavailable(None)) <= Duration::from_millis(100));
+            assert!(policy.backoff(2, &unavailable(None)) <= Duration::from_millis(200));
+            assert!(policy.backoff(10, &unavailable(None)) <= Duration::from_millis(300));
+        }
+        assert_eq!(policy.backoff(1, &unavailable(Some(Duration::from_secs(2)))), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::Error;
+use futures::{stream, StreamExt};
+use generic_loki_client::{DroppedEntry, LokiError, TailResponse, TailStream, Values, VectorOrStream};
+use log::warn;
+use crate::config::PartialResponseStrategy;
+use crate::federated_loki::FederatedLoki;
+
+/// Entries remembered to recognize the copies sent by replicas, the oldest are forgotten first
+const DEDUP_WINDOW: usize = 100_000;
+
+/// Merge the tails of the backends: entries sent by several replicas are forwarded once,
+/// and entries are held until they are `delay_for` old so the entries of every backend are forwarded in timestamp order
+pub struct TailMerger {
+    replica_labels: Vec<String>,
+    /// Nanoseconds
+    delay: i64,
+    seen: HashSet<u64>,
+    seen_order: VecDeque<u64>,
+    /// Labels of the streams of the pending entries, by fingerprint
+    labels: HashMap<u64, HashMap<String, String>>,
+    /// Timestamp, stream fingerprint and line of the entries not yet forwarded
+    pending: Vec<(i64, u64, String)>,
+    dropped_entries: Vec<DroppedEntry>,
+}
+
+impl TailMerger {
+    pub fn new(replica_labels: Vec<String>, delay_for: u32) -> Self {
+        TailMerger {
+            replica_labels,
+            delay: Duration::from_secs(delay_for as u64).as_nanos() as i64,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            labels: HashMap::new(),
+            pending: Vec::new(),
+            dropped_entries: Vec::new(),
+        }
+    }
+
+    /// Remember an entry, false when it was already received from another backend
+    fn remember(&mut self, fingerprint: u64, timestamp: i64, line: &str) -> bool {
+        let mut hasher = DefaultHasher::new();
+        (fingerprint, timestamp, line).hash(&mut hasher);
+        let key = hasher.finish();
+        if !self.seen.insert(key) {
+            return false;
+        }
+        self.seen_order.push_back(key);
+        if self.seen_order.len() > DEDUP_WINDOW {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn push(&mut self, response: TailResponse) -> Result<(), LokiError> {
+        for stream in response.streams {
+            let labels = FederatedLoki::strip_replica_labels(&stream.stream.unwrap_or_default(), &self.replica_labels);
+            let fingerprint = FederatedLoki::fingerprint(&Some(labels.clone()));
+            let values = match stream.values {
+                Some(Values::Streams(values)) => values,
+                _ => continue,
+            };
+            let pending = self.pending.len();
+            for (timestamp, line) in values {
+                let timestamp = timestamp.parse::<i64>().map_err(|e| LokiError::Other(Error::new(e)))?;
+                if self.remember(fingerprint, timestamp, &line) {
+                    self.pending.push((timestamp, fingerprint, line));
+                }
+            }
+            if self.pending.len() > pending {
+                self.labels.entry(fingerprint).or_insert(labels);
+            }
+        }
+        for mut dropped_entry in response.dropped_entries {
+            dropped_entry.labels = FederatedLoki::strip_replica_labels(&dropped_entry.labels, &self.replica_labels);
+            if !self.dropped_entries.contains(&dropped_entry) {
+                self.dropped_entries.push(dropped_entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Time at which the oldest pending entry is due, in nanoseconds
+    pub fn next_flush(&self) -> Option<i64> {
+        self.pending.iter().map(|(timestamp, _, _)| timestamp.saturating_add(self.delay)).min()
+    }
+
+    /// Entries due at `now` (in nanoseconds) sorted by timestamp, None when there is nothing to forward
+    pub fn flush(&mut self, now: i64) -> Option<TailResponse> {
+        let due_before = now.saturating_sub(self.delay);
+        let (mut due, pending): (Vec<_>, Vec<_>) = self.pending.drain(..).partition(|(timestamp, _, _)| *timestamp <= due_before);
+        self.pending = pending;
+        if due.is_empty() && self.dropped_entries.is_empty() {
+            return None;
+        }
+        due.sort_by_key(|(timestamp, _, _)| *timestamp);
+
+        let mut streams: Vec<(u64, Vec<(String, String)>)> = Vec::new();
+        for (timestamp, fingerprint, line) in due {
+            match streams.iter_mut().find(|(stream, _)| *stream == fingerprint) {
+                Some((_, values)) => values.push((timestamp.to_string(), line)),
+                None => streams.push((fingerprint, vec![(timestamp.to_string(), line)])),
+            }
+        }
+        let pending_streams: HashSet<u64> = self.pending.iter().map(|(_, fingerprint, _)| *fingerprint).collect();
+        let streams = streams.into_iter().map(|(fingerprint, values)| VectorOrStream {
+            stream: if pending_streams.contains(&fingerprint) { self.labels.get(&fingerprint).cloned() } else { self.labels.remove(&fingerprint) },
+            values: Some(Values::Streams(values)),
+            metric: None,
+            value: None,
+        }).collect();
+        Some(TailResponse { streams, dropped_entries: std::mem::take(&mut self.dropped_entries) })
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_nanos() as i64)
+}
+
+/// Fan in the tails of the backends through the merger, until every tail ended.
+/// A failing tail ends the merged tail unless partial responses are allowed.
+pub fn merge_tails(tails: Vec<TailStream>, merger: TailMerger, strategy: PartialResponseStrategy) -> TailStream {
+    Box::pin(stream::unfold(Some((stream::select_all(tails), merger)), move |state| async move {
+        let (mut tails, mut merger) = state?;
+        loop {
+            let next = match merger.next_flush() {
+                Some(deadline) => {
+                    let wait = Duration::from_nanos(deadline.saturating_sub(now()).max(0) as u64);
+                    tokio::time::timeout(wait, tails.next()).await.ok()
+                }
+                None => Some(tails.next().await),
+            };
+            match next {
+                //the oldest pending entries are due
+                None => {}
+                Some(Some(Ok(response))) => {
+                    if let Err(error) = merger.push(response) {
+                        return Some((Err(error), None));
+                    }
+                }
+                Some(Some(Err(error))) if strategy == PartialResponseStrategy::Warn => warn!("A tail failed, tailing the other backends: {}", error),
+                Some(Some(Err(error))) => return Some((Err(error), None)),
+                Some(None) => return merger.flush(i64::MAX).map(|response| (Ok(response), None)),
+            }
+            if let Some(response) = merger.flush(now()) {
+                return Some((Ok(response), Some((tails, merger))));
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tail_response(replica: &str, entries: &[(i64, &str)]) -> TailResponse {
+        TailResponse {
+            streams: vec![VectorOrStream {
+                stream: Some(HashMap::from([("app".to_string(), "x".to_string()), ("replica".to_string(), replica.to_string())])),
+                values: Some(Values::Streams(entries.iter().map(|(timestamp, line)| (timestamp.to_string(), line.to_string())).collect())),
+                metric: None,
+                value: None,
+            }],
+            dropped_entries: vec![],
+        }
+    }
+
+    fn entries(response: &TailResponse) -> Vec<(String, String)> {
+        response.streams.iter().flat_map(|stream| match &stream.values {
+            Some(Values::Streams(values)) => values.clone(),
+            _ => vec![],
+        }).collect()
+    }
+
+    #[test]
+    fn it_should_forward_entries_of_replicas_once() {
+        let mut merger = TailMerger::new(vec!["replica".to_string()], 0);
+        merger.push(tail_response("a", &[(1, "a"), (2, "b")])).unwrap();
+        let response = merger.flush(now()).unwrap();
+        assert_eq!(entries(&response), vec![("1".to_string(), "a".to_string()), ("2".to_string(), "b".to_string())]);
+        assert_eq!(response.streams[0].stream, Some(HashMap::from([("app".to_string(), "x".to_string())])));
+
+        merger.push(tail_response("b", &[(1, "a"), (2, "b"), (3, "c")])).unwrap();
+        assert_eq!(entries(&merger.flush(now()).unwrap()), vec![("3".to_string(), "c".to_string())]);
+        assert!(merger.flush(now()).is_none());
+    }
+
+    #[test]
+    fn it_should_hold_entries_for_the_delay() {
+        let mut merger = TailMerger::new(vec![], 2);
+        merger.push(tail_response("a", &[(3_000_000_000, "c"), (1_000_000_000, "a")])).unwrap();
+        merger.push(tail_response("b", &[(2_000_000_000, "b")])).unwrap();
+        assert_eq!(merger.next_flush(), Some(3_000_000_000));
+        assert!(merger.flush(2_000_000_000).is_none());
+
+        let response = merger.flush(4_000_000_000).unwrap();
+        assert_eq!(response.streams.len(), 2);
+        assert_eq!(entries(&response), vec![("1000000000".to_string(), "a".to_string()), ("2000000000".to_string(), "b".to_string())]);
+        assert_eq!(merger.next_flush(), Some(5_000_000_000));
+    }
+
+    #[test]
+    fn it_should_forward_dropped_entries_once() {
+        let mut merger = TailMerger::new(vec!["replica".to_string()], 0);
+        for replica in ["a", "b"] {
+            merger.push(TailResponse {
+                streams: vec![],
+                dropped_entries: vec![DroppedEntry {
+                    labels: HashMap::from([("app".to_string(), "x".to_string()), ("replica".to_string(), replica.to_string())]),
+                    timestamp: "1".to_string(),
+                }],
+            }).unwrap();
+        }
+        let response = merger.flush(now()).unwrap();
+        assert_eq!(response.dropped_entries, vec![DroppedEntry { labels: HashMap::from([("app".to_string(), "x".to_string())]), timestamp: "1".to_string() }]);
+    }
+}
@@ -0,0 +1,22 @@
+use async_trait::async_trait;
+use generic_loki_client::{Direction, LabelResponse, LokiClient, LokiError, QueryType, Response, SerieResponse, TailStream};
+use mockall::automock;
+
+/// Backend mocked by the tests of the clients wrapping other clients and of the federation,
+/// expectations answer boxed futures so a backend can be slow or hang
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct TestLokiClient {}
+
+#[allow(clippy::too_many_arguments)]
+#[async_trait]
+#[automock]
+impl LokiClient for TestLokiClient {
+    async fn query(&self, _: String, _: QueryType, _: Option<i32>, _: Option<i64>, _: Option<Direction>) -> Result<Response, LokiError> { todo!() }
+    async fn query_range(&self, _: String, _: QueryType, _: i64, _: i64, _: Option<i32>, _: Option<Direction>, _: Option<String>, _: Option<String>) -> Result<Response, LokiError> { todo!() }
+    async fn labels(&self, _: Option<i64>, _: Option<i64>) -> Result<LabelResponse, LokiError> { todo!() }
+    async fn label_values(&self, _: String, _: Option<i64>, _: Option<i64>) -> Result<LabelResponse, LokiError> { todo!() }
+    async fn series(&self, _: Option<Vec<String>>, _: Option<i64>, _: Option<i64>) -> Result<SerieResponse, LokiError> { todo!() }
+    async fn tail(&self, _: String, _: Option<u32>, _: Option<i32>, _: Option<i64>) -> Result<TailStream, LokiError> { todo!() }
+    async fn ready(&self) -> Result<(), LokiError> { todo!() }
+}
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::LokiError;
+
+/// Bytes of backend responses buffered by a query, shared by the clients of every backend it is sent to.
+/// A query buffering more than the maximum is failed instead of exhausting the memory of the process.
+/// Bytes are held by [Reservation]s, they are released once the data they account for is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct BufferBudget {
+    /// No limit when None
+    max_bytes: Option<usize>,
+    used: Arc<AtomicUsize>,
+}
+
+impl BufferBudget {
+    pub fn new(max_bytes: Option<usize>) -> Self {
+        BufferBudget { max_bytes, used: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn unlimited() -> Self {
+        BufferBudget::new(None)
+    }
+
+    /// Account for bytes received from a backend, until the reservation is dropped
+    pub fn reserve(&self, backend: &str, bytes: usize) -> Result<Reservation, LokiError> {
+        let mut reservation = self.reservation();
+        reservation.grow(backend, bytes)?;
+        Ok(reservation)
+    }
+
+    /// Empty reservation, grown as data is received
+    pub fn reservation(&self) -> Reservation {
+        Reservation { budget: self.clone(), bytes: 0 }
+    }
+
+    /// Bytes held by the reservations of the budget
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    //bytes exceeding the limit are not held, the data they account for is dropped with the error
+    fn acquire(&self, backend: &str, bytes: usize) -> Result<(), LokiError> {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        match self.max_bytes {
+            Some(max_bytes) if used > max_bytes => {
+                self.release(bytes);
+                Err(LokiError::LimitExceeded {
+                    backend: backend.to_string(),
+                    message: format!("the query exceeded the limit of {} buffered bytes", max_bytes),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Bytes of a budget held by a response or by a part of it, released when the reservation is dropped:
+/// once the response was merged, when a retry starts over or when a hedged request loses
+#[derive(Debug, Default)]
+pub struct Reservation {
+    budget: BufferBudget,
+    bytes: usize,
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn grow(&mut self, backend: &str, bytes: usize) -> Result<(), LokiError> {
+        self.budget.acquire(backend, bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Grow or shrink the reservation to the given bytes
+    pub fn resize(&mut self, backend: &str, bytes: usize) -> Result<(), LokiError> {
+        if bytes > self.bytes {
+            return self.grow(backend, bytes - self.bytes);
+        }
+        self.budget.release(self.bytes - bytes);
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    /// Move some of the bytes to a new reservation, for a part of the data received
+    pub fn split(&mut self, bytes: usize) -> Reservation {
+        let bytes = bytes.min(self.bytes);
+        self.bytes -= bytes;
+        Reservation { budget: self.budget.clone(), bytes }
+    }
+
+    /// Take over the bytes of another reservation of the same budget
+    pub fn absorb(&mut self, mut other: Reservation) {
+        self.bytes += std::mem::take(&mut other.bytes);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_share_the_budget_between_backends() {
+        let budget = BufferBudget::new(Some(100));
+        let _reservation = budget.reserve("http://loki-a:3100", 60).unwrap();
+        let error = budget.clone().reserve("http://loki-b:3100", 60).unwrap_err();
+        assert_eq!(error.backend(), Some("http://loki-b:3100"));
+        assert_eq!(budget.used(), 60);
+    }
+
+    #[test]
+    fn it_should_release_the_bytes_of_dropped_reservations() {
+        let budget = BufferBudget::new(Some(100));
+        let mut reservation = budget.reserve("http://loki-a:3100", 60).unwrap();
+        let part = reservation.split(40);
+        assert_eq!((reservation.bytes(), part.bytes()), (20, 40));
+        drop(part);
+        assert_eq!(budget.used(), 20);
+        reservation.resize("http://loki-a:3100", 90).unwrap();
+        assert!(reservation.resize("http://loki-a:3100", 110).is_err());
+        assert_eq!(budget.used(), 90);
+        let mut other = budget.reserve("http://loki-b:3100", 10).unwrap();
+        other.absorb(reservation);
+        assert_eq!(budget.used(), 100);
+        drop(other);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn it_should_not_limit_unlimited_budgets() {
+        assert!(BufferBudget::unlimited().reserve("http://loki-a:3100", usize::MAX / 2).is_ok());
+    }
+}
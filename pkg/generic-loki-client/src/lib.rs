@@ -1,16 +1,37 @@
-use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::pin::Pin;
+use std::time::Duration;
+use futures::{stream, Stream, TryStreamExt};
+use serde::{Deserialize, Deserializer, Serialize};
 use async_trait::async_trait;
 use thiserror::Error;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+pub mod stats;
+pub use stats::Stats;
+mod budget;
+pub use budget::{BufferBudget, Reservation};
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ResultType {
     #[serde(alias = "vector")]
     #[serde(rename(serialize = "vector"))]
     Vector,
     #[serde(alias = "streams")]
     #[serde(rename(serialize = "streams"))]
-    Streams
+    Streams,
+    #[serde(alias = "matrix")]
+    #[serde(rename(serialize = "matrix"))]
+    Matrix
+}
+
+/// Values of a stream (`[["<nanoseconds>", "<log line>"], ...]`)
+/// or of a matrix serie (`[[<seconds>, "<sample value>"], ...]`)
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Values {
+    Streams(Vec<(String, String)>),
+    Matrix(Vec<(f64, String)>),
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -18,11 +39,24 @@ pub struct VectorOrStream {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metric: Option<HashMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub value: Option<(i64, String)>,
+    pub value: Option<(f64, String)>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<HashMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub values: Option<Vec<(String, String)>>,
+    pub values: Option<Values>,
+}
+
+impl VectorOrStream {
+    /// Type of the result holding this stream, serie or sample
+    pub fn result_type(&self) -> ResultType {
+        if self.stream.is_some() {
+            ResultType::Streams
+        } else if self.value.is_some() {
+            ResultType::Vector
+        } else {
+            ResultType::Matrix
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -30,25 +64,170 @@ pub struct VectorOrStream {
 pub struct Data {
     pub result_type: ResultType,
     pub result: Vec<VectorOrStream>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Stats>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Response {
     pub status: String,
     pub data: Data,
+    /// Failures of backends which didn't prevent answering the request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl Response {
+    /// Serialize the response as chunks of JSON, one per result, so that the response is never held serialized in memory.
+    /// The chunks are consumed lazily and make up the same document as serializing the whole response.
+    pub fn into_json_chunks(self) -> impl Iterator<Item = Result<Vec<u8>, serde_json::Error>> {
+        let Response { status, data: Data { result_type, result, stats }, warnings } = self;
+        let head = serde_json::to_string(&status).and_then(|status| {
+            serde_json::to_string(&result_type).map(|result_type| format!(r#"{{"status":{},"data":{{"resultType":{},"result":["#, status, result_type).into_bytes())
+        });
+        let results = result.into_iter().enumerate().map(|(index, result)| {
+            let mut chunk = if index > 0 { b",".to_vec() } else { Vec::new() };
+            serde_json::to_writer(&mut chunk, &result)?;
+            Ok(chunk)
+        });
+        let tail = std::iter::once_with(move || {
+            let mut chunk = b"]".to_vec();
+            if let Some(stats) = stats {
+                chunk.extend_from_slice(br#","stats":"#);
+                serde_json::to_writer(&mut chunk, &stats)?;
+            }
+            chunk.push(b'}');
+            if !warnings.is_empty() {
+                chunk.extend_from_slice(br#","warnings":"#);
+                serde_json::to_writer(&mut chunk, &warnings)?;
+            }
+            chunk.push(b'}');
+            Ok(chunk)
+        });
+        std::iter::once(head).chain(results).chain(tail)
+    }
+
+    /// Parts of a response already received, its results are not accounted in a budget
+    pub fn into_stream(self) -> ResponseStream {
+        let Response { status, data: Data { result_type, result, stats }, warnings } = self;
+        let end = Response { status, data: Data { result_type, result: vec![], stats }, warnings };
+        let results = result.into_iter().map(|result| Ok(ResponsePart::Result(result, Reservation::default())));
+        Box::pin(stream::iter(results.chain(std::iter::once(Ok(ResponsePart::End(Box::new(end)))))))
+    }
+
+    /// Receive a whole response, the parts of a stream or of a serie are joined.
+    /// The reservation holds the bytes of the response in the budget of the query.
+    pub async fn from_stream(mut parts: impl Stream<Item = Result<ResponsePart, LokiError>> + Unpin) -> Result<(Response, Reservation), LokiError> {
+        let mut result: Vec<VectorOrStream> = Vec::new();
+        let mut result_index: HashMap<(ResultType, Option<BTreeMap<String, String>>), usize> = HashMap::new();
+        let mut reservation = Reservation::default();
+        while let Some(part) = parts.try_next().await? {
+            let (part, part_reservation) = match part {
+                ResponsePart::Result(part, part_reservation) => (part, part_reservation),
+                ResponsePart::End(mut response) => {
+                    response.data.result = result;
+                    return Ok((*response, reservation));
+                }
+            };
+            reservation.absorb(part_reservation);
+            let labels = part.stream.as_ref().or(part.metric.as_ref()).map(|labels| labels.clone().into_iter().collect());
+            match result_index.entry((part.result_type(), labels)) {
+                Entry::Occupied(index) if part.value.is_none() => {
+                    let joined = &mut result[*index.get()];
+                    match (&mut joined.values, part.values) {
+                        (Some(Values::Streams(values)), Some(Values::Streams(part_values))) => values.extend(part_values),
+                        (Some(Values::Matrix(values)), Some(Values::Matrix(part_values))) => {
+                            values.extend(part_values);
+                            values.sort_by(|(timestamp_a, _), (timestamp_b, _)| timestamp_a.total_cmp(timestamp_b));
+                        }
+                        (values, part_values) => *values = values.take().or(part_values),
+                    }
+                }
+                Entry::Occupied(_) => result.push(part),
+                Entry::Vacant(index) => {
+                    index.insert(result.len());
+                    result.push(part);
+                }
+            }
+        }
+        Err(LokiError::Other(anyhow::anyhow!("The response ended before it was fully received")))
+    }
+}
+
+/// Part of the response to a query. The results are received one at a time, a stream or a serie may be split
+/// across several parts. The end of the response comes last, after every result.
+#[derive(Debug)]
+pub enum ResponsePart {
+    /// Stream, serie or sample of the result, its bytes are held in the budget of the query until it is dropped
+    Result(VectorOrStream, Reservation),
+    /// Rest of the response, its result is empty
+    End(Box<Response>),
 }
 
+/// Parts of the response to a query as they are received from the backend
+pub type ResponseStream = Pin<Box<dyn Stream<Item = Result<ResponsePart, LokiError>> + Send>>;
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct LabelResponse {
     pub status: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<Vec<String>>,
+    /// Failures of backends which didn't prevent answering the request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct SerieResponse {
     pub status: String,
     pub data: Vec<HashMap<String, String>>,
+    /// Failures of backends which didn't prevent answering the request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// Message sent by Loki to tailing clients, streams hold the entries ingested since the previous message
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TailResponse {
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub streams: Vec<VectorOrStream>,
+    /// Entries the backend dropped because the client was too slow to receive them
+    #[serde(default, deserialize_with = "null_as_default", skip_serializing_if = "Vec::is_empty")]
+    pub dropped_entries: Vec<DroppedEntry>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DroppedEntry {
+    pub labels: HashMap<String, String>,
+    /// Nanoseconds
+    pub timestamp: String,
+}
+
+/// Responses of a tail, until the backend closes the connection
+pub type TailStream = Pin<Box<dyn Stream<Item = Result<TailResponse, LokiError>> + Send>>;
+
+//Loki serializes empty arrays of tail responses as null
+fn null_as_default<'de, D: Deserializer<'de>, T: Deserialize<'de> + Default>(deserializer: D) -> Result<T, D::Error> {
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Error body answered by Loki (`{"status":"error","errorType":"bad_data","error":"..."}`)
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    pub status: String,
+    pub error_type: String,
+    pub error: String,
+}
+
+impl From<&LokiError> for ErrorResponse {
+    fn from(error: &LokiError) -> Self {
+        ErrorResponse {
+            status: "error".to_string(),
+            error_type: error.error_type().to_string(),
+            error: error.to_string(),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Copy, Clone)]
@@ -57,21 +236,222 @@ pub enum Direction {
     Backward
 }
 
+/// Log queries answer streams while metric queries answer samples,
+/// the federation tells them apart once it parsed the query
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueryType {
+    Logs,
+    Metric,
+}
+
+/// Errors of the federation, errors raised by a backend are tagged with its url
 #[derive(Error, Debug)]
 pub enum LokiError {
     #[error("Not yet implemented")]
     NotImplemented,
     #[error("No data")]
     NoData,
+    #[error("{0}")]
+    InvalidQuery(String),
+    #[error("{message}")]
+    BadRequest { backend: String, message: String },
+    #[error("{backend} rejected the credentials: {message}")]
+    Unauthorized { backend: String, message: String },
+    /// `retry_after` is the delay requested by the backend before sending another request
+    #[error("{backend} is rate limiting: {message}")]
+    TooManyRequests { backend: String, message: String, retry_after: Option<Duration> },
+    #[error("{backend} timed out: {message}")]
+    Timeout { backend: String, message: String },
+    #[error("{backend} is unavailable: {message}")]
+    Unavailable { backend: String, message: String, retry_after: Option<Duration> },
+    #[error("{backend} failed: {message}")]
+    Internal { backend: String, message: String },
+    #[error("Unable to decode the response of {backend}: {message}")]
+    Decode { backend: String, message: String },
+    #[error("Request to {backend} was canceled: {message}")]
+    Canceled { backend: String, message: String },
+    #[error("Response of {backend} was rejected, {message}")]
+    LimitExceeded { backend: String, message: String },
     #[error(transparent)]
     Other(#[from] anyhow::Error)
 }
 
+impl LokiError {
+    /// Url of the backend which raised the error
+    pub fn backend(&self) -> Option<&str> {
+        match self {
+            LokiError::BadRequest { backend, .. }
+            | LokiError::Unauthorized { backend, .. }
+            | LokiError::TooManyRequests { backend, .. }
+            | LokiError::Timeout { backend, .. }
+            | LokiError::Unavailable { backend, .. }
+            | LokiError::Internal { backend, .. }
+            | LokiError::Decode { backend, .. }
+            | LokiError::Canceled { backend, .. }
+            | LokiError::LimitExceeded { backend, .. } => Some(backend),
+            _ => None,
+        }
+    }
+
+    /// Http status code answered by the federation for this error
+    pub fn status_code(&self) -> u16 {
+        match self {
+            LokiError::InvalidQuery(_) | LokiError::BadRequest { .. } => 400,
+            LokiError::Unauthorized { .. } => 401,
+            LokiError::LimitExceeded { .. } => 422,
+            LokiError::TooManyRequests { .. } => 429,
+            LokiError::Canceled { .. } => 499,
+            LokiError::NotImplemented => 501,
+            LokiError::Decode { .. } => 502,
+            LokiError::Unavailable { .. } => 503,
+            LokiError::Timeout { .. } => 504,
+            LokiError::NoData | LokiError::Internal { .. } | LokiError::Other(_) => 500,
+        }
+    }
+
+    /// Type of the error as reported in the `errorType` field of Loki error responses
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            LokiError::InvalidQuery(_) | LokiError::BadRequest { .. } => "bad_data",
+            LokiError::Unauthorized { .. } => "unauthorized",
+            LokiError::TooManyRequests { .. } => "too_many_requests",
+            LokiError::Canceled { .. } => "canceled",
+            LokiError::Timeout { .. } => "timeout",
+            LokiError::Unavailable { .. } => "unavailable",
+            LokiError::LimitExceeded { .. } => "execution",
+            LokiError::NotImplemented | LokiError::NoData | LokiError::Internal { .. } | LokiError::Decode { .. } | LokiError::Other(_) => "internal",
+        }
+    }
+
+    /// Delay requested by the backend before retrying, as with the `Retry-After` header
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LokiError::TooManyRequests { retry_after, .. } | LokiError::Unavailable { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Set the delay requested by the backend before retrying, kept only by the errors it applies to
+    pub fn with_retry_after(mut self, delay: Option<Duration>) -> Self {
+        if let LokiError::TooManyRequests { retry_after, .. } | LokiError::Unavailable { retry_after, .. } = &mut self {
+            *retry_after = delay;
+        }
+        self
+    }
+
+    /// Error matching the http status code answered by a backend, None for success codes
+    pub fn from_status_code(backend: &str, status_code: u16, message: String) -> Option<LokiError> {
+        let backend = backend.to_string();
+        match status_code {
+            100..=399 => None,
+            400 | 422 => Some(LokiError::BadRequest { backend, message }),
+            401 | 403 => Some(LokiError::Unauthorized { backend, message }),
+            429 => Some(LokiError::TooManyRequests { backend, message, retry_after: None }),
+            499 => Some(LokiError::Canceled { backend, message }),
+            408 | 504 => Some(LokiError::Timeout { backend, message }),
+            502 | 503 => Some(LokiError::Unavailable { backend, message, retry_after: None }),
+            500..=599 => Some(LokiError::Internal { backend, message: format!("status {}: {}", status_code, message) }),
+            _ => Some(LokiError::Other(anyhow::anyhow!("{} answered with status {}: {}", backend, status_code, message))),
+        }
+    }
+}
+
 #[async_trait]
-pub trait LokiClient {
-    async fn query(&self, query: String, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<Response, LokiError>;
-    async fn query_range(&self, query: String, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<Response, LokiError>;
+/// Client of a backend, the default methods hold it across await points so it must be `Sync`
+pub trait LokiClient: Send + Sync {
+    async fn query(&self, query: String, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<Response, LokiError>;
+    #[allow(clippy::too_many_arguments)]
+    async fn query_range(&self, query: String, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<Response, LokiError>;
+    /// Response of [LokiClient::query] as it is received, the request has started once the stream is returned
+    async fn query_stream(&self, query: String, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<ResponseStream, LokiError> {
+        Ok(self.query(query, query_type, limit, time, direction).await?.into_stream())
+    }
+    /// Response of [LokiClient::query_range] as it is received, the request has started once the stream is returned
+    #[allow(clippy::too_many_arguments)]
+    async fn query_range_stream(&self, query: String, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<ResponseStream, LokiError> {
+        Ok(self.query_range(query, query_type, start, end, limit, direction, step, interval).await?.into_stream())
+    }
     async fn labels(&self, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError>;
     async fn label_values(&self, label: String, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError>;
     async fn series(&self, matches: Option<Vec<String>>, start: Option<i64>, end: Option<i64>) -> Result<SerieResponse, LokiError>;
-}
\ No newline at end of file
+    /// Entries matching the query as they are ingested, `delay_for` is in seconds and `start` in nanoseconds
+    async fn tail(&self, query: String, delay_for: Option<u32>, limit: Option<i32>, start: Option<i64>) -> Result<TailStream, LokiError>;
+    /// Probe the backend, an error when it isn't ready to answer queries
+    async fn ready(&self) -> Result<(), LokiError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_map_backend_status_codes() {
+        assert!(LokiError::from_status_code("http://loki:3100", 200, "".to_string()).is_none());
+        let error = LokiError::from_status_code("http://loki:3100", 400, "parse error at line 1, col 5".to_string()).unwrap();
+        assert_eq!(error.status_code(), 400);
+        assert_eq!(error.backend(), Some("http://loki:3100"));
+        assert_eq!(error.to_string(), "parse error at line 1, col 5");
+        assert_eq!(LokiError::from_status_code("http://loki:3100", 403, "".to_string()).unwrap().status_code(), 401);
+        assert_eq!(LokiError::from_status_code("http://loki:3100", 429, "".to_string()).unwrap().status_code(), 429);
+        assert_eq!(LokiError::from_status_code("http://loki:3100", 503, "".to_string()).unwrap().status_code(), 503);
+        assert_eq!(LokiError::from_status_code("http://loki:3100", 504, "".to_string()).unwrap().status_code(), 504);
+        assert_eq!(LokiError::from_status_code("http://loki:3100", 500, "".to_string()).unwrap().status_code(), 500);
+        let error = LokiError::from_status_code("http://loki:3100", 500, "too many outstanding requests".to_string()).unwrap();
+        assert_eq!(error.to_string(), "http://loki:3100 failed: status 500: too many outstanding requests");
+
+        let error = LokiError::from_status_code("http://loki:3100", 429, "".to_string()).unwrap().with_retry_after(Some(Duration::from_secs(2)));
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(2)));
+        assert_eq!(LokiError::from_status_code("http://loki:3100", 400, "".to_string()).unwrap().with_retry_after(Some(Duration::from_secs(2))).retry_after(), None);
+    }
+
+    #[test]
+    fn it_should_serialize_responses_in_chunks() {
+        let json = r#"{"status":"success","data":{"resultType":"streams","result":[{"stream":{"app":"x"},"values":[["2","b"],["1","a"]]},{"stream":{"app":"y"},"values":[["3","c"]]}],"stats":{"summary":{"totalBytesProcessed":10}}},"warnings":["http://loki:3100 is unavailable: connection refused"]}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+        let chunks = response.clone().into_json_chunks().collect::<Result<Vec<Vec<u8>>, serde_json::Error>>().unwrap();
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks.concat(), serde_json::to_vec(&response).unwrap());
+
+        let empty: Response = serde_json::from_str(r#"{"status":"success","data":{"resultType":"vector","result":[]}}"#).unwrap();
+        assert_eq!(empty.clone().into_json_chunks().collect::<Result<Vec<Vec<u8>>, serde_json::Error>>().unwrap().concat(), serde_json::to_vec(&empty).unwrap());
+    }
+
+    #[test]
+    fn it_should_join_the_parts_of_streams_and_series() {
+        let serie = |values: Vec<(f64, String)>| VectorOrStream { metric: Some(HashMap::from([("app".to_string(), "x".to_string())])), value: None, stream: None, values: Some(Values::Matrix(values)) };
+        let end = Response { status: "success".to_string(), data: Data { result_type: ResultType::Matrix, result: vec![], stats: None }, warnings: vec![] };
+        let budget = BufferBudget::unlimited();
+        let parts = vec![
+            Ok(ResponsePart::Result(serie(vec![(2.0, "2".to_string())]), budget.reserve("http://loki:3100", 10).unwrap())),
+            Ok(ResponsePart::Result(serie(vec![(1.0, "1".to_string())]), budget.reserve("http://loki:3100", 20).unwrap())),
+            Ok(ResponsePart::End(Box::new(end))),
+        ];
+        let (response, reservation) = futures::executor::block_on(Response::from_stream(stream::iter(parts))).unwrap();
+        assert_eq!(response.data.result.len(), 1);
+        assert_eq!(response.data.result[0].values, Some(Values::Matrix(vec![(1.0, "1".to_string()), (2.0, "2".to_string())])));
+        assert_eq!((reservation.bytes(), budget.used()), (30, 30));
+
+        let parts = vec![Ok(ResponsePart::Result(serie(vec![]), Reservation::default()))];
+        assert!(futures::executor::block_on(Response::from_stream(stream::iter(parts))).is_err());
+    }
+
+    #[test]
+    fn it_should_serialize_loki_error_responses() {
+        let error = LokiError::BadRequest { backend: "http://loki:3100".to_string(), message: "parse error at line 1, col 5".to_string() };
+        assert_eq!(
+            serde_json::to_string(&ErrorResponse::from(&error)).unwrap(),
+            r#"{"status":"error","errorType":"bad_data","error":"parse error at line 1, col 5"}"#
+        );
+    }
+
+    #[test]
+    fn it_should_decode_tail_responses() {
+        let response: TailResponse = serde_json::from_str(r#"{"streams":[{"stream":{"app":"x"},"values":[["1","a"]]}],"dropped_entries":null}"#).unwrap();
+        assert_eq!(response.streams[0].values, Some(Values::Streams(vec![("1".to_string(), "a".to_string())])));
+        assert!(response.dropped_entries.is_empty());
+
+        let response: TailResponse = serde_json::from_str(r#"{"streams":null,"dropped_entries":[{"labels":{"app":"x"},"timestamp":"2"}]}"#).unwrap();
+        assert!(response.streams.is_empty());
+        assert_eq!(response.dropped_entries, vec![DroppedEntry { labels: HashMap::from([("app".to_string(), "x".to_string())]), timestamp: "2".to_string() }]);
+    }
+}
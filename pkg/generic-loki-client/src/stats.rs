@@ -0,0 +1,228 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+/// Statistics of a query as returned by Loki in `data.stats`, completed by the federation statistics
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Stats {
+    pub summary: Summary,
+    pub querier: Querier,
+    pub ingester: Ingester,
+    pub cache: Caches,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub federation: Option<FederationStats>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Summary {
+    pub bytes_processed_per_second: i64,
+    pub lines_processed_per_second: i64,
+    pub total_bytes_processed: i64,
+    pub total_lines_processed: i64,
+    /// Seconds
+    pub exec_time: f64,
+    /// Seconds
+    pub queue_time: f64,
+    pub subqueries: i64,
+    pub total_entries_returned: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Querier {
+    pub store: Store,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Ingester {
+    pub total_reached: i64,
+    pub total_chunks_matched: i64,
+    pub total_batches: i64,
+    pub total_lines_sent: i64,
+    pub store: Store,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Store {
+    pub total_chunks_ref: i64,
+    pub total_chunks_downloaded: i64,
+    /// Nanoseconds
+    pub chunks_download_time: i64,
+    pub chunk: Chunk,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Chunk {
+    pub head_chunk_bytes: i64,
+    pub head_chunk_lines: i64,
+    pub decompressed_bytes: i64,
+    pub decompressed_lines: i64,
+    pub compressed_bytes: i64,
+    pub total_duplicates: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Caches {
+    pub chunk: Cache,
+    pub index: Cache,
+    pub result: Cache,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Cache {
+    pub entries_found: i64,
+    pub entries_requested: i64,
+    pub entries_stored: i64,
+    pub bytes_received: i64,
+    pub bytes_sent: i64,
+    pub requests: i64,
+    /// Nanoseconds
+    pub download_time: i64,
+}
+
+/// Statistics of the federation itself, not part of Loki's response
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FederationStats {
+    pub backends_queried: usize,
+    pub backends_failed: usize,
+    /// Entries (log lines or samples) received from the backends
+    pub entries_before_dedup: usize,
+    /// Entries answered once the results of the backends are merged
+    pub entries_after_dedup: usize,
+    pub backends: Vec<BackendStats>,
+}
+
+/// Cost of a request to a backend
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BackendStats {
+    pub backend: String,
+    pub latency_seconds: f64,
+    pub bytes_received: usize,
+}
+
+impl Stats {
+    /// Stats of a response received from a backend
+    pub fn backend(backend: &str, latency: Duration, bytes_received: usize) -> Self {
+        Stats {
+            federation: Some(FederationStats {
+                backends: vec![BackendStats { backend: backend.to_string(), latency_seconds: latency.as_secs_f64(), bytes_received }],
+                ..FederationStats::default()
+            }),
+            ..Stats::default()
+        }
+    }
+
+    /// Add the stats of another response, as Loki does when merging the stats of subqueries
+    pub fn merge(&mut self, other: &Stats) {
+        self.summary.merge(&other.summary);
+        self.querier.store.merge(&other.querier.store);
+        self.ingester.merge(&other.ingester);
+        self.cache.chunk.merge(&other.cache.chunk);
+        self.cache.index.merge(&other.cache.index);
+        self.cache.result.merge(&other.cache.result);
+        if let Some(other) = &other.federation {
+            self.federation.get_or_insert_with(FederationStats::default).merge(other);
+        }
+    }
+}
+
+impl Summary {
+    fn merge(&mut self, other: &Summary) {
+        self.total_bytes_processed += other.total_bytes_processed;
+        self.total_lines_processed += other.total_lines_processed;
+        self.exec_time += other.exec_time;
+        self.queue_time += other.queue_time;
+        self.subqueries += other.subqueries;
+        self.total_entries_returned += other.total_entries_returned;
+        //rates are computed from the totals, as Loki does
+        if self.exec_time > 0.0 {
+            self.bytes_processed_per_second = (self.total_bytes_processed as f64 / self.exec_time) as i64;
+            self.lines_processed_per_second = (self.total_lines_processed as f64 / self.exec_time) as i64;
+        }
+    }
+}
+
+impl Ingester {
+    fn merge(&mut self, other: &Ingester) {
+        self.total_reached += other.total_reached;
+        self.total_chunks_matched += other.total_chunks_matched;
+        self.total_batches += other.total_batches;
+        self.total_lines_sent += other.total_lines_sent;
+        self.store.merge(&other.store);
+    }
+}
+
+impl Store {
+    fn merge(&mut self, other: &Store) {
+        self.total_chunks_ref += other.total_chunks_ref;
+        self.total_chunks_downloaded += other.total_chunks_downloaded;
+        self.chunks_download_time += other.chunks_download_time;
+        self.chunk.head_chunk_bytes += other.chunk.head_chunk_bytes;
+        self.chunk.head_chunk_lines += other.chunk.head_chunk_lines;
+        self.chunk.decompressed_bytes += other.chunk.decompressed_bytes;
+        self.chunk.decompressed_lines += other.chunk.decompressed_lines;
+        self.chunk.compressed_bytes += other.chunk.compressed_bytes;
+        self.chunk.total_duplicates += other.chunk.total_duplicates;
+    }
+}
+
+impl Cache {
+    fn merge(&mut self, other: &Cache) {
+        self.entries_found += other.entries_found;
+        self.entries_requested += other.entries_requested;
+        self.entries_stored += other.entries_stored;
+        self.bytes_received += other.bytes_received;
+        self.bytes_sent += other.bytes_sent;
+        self.requests += other.requests;
+        self.download_time += other.download_time;
+    }
+}
+
+impl FederationStats {
+    fn merge(&mut self, other: &FederationStats) {
+        self.backends_queried += other.backends_queried;
+        self.backends_failed += other.backends_failed;
+        self.entries_before_dedup += other.entries_before_dedup;
+        self.entries_after_dedup += other.entries_after_dedup;
+        self.backends.extend(other.backends.iter().cloned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_sum_loki_stats() {
+        let mut stats: Stats = serde_json::from_str(r#"{"summary":{"totalBytesProcessed":100,"totalLinesProcessed":10,"execTime":1.0,"subqueries":1},"querier":{"store":{"totalChunksRef":2,"chunk":{"decompressedLines":10}}},"cache":{"chunk":{"requests":3}}}"#).unwrap();
+        let other: Stats = serde_json::from_str(r#"{"summary":{"totalBytesProcessed":300,"totalLinesProcessed":30,"execTime":3.0,"subqueries":1},"querier":{"store":{"totalChunksRef":4,"chunk":{"decompressedLines":30}}},"ingester":{"totalReached":2},"cache":{"chunk":{"requests":1}}}"#).unwrap();
+        stats.merge(&other);
+        assert_eq!(stats.summary.total_bytes_processed, 400);
+        assert_eq!(stats.summary.bytes_processed_per_second, 100);
+        assert_eq!(stats.summary.lines_processed_per_second, 10);
+        assert_eq!(stats.summary.subqueries, 2);
+        assert_eq!(stats.querier.store.total_chunks_ref, 6);
+        assert_eq!(stats.querier.store.chunk.decompressed_lines, 40);
+        assert_eq!(stats.ingester.total_reached, 2);
+        assert_eq!(stats.cache.chunk.requests, 4);
+        assert_eq!(stats.federation, None);
+    }
+
+    #[test]
+    fn it_should_collect_backend_stats() {
+        let mut stats = Stats::backend("http://loki-a:3100", Duration::from_millis(250), 1024);
+        stats.merge(&Stats::backend("http://loki-b:3100", Duration::from_millis(500), 2048));
+        let federation = stats.federation.unwrap();
+        assert_eq!(federation.backends.iter().map(|backend| backend.backend.as_str()).collect::<Vec<&str>>(), vec!["http://loki-a:3100", "http://loki-b:3100"]);
+        assert_eq!(federation.backends[1].latency_seconds, 0.5);
+        assert_eq!(federation.backends[1].bytes_received, 2048);
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::anyhow;
+use generic_loki_client::LokiError;
+use log::info;
+use tonic::transport::{Channel, Endpoint};
+
+/// Transport settings of the channels opened to the backends
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    pub connect_timeout: Duration,
+    /// Deadline of the requests sent to the backends, tails excepted, none when None
+    pub request_timeout: Option<Duration>,
+    /// Interval of the http2 keepalive pings, disabled when None
+    pub keep_alive_interval: Option<Duration>,
+    pub keep_alive_timeout: Duration,
+    pub tcp_keepalive: Option<Duration>,
+    /// Largest response message accepted from a backend, in bytes
+    pub max_message_size: usize,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Some(Duration::from_secs(60)),
+            keep_alive_interval: Some(Duration::from_secs(30)),
+            keep_alive_timeout: Duration::from_secs(20),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            max_message_size: 100 * 1024 * 1024,
+        }
+    }
+}
+
+/// Long-lived channels shared by every client of a backend, keyed by url.
+/// Channels connect on first use and reconnect by themselves when the connection is lost,
+/// a channel is only dropped from the cache when the backend is unavailable so the next request opens a new one.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelCache {
+    config: ChannelConfig,
+    channels: Arc<Mutex<HashMap<String, Channel>>>,
+}
+
+impl ChannelCache {
+    pub fn new(config: ChannelConfig) -> Self {
+        ChannelCache { config, channels: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn config(&self) -> &ChannelConfig {
+        &self.config
+    }
+
+    pub fn get(&self, url: &str) -> Result<Channel, LokiError> {
+        let mut channels = self.channels.lock().map_err(|e| LokiError::Other(anyhow!("Channel cache is poisoned: {}", e)))?;
+        if let Some(channel) = channels.get(url) {
+            return Ok(channel.clone());
+        }
+        info!("Opening a channel to {}", url);
+        let mut endpoint = Endpoint::from_shared(url.to_string())
+            .map_err(|e| LokiError::Other(anyhow!("Invalid url {}: {}", url, e)))?
+            .connect_timeout(self.config.connect_timeout)
+            .keep_alive_timeout(self.config.keep_alive_timeout)
+            .tcp_keepalive(self.config.tcp_keepalive);
+        if let Some(keep_alive_interval) = self.config.keep_alive_interval {
+            endpoint = endpoint.http2_keep_alive_interval(keep_alive_interval);
+        }
+        let channel = endpoint.connect_lazy();
+        channels.insert(url.to_string(), channel.clone());
+        Ok(channel)
+    }
+
+    pub fn invalidate(&self, url: &str) {
+        if let Ok(mut channels) = self.channels.lock() {
+            channels.remove(url);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.channels.lock().map_or(0, |channels| channels.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_should_reuse_channels_of_a_backend() {
+        let cache = ChannelCache::new(ChannelConfig::default());
+        cache.get("http://localhost:9096").unwrap();
+        cache.clone().get("http://localhost:9096").unwrap();
+        cache.get("http://localhost:9097").unwrap();
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_reopen_invalidated_channels() {
+        let cache = ChannelCache::new(ChannelConfig::default());
+        cache.get("http://localhost:9096").unwrap();
+        cache.invalidate("http://localhost:9096");
+        assert_eq!(cache.len(), 0);
+        cache.get("http://localhost:9096").unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn it_should_fail_on_invalid_url() {
+        assert!(ChannelCache::default().get("not a url").is_err());
+    }
+}
@@ -1,154 +1,578 @@
-//Allowing daed code in this module as it is not yet implemented
-#![allow(dead_code, unused_imports, unused_variables)]
-
-use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use anyhow::anyhow;
-use generic_loki_client::{Data, Direction, LabelResponse, LokiClient, LokiError, Response, ResultType, SerieResponse, VectorOrStream};
+use generic_loki_client::{BufferBudget, Data, Direction, DroppedEntry, LabelResponse, LokiClient, LokiError, QueryType, Reservation, Response, ResponsePart, ResponseStream, ResultType, SerieResponse, Stats, TailResponse, TailStream, Values, VectorOrStream};
 use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use prost::Message;
 use log::{error, info};
 use prost_types::Timestamp;
-use prometheus_labels_parser::{parse_labels, parse_labels_into_map};
+use prometheus_labels_parser::parse_labels_into_map;
 
 pub mod grpc_loki_client {
     tonic::include_proto!("logproto");
 }
 
+pub mod grpc_health {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+mod channels;
+
+pub use channels::{ChannelCache, ChannelConfig};
+
+const DEFAULT_TIME_RANGE: i64 = 6 * 60 * 60 * 1_000_000_000;
+/// Tails start an hour ago by default, as in Loki
+const DEFAULT_TAIL_LOOKBACK: i64 = 60 * 60 * 1_000_000_000;
+pub const DEFAULT_INSTANT_QUERY_LOOKBACK: Duration = Duration::from_secs(30);
+/// Message of the status answered when the channel enforces the deadline of a request
+const TIMEOUT_EXPIRED: &str = "Timeout expired";
+
+#[derive(Clone)]
 pub struct GrpcLokiClient {
     url: String,
+    /// Window before the evaluation time searched by instant log queries
+    instant_query_lookback: Duration,
+    channels: ChannelCache,
+    budget: BufferBudget,
 }
 
 impl GrpcLokiClient {
-    pub fn new(url: String) -> Self {
-        GrpcLokiClient { url }
+    pub fn new(url: String, instant_query_lookback: Duration, channels: ChannelCache, budget: BufferBudget) -> Self {
+        GrpcLokiClient { url, instant_query_lookback, channels, budget }
+    }
+
+    fn client(&self) -> Result<grpc_loki_client::querier_client::QuerierClient<tonic::transport::Channel>, LokiError> {
+        Ok(grpc_loki_client::querier_client::QuerierClient::new(self.channels.get(&self.url)?))
+    }
+
+    /// Requests carry their deadline in the grpc-timeout header, it is enforced by the channel and by the backend
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        if let Some(request_timeout) = self.channels.config().request_timeout {
+            request.set_timeout(request_timeout);
+        }
+        request
+    }
+
+    fn rpc_error(&self, status: tonic::Status) -> LokiError {
+        error!("Error while sending the request to {}: {}", self.url, status);
+        let backend = self.url.clone();
+        let message = status.message().to_string();
+        match status.code() {
+            tonic::Code::InvalidArgument => LokiError::BadRequest { backend, message },
+            tonic::Code::Unauthenticated | tonic::Code::PermissionDenied => LokiError::Unauthorized { backend, message },
+            tonic::Code::ResourceExhausted => LokiError::TooManyRequests { backend, message, retry_after: None },
+            tonic::Code::DeadlineExceeded => LokiError::Timeout { backend, message },
+            tonic::Code::Cancelled if message == TIMEOUT_EXPIRED => LokiError::Timeout { backend, message },
+            tonic::Code::Cancelled => LokiError::Canceled { backend, message },
+            tonic::Code::Unavailable => {
+                self.channels.invalidate(&self.url);
+                LokiError::Unavailable { backend, message, retry_after: None }
+            }
+            tonic::Code::Internal | tonic::Code::Unknown | tonic::Code::DataLoss => LokiError::Internal { backend, message },
+            _ => LokiError::Other(anyhow!("{} answered with {}", backend, status)),
+        }
+    }
+
+    //tonic doesn't bound the size of the decoded messages, oversized responses are rejected once received.
+    //Messages are accounted in the budget of the query as they are received, until the reservation is dropped.
+    fn check_message_size<M: Message>(&self, message: &M) -> Result<Reservation, LokiError> {
+        let size = message.encoded_len();
+        let max_message_size = self.channels.config().max_message_size;
+        if size > max_message_size {
+            return Err(decode_error(&self.url, format!("Response message of {} bytes exceeds the max message size of {} bytes", size, max_message_size)));
+        }
+        self.budget.reserve(&self.url, size)
+    }
+
+    async fn query_streams(&self, request: grpc_loki_client::QueryRequest) -> Result<ResponseStream, LokiError> {
+        let started = Instant::now();
+        let mut client = self.client()?;
+        info!("Request, {:?}", request);
+        let limit = request.limit as usize;
+        let response = client.query(self.request(request)).await.map_err(|status| self.rpc_error(status))?;
+        info!("Response received, {:?}", response);
+        Ok(self.response_stream(response.into_inner(), (limit > 0).then_some(limit), started))
+    }
+
+    async fn query_samples(&self, request: grpc_loki_client::SampleQueryRequest) -> Result<ResponseStream, LokiError> {
+        let started = Instant::now();
+        let mut client = self.client()?;
+        info!("Request, {:?}", request);
+        let response = client.query_sample(self.request(request)).await.map_err(|status| self.rpc_error(status))?;
+        Ok(self.response_stream(response.into_inner(), None, started))
+    }
+
+    fn response_stream<M: QueryMessage>(&self, messages: tonic::Streaming<M>, limit: Option<usize>, started: Instant) -> ResponseStream {
+        let messages = ResponseMessages {
+            client: self.clone(),
+            messages: Some(messages),
+            parts: VecDeque::new(),
+            remaining: limit,
+            bytes_received: 0,
+            started,
+        };
+        Box::pin(stream::try_unfold(messages, ResponseMessages::next_part))
+    }
+
+    async fn label(&self, name: String, values: bool, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
+        let (start, end) = default_time_range(start, end);
+        let mut client = self.client()?;
+        let request = grpc_loki_client::LabelRequest {
+            name,
+            values,
+            start: Some(from_unix_nano_timestamp(start)),
+            end: Some(from_unix_nano_timestamp(end)),
+        };
+        info!("Request, {:?}", request);
+        let response = client.label(self.request(request)).await.map_err(|status| self.rpc_error(status))?.into_inner();
+        let _reservation = self.check_message_size(&response)?;
+        Ok(LabelResponse {
+            status: "success".to_string(),
+            data: Some(response.values),
+            warnings: vec![],
+        })
+    }
+
+}
+
+/// Messages of a query response being received. Streams and series can be split across several messages,
+/// their parts are joined by the consumer of the response.
+struct ResponseMessages<M> {
+    client: GrpcLokiClient,
+    /// None once the response ended
+    messages: Option<tonic::Streaming<M>>,
+    /// Parts decoded and not yet consumed
+    parts: VecDeque<ResponsePart>,
+    /// Entries still to be received, None without limit
+    remaining: Option<usize>,
+    bytes_received: usize,
+    started: Instant,
+}
+
+impl<M: QueryMessage> ResponseMessages<M> {
+    async fn next_part(mut self) -> Result<Option<(ResponsePart, Self)>, LokiError> {
+        loop {
+            if let Some(part) = self.parts.pop_front() {
+                return Ok(Some((part, self)));
+            }
+            let messages = match self.messages.as_mut() {
+                Some(messages) => messages,
+                None => return Ok(None),
+            };
+            //the rpc is canceled once the limit is reached
+            let message = match self.remaining {
+                Some(0) => None,
+                _ => messages.message().await.map_err(|status| self.client.rpc_error(status))?,
+            };
+            match message {
+                Some(message) => {
+                    let mut reservation = self.client.check_message_size(&message)?;
+                    self.bytes_received += reservation.bytes();
+                    for (result, size) in message.into_results(&self.client.url, &mut self.remaining)? {
+                        self.parts.push_back(ResponsePart::Result(result, reservation.split(size)));
+                    }
+                }
+                None => {
+                    self.messages = None;
+                    self.parts.push_back(ResponsePart::End(Box::new(Response {
+                        status: "success".to_string(),
+                        data: Data {
+                            result_type: M::RESULT_TYPE,
+                            result: vec![],
+                            stats: Some(Stats::backend(&self.client.url, self.started.elapsed(), self.bytes_received)),
+                        },
+                        warnings: vec![],
+                    })));
+                }
+            }
+        }
+    }
+}
+
+/// Messages of the rpcs answering queries
+trait QueryMessage: Message + Default + 'static {
+    const RESULT_TYPE: ResultType;
+
+    /// Results of the message with their encoded size, the entries are counted down from `remaining` when limited
+    fn into_results(self, backend: &str, remaining: &mut Option<usize>) -> Result<Vec<(VectorOrStream, usize)>, LokiError>;
+}
+
+impl QueryMessage for grpc_loki_client::QueryResponse {
+    const RESULT_TYPE: ResultType = ResultType::Streams;
+
+    fn into_results(self, backend: &str, remaining: &mut Option<usize>) -> Result<Vec<(VectorOrStream, usize)>, LokiError> {
+        let mut results = Vec::new();
+        for mut stream in self.streams {
+            let size = stream.encoded_len();
+            if let Some(remaining) = remaining.as_mut() {
+                if *remaining == 0 {
+                    break;
+                }
+                stream.entries.truncate(*remaining);
+                *remaining -= stream.entries.len();
+            }
+            results.push((stream_to_result(backend, stream)?, size));
+        }
+        Ok(results)
+    }
+}
+
+impl QueryMessage for grpc_loki_client::SampleQueryResponse {
+    const RESULT_TYPE: ResultType = ResultType::Matrix;
+
+    fn into_results(self, backend: &str, _remaining: &mut Option<usize>) -> Result<Vec<(VectorOrStream, usize)>, LokiError> {
+        self.series.into_iter().map(|serie| {
+            let size = serie.encoded_len();
+            Ok((serie_to_result(backend, serie)?, size))
+        }).collect()
     }
 }
 
 
 pub fn from_unix_nano_timestamp(timestamp: i64) -> Timestamp {
-    let ts = Timestamp {
+    Timestamp {
         seconds: timestamp / 1_000_000_000,
         nanos: (timestamp % 1_000_000_000) as i32,
-    };
-    ts
+    }
+}
+
+fn decode_error(backend: &str, message: String) -> LokiError {
+    LokiError::Decode { backend: backend.to_string(), message }
+}
+
+fn streams_to_result(backend: &str, streams: Vec<grpc_loki_client::StreamAdapter>) -> Result<Vec<VectorOrStream>, LokiError> {
+    streams.into_iter().map(|stream| stream_to_result(backend, stream)).collect()
+}
+
+fn stream_to_result(backend: &str, stream: grpc_loki_client::StreamAdapter) -> Result<VectorOrStream, LokiError> {
+    let mut vectors: Vec<(String, String)> = Vec::new();
+    for entry in stream.entries {
+        let timestamp = entry.timestamp.ok_or_else(|| decode_error(backend, "Timestamp is missing in the response".to_string()))?;
+        vectors.push((format_timestamp(&timestamp), entry.line));
+    }
+    let labels = parse_labels_into_map(stream.labels).map_err(|err| decode_error(backend, format!("Error while parsing labels: {}", err)))?;
+    Ok(VectorOrStream {
+        stream: Some(labels),
+        values: Some(Values::Streams(vectors)),
+        value: None,
+        metric: None,
+    })
+}
+
+fn serie_to_result(backend: &str, mut serie: grpc_loki_client::Series) -> Result<VectorOrStream, LokiError> {
+    let labels = parse_labels_into_map(serie.labels).map_err(|err| decode_error(backend, format!("Error while parsing labels: {}", err)))?;
+    serie.samples.sort_by_key(|sample| sample.timestamp);
+    Ok(VectorOrStream {
+        metric: Some(labels),
+        values: Some(Values::Matrix(serie.samples.into_iter().map(|sample| (from_unix_nano_to_seconds(sample.timestamp), format_sample_value(sample.value))).collect())),
+        value: None,
+        stream: None,
+    })
+}
+
+/// Timestamps are exposed as a string of nanoseconds, as Loki does
+fn format_timestamp(timestamp: &Timestamp) -> String {
+    timestamp.seconds.to_string() + &format!("{:0>9}", timestamp.nanos)
+}
+
+/// Ingesters send the entries of one stream per message, along with the streams they dropped
+fn tail_response(backend: &str, message: grpc_loki_client::TailResponse) -> Result<TailResponse, LokiError> {
+    let dropped_entries = message.dropped_streams.into_iter().map(|dropped| {
+        Ok(DroppedEntry {
+            labels: parse_labels_into_map(dropped.labels).map_err(|err| decode_error(backend, format!("Error while parsing labels: {}", err)))?,
+            timestamp: dropped.to.as_ref().map(format_timestamp).unwrap_or_default(),
+        })
+    }).collect::<Result<Vec<DroppedEntry>, LokiError>>()?;
+    Ok(TailResponse {
+        streams: streams_to_result(backend, message.stream.into_iter().collect())?,
+        dropped_entries,
+    })
+}
+
+/// Default to the last 6 hours as the Loki http api does, timestamps are in nanoseconds
+fn default_time_range(start: Option<i64>, end: Option<i64>) -> (i64, i64) {
+    let end = end.unwrap_or_else(now);
+    (start.unwrap_or(end - DEFAULT_TIME_RANGE), end)
+}
+
+/// Default to 100 entries as the Loki http api does, a negative limit is rejected rather than wrapped around
+fn grpc_limit(limit: Option<i32>) -> Result<u32, LokiError> {
+    let limit = limit.unwrap_or(100);
+    u32::try_from(limit).map_err(|_| LokiError::InvalidQuery(format!("limit must not be negative, got {}", limit)))
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_nanos() as i64)
+}
+
+/// Keep the latest sample of each serie at the evaluation time, as an instant query would
+fn matrix_to_vector(response: Response) -> Response {
+    let result = response.data.result.into_iter().filter_map(|serie| match serie.values {
+        Some(Values::Matrix(mut samples)) => samples.pop().map(|sample| VectorOrStream {
+            metric: serie.metric,
+            value: Some(sample),
+            stream: None,
+            values: None,
+        }),
+        _ => None,
+    }).collect();
+    Response {
+        status: response.status,
+        data: Data {
+            result_type: ResultType::Vector,
+            result,
+            stats: response.data.stats,
+        },
+        warnings: response.warnings,
+    }
+}
+
+/// Sample timestamps are exposed in seconds with a millisecond precision, as Loki does
+pub fn from_unix_nano_to_seconds(timestamp: i64) -> f64 {
+    (timestamp / 1_000_000) as f64 / 1000.0
+}
+
+fn format_sample_value(value: f64) -> String {
+    match value {
+        value if value == f64::INFINITY => "+Inf".to_string(),
+        value if value == f64::NEG_INFINITY => "-Inf".to_string(),
+        value if value.is_nan() => "NaN".to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn to_grpc_direction(direction: Option<Direction>) -> i32 {
+    match direction {
+        Some(Direction::Forward) => grpc_loki_client::Direction::Forward as i32,
+        Some(Direction::Backward) => grpc_loki_client::Direction::Backward as i32,
+        None => grpc_loki_client::Direction::Backward as i32,
+    }
 }
 
 #[async_trait]
 impl LokiClient for GrpcLokiClient {
-    //Query loki grpc api using tonic asynchronously
-    async fn query(&self, query: String, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<Response, LokiError> {
+    async fn query(&self, query: String, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<Response, LokiError> {
+        Ok(Response::from_stream(self.query_stream(query, query_type, limit, time, direction).await?).await?.0)
+    }
+
+    async fn query_range(&self, query: String, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<Response, LokiError> {
+        Ok(Response::from_stream(self.query_range_stream(query, query_type, start, end, limit, direction, step, interval).await?).await?.0)
+    }
+
+    //Query loki grpc api using tonic asynchronously.
+    //Log queries are served by the Query rpc while metric queries are served by the QuerySample rpc.
+    async fn query_stream(&self, query: String, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<ResponseStream, LokiError> {
         info!("Query: {}", query);
-        let client = grpc_loki_client::querier_client::QuerierClient::connect(self.url.clone()).await;
-        match client {
-            Ok(mut client) => {
-                let request = grpc_loki_client::QueryRequest {//QuerySampleRequest doesn't support limit and direction, we then have to use QueryRequest instead with a 30 seconds timeframe
-                    selector: query,
-                    limit: limit.unwrap_or(100) as u32,//Todo the actual parameter could be a u32
-                    start: if let Some(start) = time { Some(from_unix_nano_timestamp(start - 30_000_000_000)) } else { None },
-                    end: if let Some(end) = time { Some(from_unix_nano_timestamp(end)) } else { None },
-                    direction: match direction {
-                        Some(Direction::Forward) => grpc_loki_client::Direction::Forward as i32,
-                        Some(Direction::Backward) => grpc_loki_client::Direction::Backward as i32,
-                        None => grpc_loki_client::Direction::Backward as i32,
-                    },
-                    shards: ::prost::alloc::vec::Vec::new(),
-                };
-
-                info!("Request, {:?}", request);
-
-                let response: Result<tonic::Response<tonic::Streaming<grpc_loki_client::QueryResponse>>, tonic::Status> = client.query(request).await;
-
-                match response {
-                    Ok(response) => {
-                        info!("Response received, {:?}", response);
-                        let mut streaming_response: tonic::Streaming<grpc_loki_client::QueryResponse> = response.into_inner();
-                        let message_result = streaming_response.message().await;
-
-                        let result = match message_result {
-                            Ok(message_option) => {
-                                info!("Message option, {:?}", message_option);
-                                match message_option {
-                                    Some(message) => {
-                                        let streams: ::prost::alloc::vec::Vec<grpc_loki_client::StreamAdapter> = message.streams;
-                                        let mut result: Vec<VectorOrStream> = Vec::new();
-
-                                        for stream in streams {
-                                            let labels: ::prost::alloc::string::String = stream.labels;
-                                            let entries: ::prost::alloc::vec::Vec<grpc_loki_client::EntryAdapter> = stream.entries;
-                                            let mut vectors: Vec<(String, String)> = Vec::new();
-
-                                            for entry in entries {
-                                                let timestamp: Option<Timestamp> = entry.timestamp;
-                                                let line: ::prost::alloc::string::String = entry.line;
-
-                                                if timestamp.is_none() {
-                                                    return Err(LokiError::Other(anyhow!("Timestamp is missing in the response")));
-                                                }
-
-                                                let nanos = format!("{:0>9}", &timestamp.as_ref().unwrap().nanos);
-
-                                                vectors.push((timestamp.unwrap().seconds.to_string() + &nanos, line));
-                                            }
-
-                                            let parse_label_result = parse_labels_into_map(labels.to_string());
-
-                                            match parse_label_result {
-                                                Ok(labels) => {
-                                                    result.push(VectorOrStream {
-                                                        stream: Some(labels),
-                                                        values: Some(vectors),
-                                                        value: None,
-                                                        metric: None,
-                                                    })
-                                                },
-                                                Err(err) => {
-                                                    return Err(LokiError::Other(anyhow!("Error while parsing labels: {}", err)));
-                                                }
-                                            }
-                                        }
-                                        Ok(result)
-                                    }
-                                    None => {
-                                        return Err(LokiError::Other(anyhow!("No message in the response")));
-                                    }
-                                }
-                            }
-                            Err(error) => {
-                                return Err(LokiError::Other(anyhow!("Error while receiving the response: {}", error)));
-                            }
-                        };
-
-                        if let Err(error) = result {
-                            return error;
-                        }
-
-                        Ok(Response {
-                            status: "success".to_string(),
-                            data: Data {
-                                result_type: ResultType::Streams,
-                                result: result.unwrap(),
-                            },
-                        })
-                    }
-                    Err(err) => Err(LokiError::Other(anyhow!(err))),
-                }
-            }
-            Err(e) => {
-                error!("Error while sending the request: {}", e);
-                Err(LokiError::Other(anyhow!("Error while sending the request: {}", e)))
-            },
+        let time = time.unwrap_or_else(now);
+        if query_type == QueryType::Metric {
+            let samples = self.query_samples(grpc_loki_client::SampleQueryRequest {
+                selector: query,
+                start: Some(from_unix_nano_timestamp(time)),
+                end: Some(from_unix_nano_timestamp(time)),
+                shards: Vec::new(),
+            }).await?;
+            //the latest sample of a serie is known once the whole serie is received
+            let (response, _reservation) = Response::from_stream(samples).await?;
+            return Ok(matrix_to_vector(response).into_stream());
         }
+        //QueryRequest has no evaluation time, log lines are searched in the lookback window preceding it
+        self.query_streams(grpc_loki_client::QueryRequest {
+            selector: query,
+            limit: grpc_limit(limit)?,
+            start: Some(from_unix_nano_timestamp(time - self.instant_query_lookback.as_nanos() as i64)),
+            end: Some(from_unix_nano_timestamp(time)),
+            direction: to_grpc_direction(direction),
+            shards: Vec::new(),
+        }).await
     }
 
-    async fn query_range(&self, query: String, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<Response, LokiError> {
-        Err(LokiError::NotImplemented)
+    //Samples are returned as computed by the backend, step and interval are not supported by the grpc api
+    async fn query_range_stream(&self, query: String, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, _step: Option<String>, _interval: Option<String>) -> Result<ResponseStream, LokiError> {
+        info!("Query range: {}", query);
+        if query_type == QueryType::Metric {
+            return self.query_samples(grpc_loki_client::SampleQueryRequest {
+                selector: query,
+                start: Some(from_unix_nano_timestamp(start)),
+                end: Some(from_unix_nano_timestamp(end)),
+                shards: Vec::new(),
+            }).await;
+        }
+        self.query_streams(grpc_loki_client::QueryRequest {
+            selector: query,
+            limit: grpc_limit(limit)?,
+            start: Some(from_unix_nano_timestamp(start)),
+            end: Some(from_unix_nano_timestamp(end)),
+            direction: to_grpc_direction(direction),
+            shards: Vec::new(),
+        }).await
     }
     async fn labels(&self, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
-        Err(LokiError::NotImplemented)
+        self.label(String::new(), false, start, end).await
     }
     async fn label_values(&self, label: String, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
-        Err(LokiError::NotImplemented)
+        self.label(label, true, start, end).await
     }
     async fn series(&self, matches: Option<Vec<String>>, start: Option<i64>, end: Option<i64>) -> Result<SerieResponse, LokiError> {
-        Err(LokiError::NotImplemented)
+        let (start, end) = default_time_range(start, end);
+        let mut client = self.client()?;
+        let request = grpc_loki_client::SeriesRequest {
+            start: Some(from_unix_nano_timestamp(start)),
+            end: Some(from_unix_nano_timestamp(end)),
+            groups: matches.unwrap_or_default(),
+            shards: Vec::new(),
+        };
+        info!("Request, {:?}", request);
+        let response = client.series(self.request(request)).await.map_err(|status| self.rpc_error(status))?.into_inner();
+        let _reservation = self.check_message_size(&response)?;
+        Ok(SerieResponse {
+            status: "success".to_string(),
+            data: response.series.into_iter().map(|serie| serie.labels.into_iter().collect()).collect(),
+            warnings: vec![],
+        })
+    }
+    async fn tail(&self, query: String, delay_for: Option<u32>, limit: Option<i32>, start: Option<i64>) -> Result<TailStream, LokiError> {
+        let mut client = self.client()?;
+        let request = grpc_loki_client::TailRequest {
+            query,
+            delay_for: delay_for.unwrap_or(0),
+            limit: grpc_limit(limit)?,
+            start: Some(from_unix_nano_timestamp(start.unwrap_or_else(|| now() - DEFAULT_TAIL_LOOKBACK))),
+        };
+        info!("Request, {:?}", request);
+        let response = client.tail(request).await.map_err(|status| self.rpc_error(status))?;
+        //the stream outlives the request, it keeps its own client to report errors
+        let this = self.clone();
+        Ok(Box::pin(response.into_inner().map(move |message| {
+            message.map_err(|status| this.rpc_error(status)).and_then(|message| tail_response(&this.url, message))
+        })))
+    }
+
+    //Backends are probed through the grpc health checking service
+    async fn ready(&self) -> Result<(), LokiError> {
+        let mut client = grpc_health::health_client::HealthClient::new(self.channels.get(&self.url)?);
+        let response = client.check(self.request(grpc_health::HealthCheckRequest { service: String::new() })).await
+            .map_err(|status| self.rpc_error(status))?.into_inner();
+        match response.status() {
+            grpc_health::health_check_response::ServingStatus::Serving => Ok(()),
+            status => Err(LokiError::Unavailable { backend: self.url.clone(), message: format!("health status is {:?}", status), retry_after: None }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn stream(labels: &str, lines: &[&str]) -> grpc_loki_client::StreamAdapter {
+        grpc_loki_client::StreamAdapter {
+            labels: labels.to_string(),
+            entries: lines.iter().enumerate().map(|(index, line)| grpc_loki_client::EntryAdapter {
+                timestamp: Some(from_unix_nano_timestamp(index as i64)),
+                line: line.to_string(),
+            }).collect(),
+        }
+    }
+
+    fn lines(results: &[(VectorOrStream, usize)]) -> Vec<(&str, usize)> {
+        results.iter().map(|(result, _)| match &result.values {
+            Some(Values::Streams(values)) => (result.stream.as_ref().unwrap()["app"].as_str(), values.len()),
+            _ => unreachable!(),
+        }).collect()
+    }
+
+    #[test]
+    fn it_should_keep_the_streams_of_messages_with_their_size() {
+        let message = grpc_loki_client::QueryResponse { streams: vec![stream("{app=\"a\"}", &["1", "2"]), stream("{app=\"b\"}", &["3"])] };
+        let sizes: Vec<usize> = message.streams.iter().map(Message::encoded_len).collect();
+        let results = message.into_results("http://localhost:9096", &mut None).unwrap();
+        assert_eq!(lines(&results), vec![("a", 2), ("b", 1)]);
+        assert_eq!(results.iter().map(|(_, size)| *size).collect::<Vec<usize>>(), sizes);
+    }
+
+    #[test]
+    fn it_should_stop_receiving_at_the_limit() {
+        let mut remaining = Some(3);
+        let results = grpc_loki_client::QueryResponse { streams: vec![stream("{app=\"a\"}", &["1", "2"])] }.into_results("http://localhost:9096", &mut remaining).unwrap();
+        assert_eq!(lines(&results), vec![("a", 2)]);
+        let results = grpc_loki_client::QueryResponse { streams: vec![stream("{app=\"b\"}", &["3", "4"]), stream("{app=\"c\"}", &["5"])] }.into_results("http://localhost:9096", &mut remaining).unwrap();
+        assert_eq!(lines(&results), vec![("b", 1)]);
+        assert_eq!(remaining, Some(0));
+    }
+
+    #[test]
+    fn it_should_sort_the_samples_of_series() {
+        let results = grpc_loki_client::SampleQueryResponse { series: vec![grpc_loki_client::Series {
+            labels: "{app=\"a\"}".to_string(),
+            samples: vec![
+                grpc_loki_client::Sample { timestamp: 2_000_000_000, value: 2.0, hash: 0 },
+                grpc_loki_client::Sample { timestamp: 1_000_000_000, value: 1.0, hash: 0 },
+            ],
+        }] }.into_results("http://localhost:9096", &mut None).unwrap();
+        assert_eq!(results[0].0.values, Some(Values::Matrix(vec![(1.0, "1".to_string()), (2.0, "2".to_string())])));
+    }
+
+    #[test]
+    fn it_should_return_an_empty_result_for_an_empty_stream() {
+        assert!(streams_to_result("http://localhost:9096", vec![]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_should_keep_the_latest_sample_of_instant_queries() {
+        let response = matrix_to_vector(Response {
+            status: "success".to_string(),
+            data: Data {
+                result_type: ResultType::Matrix,
+                result: vec![
+                    VectorOrStream { metric: Some(HashMap::new()), value: None, stream: None, values: Some(Values::Matrix(vec![(1.0, "1".to_string()), (2.0, "2".to_string())])) },
+                    VectorOrStream { metric: Some(HashMap::new()), value: None, stream: None, values: Some(Values::Matrix(vec![])) },
+                ],
+                stats: None,
+            },
+            warnings: vec![],
+        });
+        assert_eq!(response.data.result_type, ResultType::Vector);
+        assert_eq!(response.data.result.len(), 1);
+        assert_eq!(response.data.result[0].value, Some((2.0, "2".to_string())));
+    }
+
+    #[test]
+    fn it_should_convert_tail_responses() {
+        let response = tail_response("http://localhost:9096", grpc_loki_client::TailResponse {
+            stream: Some(stream("{app=\"a\"}", &["1"])),
+            dropped_streams: vec![grpc_loki_client::DroppedStream {
+                from: Some(from_unix_nano_timestamp(1_000_000_001)),
+                to: Some(from_unix_nano_timestamp(2_000_000_002)),
+                labels: "{app=\"b\"}".to_string(),
+            }],
+        }).unwrap();
+        assert_eq!(response.streams[0].stream, Some(HashMap::from([("app".to_string(), "a".to_string())])));
+        assert_eq!(response.dropped_entries, vec![DroppedEntry { labels: HashMap::from([("app".to_string(), "b".to_string())]), timestamp: "2000000002".to_string() }]);
+    }
+
+    #[test]
+    fn it_should_convert_entries_timestamps() {
+        let result = streams_to_result("http://localhost:9096", vec![grpc_loki_client::StreamAdapter {
+            labels: "{app=\"a\"}".to_string(),
+            entries: vec![grpc_loki_client::EntryAdapter { timestamp: Some(from_unix_nano_timestamp(1_588_889_221_000_000_123)), line: "line".to_string() }],
+        }]).unwrap();
+        assert_eq!(result[0].values, Some(Values::Streams(vec![("1588889221000000123".to_string(), "line".to_string())])));
+    }
+
+    #[test]
+    fn it_should_reject_negative_limits() {
+        assert_eq!(grpc_limit(None).unwrap(), 100);
+        assert_eq!(grpc_limit(Some(5000)).unwrap(), 5000);
+        assert_eq!(grpc_limit(Some(-1)).unwrap_err().status_code(), 400);
+    }
+
+    #[test]
+    fn it_should_report_expired_deadlines_as_timeouts() {
+        let client = GrpcLokiClient::new("http://localhost:9096".to_string(), DEFAULT_INSTANT_QUERY_LOOKBACK, ChannelCache::default(), BufferBudget::unlimited());
+        assert_eq!(client.request(()).metadata().get("grpc-timeout").unwrap(), "60000000u");
+        assert_eq!(client.rpc_error(tonic::Status::cancelled(TIMEOUT_EXPIRED)).status_code(), 504);
+        assert_eq!(client.rpc_error(tonic::Status::cancelled("canceled by the client")).error_type(), "canceled");
     }
 }
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::anyhow;
+use generic_loki_client::LokiError;
+use log::info;
+
+/// Connection pool settings and timeouts of the http clients
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub connect_timeout: Duration,
+    /// Timeout of a whole request, body included, none when None
+    pub request_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle connection is kept in the pool, forever when None
+    pub pool_idle_timeout: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    /// Talk http2 to the backend without negotiating it first
+    pub http2_prior_knowledge: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Some(Duration::from_secs(60)),
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            http2_prior_knowledge: false,
+        }
+    }
+}
+
+/// Long-lived clients, one per backend url, keeping their connection pool, DNS cache and TLS sessions across requests
+#[derive(Debug, Clone, Default)]
+pub struct ClientCache {
+    config: ClientConfig,
+    clients: Arc<Mutex<HashMap<String, reqwest::Client>>>,
+}
+
+impl ClientCache {
+    pub fn new(config: ClientConfig) -> Self {
+        ClientCache { config, clients: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn get(&self, url: &str) -> Result<reqwest::Client, LokiError> {
+        let mut clients = self.clients.lock().map_err(|e| LokiError::Other(anyhow!("Client cache is poisoned: {}", e)))?;
+        if let Some(client) = clients.get(url) {
+            return Ok(client.clone());
+        }
+        info!("Creating an http client for {}", url);
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.config.connect_timeout)
+            .pool_max_idle_per_host(self.config.pool_max_idle_per_host)
+            .pool_idle_timeout(self.config.pool_idle_timeout)
+            .tcp_keepalive(self.config.tcp_keepalive);
+        if self.config.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(request_timeout) = self.config.request_timeout {
+            builder = builder.timeout(request_timeout);
+        }
+        let client = builder.build().map_err(|e| LokiError::Other(anyhow!("Unable to create an http client for {}: {}", url, e)))?;
+        clients.insert(url.to_string(), client.clone());
+        Ok(client)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.clients.lock().map_or(0, |clients| clients.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_reuse_clients_of_a_backend() {
+        let cache = ClientCache::new(ClientConfig::default());
+        cache.get("http://localhost:3100").unwrap();
+        cache.clone().get("http://localhost:3100").unwrap();
+        cache.get("http://localhost:3101").unwrap();
+        assert_eq!(cache.len(), 2);
+    }
+}
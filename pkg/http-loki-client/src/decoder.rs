@@ -0,0 +1,200 @@
+use generic_loki_client::{Response, VectorOrStream};
+
+/// Object or array enclosing the bytes being decoded
+struct Container {
+    is_object: bool,
+    /// Last key read in an object
+    key: Vec<u8>,
+    expects_key: bool,
+}
+
+/// Incremental decoder of the body of a query response (`{"status":...,"data":{"resultType":...,"result":[...]}}`).
+/// The elements of `data.result` are decoded as soon as they are received, while the rest of the response
+/// is kept aside and decoded once the body ended. JSON is only scanned for its structure, elements are decoded by serde.
+pub(crate) struct ResultsDecoder {
+    containers: Vec<Container>,
+    in_string: bool,
+    escaped: bool,
+    /// Depth of the `data.result` array while it is being received
+    results_depth: Option<usize>,
+    results_received: bool,
+    /// Bytes of the element of `data.result` being received
+    element: Vec<u8>,
+    /// Bytes of the response without the elements of `data.result`
+    envelope: Vec<u8>,
+    bytes: usize,
+}
+
+impl ResultsDecoder {
+    pub(crate) fn new() -> Self {
+        ResultsDecoder {
+            containers: Vec::new(),
+            in_string: false,
+            escaped: false,
+            results_depth: None,
+            results_received: false,
+            element: Vec::new(),
+            envelope: Vec::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Bytes of the body received so far
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Decode a chunk of the body, the elements of the result completed by the chunk are returned
+    /// with the number of bytes they were received in
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Result<Vec<(VectorOrStream, usize)>, serde_json::Error> {
+        self.bytes += chunk.len();
+        let mut elements = Vec::new();
+        for &byte in chunk {
+            if self.in_string {
+                self.read_string(byte);
+                continue;
+            }
+            if self.results_depth == Some(self.containers.len()) {
+                //between the elements of the result, or at the start of an element
+                match byte {
+                    b',' | b']' => {
+                        if !self.element.is_empty() {
+                            let size = self.element.len();
+                            elements.push((serde_json::from_slice(&self.element)?, size));
+                            self.element.clear();
+                        }
+                        if byte == b']' {
+                            self.results_depth = None;
+                            self.results_received = true;
+                            self.close_container(byte);
+                        }
+                        continue;
+                    }
+                    byte if byte.is_ascii_whitespace() => continue,
+                    _ => {}
+                }
+            }
+            match byte {
+                b'"' => {
+                    self.in_string = true;
+                    if let Some(container) = self.containers.last_mut().filter(|container| container.is_object && container.expects_key) {
+                        container.key.clear();
+                    }
+                    self.output(byte);
+                }
+                b'{' | b'[' => {
+                    self.output(byte);
+                    let is_results = byte == b'[' && !self.results_received && self.is_in_data_result();
+                    self.containers.push(Container { is_object: byte == b'{', key: Vec::new(), expects_key: byte == b'{' });
+                    if is_results {
+                        self.results_depth = Some(self.containers.len());
+                    }
+                }
+                b'}' | b']' => self.close_container(byte),
+                b':' => {
+                    if let Some(container) = self.containers.last_mut() {
+                        container.expects_key = false;
+                    }
+                    self.output(byte);
+                }
+                b',' => {
+                    if let Some(container) = self.containers.last_mut() {
+                        container.expects_key = container.is_object;
+                    }
+                    self.output(byte);
+                }
+                _ => self.output(byte),
+            }
+        }
+        Ok(elements)
+    }
+
+    /// Decode the rest of the response once the body ended, its result is empty
+    pub(crate) fn finish(self) -> Result<Response, serde_json::Error> {
+        serde_json::from_slice(&self.envelope)
+    }
+
+    fn read_string(&mut self, byte: u8) {
+        self.output(byte);
+        if self.escaped {
+            self.escaped = false;
+        } else if byte == b'\\' {
+            self.escaped = true;
+        } else if byte == b'"' {
+            self.in_string = false;
+            return;
+        }
+        //keys are kept to find the result, they are short
+        if let Some(container) = self.containers.last_mut().filter(|container| container.is_object && container.expects_key) {
+            container.key.push(byte);
+        }
+    }
+
+    fn close_container(&mut self, byte: u8) {
+        self.containers.pop();
+        self.output(byte);
+    }
+
+    /// The array being opened is the value of `data.result`
+    fn is_in_data_result(&self) -> bool {
+        match self.containers.as_slice() {
+            [response, data] => response.key == b"data" && data.key == b"result" && data.is_object && !data.expects_key,
+            _ => false,
+        }
+    }
+
+    fn output(&mut self, byte: u8) {
+        match self.results_depth {
+            Some(depth) if self.containers.len() >= depth => self.element.push(byte),
+            _ => self.envelope.push(byte),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generic_loki_client::{ResultType, Values};
+
+    const RESPONSE: &str = r#"{"status":"success","data":{"resultType":"streams","result":[ {"stream":{"app":"x"},"values":[["2","b]},\"{["],["1","a"]]} , {"stream":{"app":"y"},"values":[["3","c"]]}],"stats":{"summary":{"totalBytesProcessed":10}}},"warnings":["result: [\"]"]}"#;
+
+    fn decode(chunk_size: usize) -> (Vec<(VectorOrStream, usize)>, Response) {
+        let mut decoder = ResultsDecoder::new();
+        let elements = RESPONSE.as_bytes().chunks(chunk_size)
+            .map(|chunk| decoder.feed(chunk).unwrap())
+            .collect::<Vec<Vec<(VectorOrStream, usize)>>>()
+            .concat();
+        assert_eq!(decoder.bytes(), RESPONSE.len());
+        (elements, decoder.finish().unwrap())
+    }
+
+    #[test]
+    fn it_should_decode_the_results_as_they_are_received() {
+        for chunk_size in [1, 2, 3, 7, 16, RESPONSE.len()] {
+            let (elements, response) = decode(chunk_size);
+            assert_eq!(elements.len(), 2);
+            assert_eq!(elements[0].0.values, Some(Values::Streams(vec![("2".to_string(), "b]},\"{[".to_string()), ("1".to_string(), "a".to_string())])));
+            assert_eq!(elements[0].1, r#"{"stream":{"app":"x"},"values":[["2","b]},\"{["],["1","a"]]}"#.len());
+            assert_eq!(elements[1].0.stream.as_ref().and_then(|labels| labels.get("app")), Some(&"y".to_string()));
+            assert_eq!(response.data.result_type, ResultType::Streams);
+            assert!(response.data.result.is_empty());
+            assert_eq!(response.data.stats.unwrap().summary.total_bytes_processed, 10);
+            assert_eq!(response.warnings, vec!["result: [\"]".to_string()]);
+        }
+    }
+
+    #[test]
+    fn it_should_decode_empty_results() {
+        let mut decoder = ResultsDecoder::new();
+        assert!(decoder.feed(br#"{"data":{"result":[],"resultType":"matrix"},"status":"success"}"#).unwrap().is_empty());
+        assert_eq!(decoder.finish().unwrap().data.result_type, ResultType::Matrix);
+    }
+
+    #[test]
+    fn it_should_fail_on_truncated_bodies() {
+        let mut decoder = ResultsDecoder::new();
+        decoder.feed(&RESPONSE.as_bytes()[..100]).unwrap();
+        assert!(decoder.finish().is_err());
+        assert!(ResultsDecoder::new().feed(br#"{"data":{"result":[{"stream":}]}}"#).is_err());
+    }
+}
@@ -1,44 +1,217 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime};
 use anyhow::anyhow;
-use generic_loki_client::{Direction, LabelResponse, LokiClient, LokiError, Response, SerieResponse};
+use generic_loki_client::{BufferBudget, Direction, ErrorResponse, LabelResponse, LokiClient, LokiError, QueryType, Reservation, Response, ResponsePart, ResponseStream, SerieResponse, Stats, TailResponse, TailStream};
 use async_trait::async_trait;
+use futures::{future, stream, StreamExt};
 use serde::de;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+mod clients;
+mod decoder;
+
+pub use clients::{ClientCache, ClientConfig};
+use decoder::ResultsDecoder;
 
 pub struct HttpLokiClient {
     url: String,
+    client: reqwest::Client,
+    budget: BufferBudget,
 }
 
 impl HttpLokiClient {
-    pub fn new(url: String) -> Self {
-        HttpLokiClient { url }
+    pub fn new(url: String, client: reqwest::Client, budget: BufferBudget) -> Self {
+        HttpLokiClient { url, client, budget }
     }
 
-    async fn parse_result<T: de::DeserializeOwned>(result: Result<reqwest::Response, LokiError>) -> Result<T, LokiError> {
-        if let Ok(result) = result {
-            let body = result.text().await.or_else(|e| {
-                Err(LokiError::Other(anyhow!("{}", e)))
-            });
-            if let Ok(body) = body {
-                let response = serde_json::from_str(&body).or_else(|e| {
-                    Err(LokiError::Other(anyhow!("Failed to parse body {}", e)))
-                });
-                if let Ok(response) = response {
-                    Ok(response)
-                } else {
-                    Err(LokiError::Other(anyhow!("{}", body)))
+    //The body is read chunk by chunk so a response exceeding the budget of the query is dropped before being fully received
+    async fn read_chunks(&self, mut response: reqwest::Response) -> Result<(Vec<u8>, Reservation), LokiError> {
+        let mut reservation = self.budget.reservation();
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| transport_error(&self.url, e))? {
+            reservation.grow(&self.url, chunk.len())?;
+            body.extend_from_slice(&chunk);
+        }
+        Ok((body, reservation))
+    }
+
+    /// Responses answered with an error status are read whole to report the error of the backend
+    async fn check_status(&self, result: Result<reqwest::Response, reqwest::Error>) -> Result<reqwest::Response, LokiError> {
+        let response = result.map_err(|e| transport_error(&self.url, e))?;
+        let status = response.status();
+        if status.as_u16() < 400 {
+            return Ok(response);
+        }
+        let retry_after = retry_after(response.headers());
+        let (body, _) = self.read_chunks(response).await?;
+        //Loki answers errors with a plain text message or with an error envelope
+        let message = serde_json::from_slice::<ErrorResponse>(&body).map_or_else(|_| String::from_utf8_lossy(&body).trim().to_string(), |response| response.error);
+        let error = LokiError::from_status_code(&self.url, status.as_u16(), message)
+            .unwrap_or_else(|| LokiError::Other(anyhow!("{} answered with status {}", self.url, status)));
+        Err(error.with_retry_after(retry_after))
+    }
+
+    /// Body of a successful response, held in the budget of the query until the reservation is dropped
+    async fn read_body(&self, result: Result<reqwest::Response, reqwest::Error>) -> Result<(Vec<u8>, Reservation), LokiError> {
+        let response = self.check_status(result).await?;
+        self.read_chunks(response).await
+    }
+
+    fn decode<T: de::DeserializeOwned>(&self, body: &[u8]) -> Result<T, LokiError> {
+        serde_json::from_slice(body).map_err(|e| decode_error(&self.url, e))
+    }
+
+    async fn parse_result<T: de::DeserializeOwned>(&self, result: Result<reqwest::Response, reqwest::Error>) -> Result<T, LokiError> {
+        let (body, _reservation) = self.read_body(result).await?;
+        self.decode(&body)
+    }
+
+    /// The results of a query are decoded as the body is received, see [ResultsDecoder]
+    async fn stream_query_result(&self, result: Result<reqwest::Response, reqwest::Error>, started: Instant) -> Result<ResponseStream, LokiError> {
+        let response = self.check_status(result).await?;
+        let body = ResponseBody {
+            backend: self.url.clone(),
+            response: Some(response),
+            decoder: ResultsDecoder::new(),
+            reservation: self.budget.reservation(),
+            parts: VecDeque::new(),
+            started,
+        };
+        Ok(Box::pin(stream::try_unfold(body, ResponseBody::next_part)))
+    }
+}
+
+/// Body of a query response being received
+struct ResponseBody {
+    backend: String,
+    /// None once the body ended
+    response: Option<reqwest::Response>,
+    decoder: ResultsDecoder,
+    /// Bytes received and not yet decoded
+    reservation: Reservation,
+    /// Parts decoded and not yet consumed
+    parts: VecDeque<ResponsePart>,
+    started: Instant,
+}
+
+impl ResponseBody {
+    async fn next_part(mut self) -> Result<Option<(ResponsePart, Self)>, LokiError> {
+        loop {
+            if let Some(part) = self.parts.pop_front() {
+                return Ok(Some((part, self)));
+            }
+            let response = match self.response.as_mut() {
+                Some(response) => response,
+                None => return Ok(None),
+            };
+            match response.chunk().await.map_err(|e| transport_error(&self.backend, e))? {
+                Some(chunk) => {
+                    self.reservation.grow(&self.backend, chunk.len())?;
+                    for (result, size) in self.decoder.feed(&chunk).map_err(|e| decode_error(&self.backend, e))? {
+                        self.parts.push_back(ResponsePart::Result(result, self.reservation.split(size)));
+                    }
+                }
+                //The cost of the request is added to the stats returned by Loki
+                None => {
+                    self.response = None;
+                    let bytes = self.decoder.bytes();
+                    let decoder = std::mem::replace(&mut self.decoder, ResultsDecoder::new());
+                    let mut response = decoder.finish().map_err(|e| decode_error(&self.backend, e))?;
+                    response.data.stats.get_or_insert_with(Stats::default).merge(&Stats::backend(&self.backend, self.started.elapsed(), bytes));
+                    self.parts.push_back(ResponsePart::End(Box::new(response)));
                 }
-            } else {
-                Err(body.unwrap_err())
             }
-        } else {
-            Err(LokiError::Other(anyhow!("Failed to query loki")))
         }
     }
 }
 
+fn transport_error(backend: &str, error: reqwest::Error) -> LokiError {
+    let backend = backend.to_string();
+    let message = error.to_string();
+    if error.is_timeout() {
+        LokiError::Timeout { backend, message }
+    } else if error.is_connect() || error.is_request() || is_connection_reset(&error) {
+        LokiError::Unavailable { backend, message, retry_after: None }
+    } else if error.is_decode() || error.is_body() {
+        LokiError::Decode { backend, message }
+    } else {
+        LokiError::Other(anyhow!("Failed to query {}: {}", backend, message))
+    }
+}
+
+fn decode_error(backend: &str, error: serde_json::Error) -> LokiError {
+    LokiError::Decode {
+        backend: backend.to_string(),
+        message: format!("Failed to parse body {}", error),
+    }
+}
+
+/// Connection reset or closed by the backend while the response was received
+fn is_connection_reset(error: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            return matches!(error.kind(), std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::UnexpectedEof);
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Delay requested by the `Retry-After` header, in seconds or as an http date
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => Some(httpdate::parse_http_date(value).ok()?.duration_since(SystemTime::now()).unwrap_or_default()),
+    }
+}
+
+fn websocket_error(backend: &str, error: tungstenite::Error) -> LokiError {
+    match error {
+        tungstenite::Error::Http(response) => {
+            let message = response.body().as_deref().map_or_else(String::new, |body| {
+                serde_json::from_str::<ErrorResponse>(body).map_or_else(|_| body.trim().to_string(), |response| response.error)
+            });
+            LokiError::from_status_code(backend, response.status().as_u16(), message)
+                .unwrap_or_else(|| LokiError::Other(anyhow!("{} refused to tail with status {}", backend, response.status())))
+        }
+        tungstenite::Error::Io(error) => LokiError::Unavailable { backend: backend.to_string(), message: error.to_string(), retry_after: None },
+        error => LokiError::Other(anyhow!("Failed to tail {}: {}", backend, error)),
+    }
+}
+
+/// Tail response carried by a websocket message, None for control messages and for a normal closure
+fn tail_message(backend: &str, message: Result<tungstenite::Message, tungstenite::Error>) -> Option<Result<TailResponse, LokiError>> {
+    let decode = |body: &[u8]| serde_json::from_slice(body).map_err(|e| LokiError::Decode {
+        backend: backend.to_string(),
+        message: format!("Failed to parse tail response {}", e),
+    });
+    match message {
+        Ok(tungstenite::Message::Text(text)) => Some(decode(text.as_bytes())),
+        Ok(tungstenite::Message::Binary(body)) => Some(decode(&body)),
+        //Loki closes the websocket with the error which ended the tail
+        Ok(tungstenite::Message::Close(Some(frame))) if frame.code != CloseCode::Normal => {
+            Some(Err(LokiError::Other(anyhow!("{} closed the tail: {}", backend, frame.reason))))
+        }
+        Ok(_) | Err(tungstenite::Error::ConnectionClosed) => None,
+        Err(error) => Some(Err(websocket_error(backend, error))),
+    }
+}
+
 #[async_trait]
 impl LokiClient for HttpLokiClient {
-    //Query loki api using reqwest asynchronously
-    async fn query(&self, query: String, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<Response, LokiError> {
+    async fn query(&self, query: String, query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<Response, LokiError> {
+        Ok(Response::from_stream(self.query_stream(query, query_type, limit, time, direction).await?).await?.0)
+    }
+
+    async fn query_range(&self, query: String, query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<Response, LokiError> {
+        Ok(Response::from_stream(self.query_range_stream(query, query_type, start, end, limit, direction, step, interval).await?).await?.0)
+    }
+
+    //Query loki api using reqwest asynchronously, the endpoints serve both log and metric queries
+    async fn query_stream(&self, query: String, _query_type: QueryType, limit: Option<i32>, time: Option<i64>, direction: Option<Direction>) -> Result<ResponseStream, LokiError> {
         let mut url = self.url.clone();
         url.push_str("/loki/api/v1/query");
         let mut params = vec![("query", query)];
@@ -51,16 +224,15 @@ impl LokiClient for HttpLokiClient {
         if let Some(direction) = direction {
             params.push(("direction", match direction { Direction::Forward => "forward".to_string(), Direction::Backward => "backward".to_string() }));
         }
-        let client = reqwest::Client::new();
+        let client = &self.client;
 
-        let result = client.get(&url).query(&params).send().await.or_else(|e| {
-            Err(LokiError::Other(anyhow!("{}", e)))
-        });
+        let started = Instant::now();
+        let result = client.get(&url).query(&params).send().await;
 
-        Self::parse_result(result).await
+        self.stream_query_result(result, started).await
     }
 
-    async fn query_range(&self, query: String, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<Response, LokiError> {
+    async fn query_range_stream(&self, query: String, _query_type: QueryType, start: i64, end: i64, limit: Option<i32>, direction: Option<Direction>, step: Option<String>, interval: Option<String>) -> Result<ResponseStream, LokiError> {
         let mut url = self.url.clone();
         url.push_str("/loki/api/v1/query_range");
         let mut params = vec![("query", query), ("start", start.to_string()), ("end", end.to_string())];
@@ -76,13 +248,12 @@ impl LokiClient for HttpLokiClient {
         if let Some(interval) = interval {
             params.push(("interval", interval));
         }
-        let client = reqwest::Client::new();
+        let client = &self.client;
 
-        let result = client.get(&url).query(&params).send().await.or_else(|e| {
-            Err(LokiError::Other(anyhow!("{}", e)))
-        });
+        let started = Instant::now();
+        let result = client.get(&url).query(&params).send().await;
 
-        Self::parse_result(result).await
+        self.stream_query_result(result, started).await
     }
 
     async fn labels(&self, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
@@ -95,13 +266,11 @@ impl LokiClient for HttpLokiClient {
         if let Some(end) = end {
             params.push(("end", end.to_string()));
         }
-        let client = reqwest::Client::new();
+        let client = &self.client;
 
-        let result = client.get(&url).query(&params).send().await.or_else(|e| {
-            Err(LokiError::Other(anyhow!("{}", e)))
-        });
+        let result = client.get(&url).query(&params).send().await;
 
-        Self::parse_result(result).await
+        self.parse_result(result).await
     }
 
     async fn label_values(&self, label: String, start: Option<i64>, end: Option<i64>) -> Result<LabelResponse, LokiError> {
@@ -116,13 +285,11 @@ impl LokiClient for HttpLokiClient {
         if let Some(end) = end {
             params.push(("end", end.to_string()));
         }
-        let client = reqwest::Client::new();
+        let client = &self.client;
 
-        let result = client.get(&url).query(&params).send().await.or_else(|e| {
-            Err(LokiError::Other(anyhow!("{}", e)))
-        });
+        let result = client.get(&url).query(&params).send().await;
 
-        Self::parse_result(result).await
+        self.parse_result(result).await
     }
 
     async fn series(&self, matches: Option<Vec<String>>, start: Option<i64>, end: Option<i64>) -> Result<SerieResponse, LokiError> {
@@ -140,12 +307,71 @@ impl LokiClient for HttpLokiClient {
         if let Some(end) = end {
             params.push(("end", end.to_string()));
         }
-        let client = reqwest::Client::new();
+        let client = &self.client;
+
+        let result = client.post(&url).form(&params).send().await;
+
+        self.parse_result(result).await
+    }
+
+    //Loki sends the responses of a tail as text messages of a websocket
+    async fn tail(&self, query: String, delay_for: Option<u32>, limit: Option<i32>, start: Option<i64>) -> Result<TailStream, LokiError> {
+        let mut params = vec![("query", query)];
+        if let Some(delay_for) = delay_for {
+            params.push(("delay_for", delay_for.to_string()));
+        }
+        if let Some(limit) = limit {
+            params.push(("limit", limit.to_string()));
+        }
+        if let Some(start) = start {
+            params.push(("start", start.to_string()));
+        }
+        let mut url = reqwest::Url::parse_with_params(&format!("{}/loki/api/v1/tail", self.url), &params)
+            .map_err(|e| LokiError::Other(anyhow!("Invalid url {}: {}", self.url, e)))?;
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme).map_err(|_| LokiError::Other(anyhow!("Unable to tail {} over a websocket", self.url)))?;
 
-        let result = client.post(&url).form(&params).send().await.or_else(|e| {
-            Err(LokiError::Other(anyhow!("{}", e)))
-        });
+        let (socket, _) = tokio_tungstenite::connect_async(url).await.map_err(|e| websocket_error(&self.url, e))?;
+        let backend = self.url.clone();
+        Ok(Box::pin(socket.filter_map(move |message| future::ready(tail_message(&backend, message)))))
+    }
+
+    async fn ready(&self) -> Result<(), LokiError> {
+        let result = self.client.get(format!("{}/ready", self.url)).send().await;
+        self.read_body(result).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    #[test]
+    fn it_should_decode_tail_messages() {
+        let response = tail_message("http://loki:3100", Ok(tungstenite::Message::Text(r#"{"streams":[{"stream":{"app":"x"},"values":[["1","a"]]}],"dropped_entries":null}"#.to_string())));
+        assert_eq!(response.unwrap().unwrap().streams.len(), 1);
+        assert!(tail_message("http://loki:3100", Ok(tungstenite::Message::Ping(vec![]))).is_none());
+        assert!(tail_message("http://loki:3100", Ok(tungstenite::Message::Close(Some(CloseFrame { code: CloseCode::Normal, reason: Cow::from("") })))).is_none());
+
+        let error = tail_message("http://loki:3100", Ok(tungstenite::Message::Close(Some(CloseFrame { code: CloseCode::Error, reason: Cow::from("too many tailers") })))).unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "http://loki:3100 closed the tail: too many tailers");
+        let error = tail_message("http://loki:3100", Ok(tungstenite::Message::Text("{".to_string()))).unwrap().unwrap_err();
+        assert_eq!(error.status_code(), 502);
+    }
 
-        Self::parse_result(result).await
+    #[test]
+    fn it_should_parse_retry_after_headers() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(reqwest::header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(reqwest::header::RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+        headers.insert(reqwest::header::RETRY_AFTER, httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60)).parse().unwrap());
+        assert!(retry_after(&headers).unwrap() > Duration::from_secs(50));
+        headers.insert(reqwest::header::RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
     }
 }
\ No newline at end of file
@@ -0,0 +1,655 @@
+use std::fmt::{Display, Formatter, Result};
+
+/// A LogQL query, either returning log lines or samples
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Log(LogExpr),
+    Metric(MetricExpr),
+}
+
+impl Expr {
+    pub fn is_metric(&self) -> bool {
+        matches!(self, Expr::Metric(_))
+    }
+
+    /// Stream selectors of the query
+    pub fn selectors(&self) -> Vec<&Vec<LabelMatcher>> {
+        match self {
+            Expr::Log(log_expr) => vec![&log_expr.selector],
+            Expr::Metric(metric_expr) => metric_expr.selectors(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    RegexMatch,
+    RegexNotMatch,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelMatcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+}
+
+/// `{app="foo"} |= "error" | json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogExpr {
+    pub selector: Vec<LabelMatcher>,
+    pub pipeline: Vec<Stage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineFilterOp {
+    Contains,
+    NotContains,
+    Match,
+    NotMatch,
+    /// `|>`, the line matches a pattern
+    Pattern,
+    NotPattern,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineFilterValue {
+    String(String),
+    Ip(String),
+}
+
+/// Label extracted by the `json` and `logfmt` parsers, `first_server="servers[0]"`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelExtraction {
+    pub label: String,
+    pub expression: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelFormatValue {
+    /// `dst=src`
+    Rename(String),
+    /// `dst="{{.src}}"`
+    Template(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelFormatOperation {
+    pub label: String,
+    pub value: LabelFormatValue,
+}
+
+/// Label of a `drop` or `keep` stage, by name or when its value matches
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelSelection {
+    Name(String),
+    Matcher(LabelMatcher),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    /// `|= "a" or "b"`, the line matches any of the values
+    LineFilter { op: LineFilterOp, values: Vec<LineFilterValue> },
+    Json(Vec<LabelExtraction>),
+    /// `| logfmt --strict --keep-empty level, msg="message"`
+    Logfmt { strict: bool, keep_empty: bool, extractions: Vec<LabelExtraction> },
+    Regexp(String),
+    Pattern(String),
+    Unpack,
+    LabelFilter(LabelFilter),
+    LineFormat(String),
+    LabelFormat(Vec<LabelFormatOperation>),
+    Drop(Vec<LabelSelection>),
+    Keep(Vec<LabelSelection>),
+    Decolorize,
+    /// `| distinct app, level`, keeps the first line of each combination of the labels
+    Distinct(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComparisonOp {
+    Equal,
+    NotEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    LessThan,
+    LessThanOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComparisonValue {
+    Number(f64),
+    Duration(String),
+    Bytes(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelFilter {
+    Matcher(LabelMatcher),
+    Comparison { label: String, op: ComparisonOp, value: ComparisonValue },
+    Ip { label: String, negated: bool, cidr: String },
+    And(Box<LabelFilter>, Box<LabelFilter>),
+    Or(Box<LabelFilter>, Box<LabelFilter>),
+    Parens(Box<LabelFilter>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conversion {
+    Duration,
+    DurationSeconds,
+    Bytes,
+}
+
+/// `| unwrap duration(latency) | __error__=""`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unwrap {
+    pub label: String,
+    pub conversion: Option<Conversion>,
+    pub filters: Vec<LabelFilter>,
+}
+
+/// Log lines selected over a time range, `{app="foo"} | json | unwrap latency [5m] offset 1h`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRange {
+    pub selector: Vec<LabelMatcher>,
+    pub pipeline: Vec<Stage>,
+    pub unwrap: Option<Unwrap>,
+    pub range: String,
+    pub offset: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grouping {
+    pub without: bool,
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeOp {
+    CountOverTime,
+    Rate,
+    RateCounter,
+    BytesOverTime,
+    BytesRate,
+    AvgOverTime,
+    SumOverTime,
+    MinOverTime,
+    MaxOverTime,
+    StdvarOverTime,
+    StddevOverTime,
+    QuantileOverTime,
+    FirstOverTime,
+    LastOverTime,
+    AbsentOverTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VectorOp {
+    Sum,
+    Avg,
+    Count,
+    Min,
+    Max,
+    Stddev,
+    Stdvar,
+    Bottomk,
+    Topk,
+    ApproxTopk,
+    Sort,
+    SortDesc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Unless,
+    Equal,
+    NotEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    LessThan,
+    LessThanOrEqual,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorMatching {
+    pub ignoring: bool,
+    pub labels: Vec<String>,
+    /// `group_left` (false) or `group_right` (true) with the labels to include
+    pub group: Option<(bool, Vec<String>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryModifier {
+    pub return_bool: bool,
+    pub matching: Option<VectorMatching>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricExpr {
+    RangeAggregation {
+        op: RangeOp,
+        parameter: Option<f64>,
+        range: LogRange,
+        grouping: Option<Grouping>,
+    },
+    VectorAggregation {
+        op: VectorOp,
+        parameter: Option<f64>,
+        expr: Box<MetricExpr>,
+        grouping: Option<Grouping>,
+    },
+    Binary {
+        op: BinaryOp,
+        modifier: Option<BinaryModifier>,
+        lhs: Box<MetricExpr>,
+        rhs: Box<MetricExpr>,
+    },
+    LabelReplace {
+        expr: Box<MetricExpr>,
+        destination: String,
+        replacement: String,
+        source: String,
+        regex: String,
+    },
+    Literal(f64),
+    Vector(f64),
+    Parens(Box<MetricExpr>),
+}
+
+impl MetricExpr {
+    pub fn selectors(&self) -> Vec<&Vec<LabelMatcher>> {
+        match self {
+            MetricExpr::RangeAggregation { range, .. } => vec![&range.selector],
+            MetricExpr::VectorAggregation { expr, .. } | MetricExpr::LabelReplace { expr, .. } | MetricExpr::Parens(expr) => expr.selectors(),
+            MetricExpr::Binary { lhs, rhs, .. } => {
+                let mut selectors = lhs.selectors();
+                selectors.extend(rhs.selectors());
+                selectors
+            }
+            MetricExpr::Literal(_) | MetricExpr::Vector(_) => vec![],
+        }
+    }
+}
+
+/// Quote a string the way Go does, so that the printed query can be parsed by Loki
+pub fn quote(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn write_labels(f: &mut Formatter<'_>, labels: &[String]) -> Result {
+    write!(f, "({})", labels.join(", "))
+}
+
+fn write_selector(f: &mut Formatter<'_>, selector: &[LabelMatcher]) -> Result {
+    write!(f, "{{")?;
+    for (index, matcher) in selector.iter().enumerate() {
+        if index > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", matcher)?;
+    }
+    write!(f, "}}")
+}
+
+fn write_label_selections(f: &mut Formatter<'_>, selections: &[LabelSelection]) -> Result {
+    for (index, selection) in selections.iter().enumerate() {
+        write!(f, "{}", if index == 0 { " " } else { ", " })?;
+        match selection {
+            LabelSelection::Name(name) => write!(f, "{}", name)?,
+            LabelSelection::Matcher(matcher) => write!(f, "{}", matcher)?,
+        }
+    }
+    Ok(())
+}
+
+fn write_label_extractions(f: &mut Formatter<'_>, extractions: &[LabelExtraction]) -> Result {
+    for (index, extraction) in extractions.iter().enumerate() {
+        write!(f, "{}{}", if index == 0 { " " } else { ", " }, extraction.label)?;
+        if let Some(expression) = &extraction.expression {
+            write!(f, "={}", quote(expression))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_pipeline(f: &mut Formatter<'_>, pipeline: &[Stage]) -> Result {
+    for stage in pipeline {
+        write!(f, " {}", stage)?;
+    }
+    Ok(())
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Expr::Log(log_expr) => write!(f, "{}", log_expr),
+            Expr::Metric(metric_expr) => write!(f, "{}", metric_expr),
+        }
+    }
+}
+
+impl Display for MatchOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", match self {
+            MatchOp::Equal => "=",
+            MatchOp::NotEqual => "!=",
+            MatchOp::RegexMatch => "=~",
+            MatchOp::RegexNotMatch => "!~",
+        })
+    }
+}
+
+impl Display for LabelMatcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}{}{}", self.name, self.op, quote(&self.value))
+    }
+}
+
+impl Display for LogExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write_selector(f, &self.selector)?;
+        write_pipeline(f, &self.pipeline)
+    }
+}
+
+impl Display for LineFilterOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", match self {
+            LineFilterOp::Contains => "|=",
+            LineFilterOp::NotContains => "!=",
+            LineFilterOp::Match => "|~",
+            LineFilterOp::NotMatch => "!~",
+            LineFilterOp::Pattern => "|>",
+            LineFilterOp::NotPattern => "!>",
+        })
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Stage::LineFilter { op, values } => {
+                write!(f, "{}", op)?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " or")?;
+                    }
+                    match value {
+                        LineFilterValue::String(value) => write!(f, " {}", quote(value))?,
+                        LineFilterValue::Ip(cidr) => write!(f, " ip({})", quote(cidr))?,
+                    }
+                }
+                Ok(())
+            }
+            Stage::Json(extractions) => {
+                write!(f, "| json")?;
+                write_label_extractions(f, extractions)
+            }
+            Stage::Logfmt { strict, keep_empty, extractions } => {
+                write!(f, "| logfmt")?;
+                if *strict {
+                    write!(f, " --strict")?;
+                }
+                if *keep_empty {
+                    write!(f, " --keep-empty")?;
+                }
+                write_label_extractions(f, extractions)
+            }
+            Stage::Regexp(regexp) => write!(f, "| regexp {}", quote(regexp)),
+            Stage::Pattern(pattern) => write!(f, "| pattern {}", quote(pattern)),
+            Stage::Unpack => write!(f, "| unpack"),
+            Stage::LabelFilter(label_filter) => write!(f, "| {}", label_filter),
+            Stage::LineFormat(template) => write!(f, "| line_format {}", quote(template)),
+            Stage::LabelFormat(operations) => {
+                write!(f, "| label_format")?;
+                for (index, operation) in operations.iter().enumerate() {
+                    write!(f, "{}{}=", if index == 0 { " " } else { ", " }, operation.label)?;
+                    match &operation.value {
+                        LabelFormatValue::Rename(label) => write!(f, "{}", label)?,
+                        LabelFormatValue::Template(template) => write!(f, "{}", quote(template))?,
+                    }
+                }
+                Ok(())
+            }
+            Stage::Drop(selections) => {
+                write!(f, "| drop")?;
+                write_label_selections(f, selections)
+            }
+            Stage::Keep(selections) => {
+                write!(f, "| keep")?;
+                write_label_selections(f, selections)
+            }
+            Stage::Decolorize => write!(f, "| decolorize"),
+            Stage::Distinct(labels) => write!(f, "| distinct {}", labels.join(", ")),
+        }
+    }
+}
+
+impl Display for ComparisonOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", match self {
+            ComparisonOp::Equal => "==",
+            ComparisonOp::NotEqual => "!=",
+            ComparisonOp::GreaterThan => ">",
+            ComparisonOp::GreaterThanOrEqual => ">=",
+            ComparisonOp::LessThan => "<",
+            ComparisonOp::LessThanOrEqual => "<=",
+        })
+    }
+}
+
+impl Display for ComparisonValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            ComparisonValue::Number(number) => write!(f, "{}", number),
+            ComparisonValue::Duration(duration) => write!(f, "{}", duration),
+            ComparisonValue::Bytes(bytes) => write!(f, "{}", bytes),
+        }
+    }
+}
+
+impl Display for LabelFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            LabelFilter::Matcher(matcher) => write!(f, "{}", matcher),
+            LabelFilter::Comparison { label, op, value } => write!(f, "{} {} {}", label, op, value),
+            LabelFilter::Ip { label, negated, cidr } => write!(f, "{} {} ip({})", label, if *negated { "!=" } else { "=" }, quote(cidr)),
+            LabelFilter::And(lhs, rhs) => write!(f, "{} and {}", lhs, rhs),
+            LabelFilter::Or(lhs, rhs) => write!(f, "{} or {}", lhs, rhs),
+            LabelFilter::Parens(label_filter) => write!(f, "({})", label_filter),
+        }
+    }
+}
+
+impl Display for Conversion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", match self {
+            Conversion::Duration => "duration",
+            Conversion::DurationSeconds => "duration_seconds",
+            Conversion::Bytes => "bytes",
+        })
+    }
+}
+
+impl Display for Unwrap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self.conversion {
+            Some(conversion) => write!(f, "| unwrap {}({})", conversion, self.label)?,
+            None => write!(f, "| unwrap {}", self.label)?,
+        }
+        for filter in &self.filters {
+            write!(f, " | {}", filter)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for LogRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write_selector(f, &self.selector)?;
+        write_pipeline(f, &self.pipeline)?;
+        if let Some(unwrap) = &self.unwrap {
+            write!(f, " {}", unwrap)?;
+        }
+        if !self.pipeline.is_empty() || self.unwrap.is_some() {
+            write!(f, " ")?;
+        }
+        write!(f, "[{}]", self.range)?;
+        if let Some(offset) = &self.offset {
+            write!(f, " offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Grouping {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{} ", if self.without { "without" } else { "by" })?;
+        write_labels(f, &self.labels)
+    }
+}
+
+impl Display for RangeOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", match self {
+            RangeOp::CountOverTime => "count_over_time",
+            RangeOp::Rate => "rate",
+            RangeOp::RateCounter => "rate_counter",
+            RangeOp::BytesOverTime => "bytes_over_time",
+            RangeOp::BytesRate => "bytes_rate",
+            RangeOp::AvgOverTime => "avg_over_time",
+            RangeOp::SumOverTime => "sum_over_time",
+            RangeOp::MinOverTime => "min_over_time",
+            RangeOp::MaxOverTime => "max_over_time",
+            RangeOp::StdvarOverTime => "stdvar_over_time",
+            RangeOp::StddevOverTime => "stddev_over_time",
+            RangeOp::QuantileOverTime => "quantile_over_time",
+            RangeOp::FirstOverTime => "first_over_time",
+            RangeOp::LastOverTime => "last_over_time",
+            RangeOp::AbsentOverTime => "absent_over_time",
+        })
+    }
+}
+
+impl Display for VectorOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", match self {
+            VectorOp::Sum => "sum",
+            VectorOp::Avg => "avg",
+            VectorOp::Count => "count",
+            VectorOp::Min => "min",
+            VectorOp::Max => "max",
+            VectorOp::Stddev => "stddev",
+            VectorOp::Stdvar => "stdvar",
+            VectorOp::Bottomk => "bottomk",
+            VectorOp::Topk => "topk",
+            VectorOp::ApproxTopk => "approx_topk",
+            VectorOp::Sort => "sort",
+            VectorOp::SortDesc => "sort_desc",
+        })
+    }
+}
+
+impl Display for BinaryOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", match self {
+            BinaryOp::Or => "or",
+            BinaryOp::And => "and",
+            BinaryOp::Unless => "unless",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::GreaterThan => ">",
+            BinaryOp::GreaterThanOrEqual => ">=",
+            BinaryOp::LessThan => "<",
+            BinaryOp::LessThanOrEqual => "<=",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Pow => "^",
+        })
+    }
+}
+
+impl Display for BinaryModifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if self.return_bool {
+            write!(f, " bool")?;
+        }
+        if let Some(matching) = &self.matching {
+            write!(f, " {} ", if matching.ignoring { "ignoring" } else { "on" })?;
+            write_labels(f, &matching.labels)?;
+            if let Some((right, labels)) = &matching.group {
+                write!(f, " {} ", if *right { "group_right" } else { "group_left" })?;
+                write_labels(f, labels)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for MetricExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            MetricExpr::RangeAggregation { op, parameter, range, grouping } => {
+                write!(f, "{}(", op)?;
+                if let Some(parameter) = parameter {
+                    write!(f, "{}, ", parameter)?;
+                }
+                write!(f, "{})", range)?;
+                if let Some(grouping) = grouping {
+                    write!(f, " {}", grouping)?;
+                }
+                Ok(())
+            }
+            MetricExpr::VectorAggregation { op, parameter, expr, grouping } => {
+                write!(f, "{}", op)?;
+                if let Some(grouping) = grouping {
+                    write!(f, " {} ", grouping)?;
+                }
+                write!(f, "(")?;
+                if let Some(parameter) = parameter {
+                    write!(f, "{}, ", parameter)?;
+                }
+                write!(f, "{})", expr)
+            }
+            MetricExpr::Binary { op, modifier, lhs, rhs } => {
+                write!(f, "{} {}", lhs, op)?;
+                if let Some(modifier) = modifier {
+                    write!(f, "{}", modifier)?;
+                }
+                write!(f, " {}", rhs)
+            }
+            MetricExpr::LabelReplace { expr, destination, replacement, source, regex } => {
+                write!(f, "label_replace({}, {}, {}, {}, {})", expr, quote(destination), quote(replacement), quote(source), quote(regex))
+            }
+            MetricExpr::Literal(value) => write!(f, "{}", value),
+            MetricExpr::Vector(value) => write!(f, "vector({})", value),
+            MetricExpr::Parens(expr) => write!(f, "({})", expr),
+        }
+    }
+}
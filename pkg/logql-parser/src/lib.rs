@@ -0,0 +1,627 @@
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+
+pub mod ast;
+
+use pest::iterators::{Pair, Pairs};
+use pest::pratt_parser::{Assoc, Op, PrattParser};
+use pest::Parser;
+use thiserror::Error;
+use crate::ast::*;
+
+#[derive(Parser)]
+#[grammar = "logql_grammar.pest"]
+struct LogQLParser;
+
+/// Error returned when a query is not valid LogQL, positions start at 1
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("parse error at line {line}, col {column}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl From<pest::error::Error<Rule>> for ParseError {
+    fn from(error: pest::error::Error<Rule>) -> Self {
+        let (line, column) = match error.line_col {
+            pest::error::LineColLocation::Pos(position) => position,
+            pest::error::LineColLocation::Span(start, _) => start,
+        };
+        ParseError {
+            line,
+            column,
+            message: error.variant.message().to_string(),
+        }
+    }
+}
+
+impl ParseError {
+    fn invalid(pair: &Pair<Rule>, message: String) -> Self {
+        let (line, column) = pair.as_span().start_pos().line_col();
+        ParseError { line, column, message }
+    }
+}
+
+/// Parse a LogQL query
+/// "{app=\"foo\"} |= \"error\"" -> Expr::Log
+/// "sum by (app) (rate({app=\"foo\"}[5m]))" -> Expr::Metric
+pub fn parse(query: &str) -> Result<Expr, ParseError> {
+    let query = LogQLParser::parse(Rule::query, query)?.next().unwrap();
+    let expr = query.into_inner().next().unwrap();
+    match expr.as_rule() {
+        Rule::metric_expr => Ok(Expr::Metric(build_metric_expr(expr)?)),
+        _ => Ok(Expr::Log(build_log_expr(expr)?)),
+    }
+}
+
+/// Unquote a LogQL string, double quoted strings support Go escape sequences while backquoted strings are raw
+fn build_string(pair: Pair<Rule>) -> Result<String, ParseError> {
+    let inner = pair.into_inner().next().unwrap();
+    if inner.as_rule() == Rule::raw_string {
+        return Ok(inner.as_str().to_string());
+    }
+    let mut string = String::with_capacity(inner.as_str().len());
+    let mut chars = inner.as_str().chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('r') => string.push('\r'),
+            Some('t') => string.push('\t'),
+            Some('a') => string.push('\u{07}'),
+            Some('b') => string.push('\u{08}'),
+            Some('f') => string.push('\u{0c}'),
+            Some('v') => string.push('\u{0b}'),
+            Some(c @ ('\\' | '"' | '\'')) => string.push(c),
+            Some(c @ ('x' | 'u' | 'U')) => {
+                let length = match c { 'x' => 2, 'u' => 4, _ => 8 };
+                let code: String = chars.by_ref().take(length).collect();
+                let decoded = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32);
+                match decoded {
+                    Some(decoded) if code.len() == length => string.push(decoded),
+                    _ => return Err(ParseError::invalid(&inner, format!("invalid escape sequence \\{}{}", c, code))),
+                }
+            }
+            Some(c) => return Err(ParseError::invalid(&inner, format!("invalid escape sequence \\{}", c))),
+            None => return Err(ParseError::invalid(&inner, "unterminated escape sequence".to_string())),
+        }
+    }
+    Ok(string)
+}
+
+fn build_number(pair: Pair<Rule>) -> Result<f64, ParseError> {
+    pair.as_str().parse::<f64>().map_err(|e| ParseError::invalid(&pair, format!("invalid number {}: {}", pair.as_str(), e)))
+}
+
+fn build_label_list(pair: Pair<Rule>) -> Vec<String> {
+    pair.into_inner().map(|label| label.as_str().to_string()).collect()
+}
+
+fn build_matcher(pair: Pair<Rule>) -> Result<LabelMatcher, ParseError> {
+    let mut inner = pair.into_inner();
+    let name = inner.next().unwrap().as_str().to_string();
+    let op = match inner.next().unwrap().as_str() {
+        "=" => MatchOp::Equal,
+        "!=" => MatchOp::NotEqual,
+        "=~" => MatchOp::RegexMatch,
+        _ => MatchOp::RegexNotMatch,
+    };
+    let value = build_string(inner.next().unwrap())?;
+    Ok(LabelMatcher { name, op, value })
+}
+
+fn build_selector(pair: Pair<Rule>) -> Result<Vec<LabelMatcher>, ParseError> {
+    pair.into_inner().map(build_matcher).collect()
+}
+
+/// Parentheses around a log query are dropped
+fn build_log_expr(pair: Pair<Rule>) -> Result<LogExpr, ParseError> {
+    let mut inner = pair.into_inner();
+    let first = inner.next().unwrap();
+    if first.as_rule() == Rule::log_expr {
+        return build_log_expr(first);
+    }
+    let selector = build_selector(first)?;
+    let pipeline = build_pipeline(inner.next().unwrap())?;
+    Ok(LogExpr { selector, pipeline })
+}
+
+fn build_ip(pair: Pair<Rule>) -> Result<String, ParseError> {
+    build_string(pair.into_inner().nth(1).unwrap())
+}
+
+fn build_pipeline(pair: Pair<Rule>) -> Result<Vec<Stage>, ParseError> {
+    pair.into_inner().map(build_stage).collect()
+}
+
+fn build_stage(pair: Pair<Rule>) -> Result<Stage, ParseError> {
+    match pair.as_rule() {
+        Rule::line_filter => {
+            let mut inner = pair.into_inner();
+            let op = match inner.next().unwrap().as_str() {
+                "|=" => LineFilterOp::Contains,
+                "!=" => LineFilterOp::NotContains,
+                "|~" => LineFilterOp::Match,
+                "!~" => LineFilterOp::NotMatch,
+                "|>" => LineFilterOp::Pattern,
+                _ => LineFilterOp::NotPattern,
+            };
+            let values = inner.filter(|value| value.as_rule() != Rule::or_keyword).map(|value| match value.as_rule() {
+                Rule::ip => Ok(LineFilterValue::Ip(build_ip(value)?)),
+                _ => Ok(LineFilterValue::String(build_string(value)?)),
+            }).collect::<Result<Vec<LineFilterValue>, ParseError>>()?;
+            Ok(Stage::LineFilter { op, values })
+        }
+        Rule::json_parser => Ok(Stage::Json(pair.into_inner().skip(1).map(build_label_extraction).collect::<Result<Vec<LabelExtraction>, ParseError>>()?)),
+        Rule::logfmt_parser => {
+            let (mut strict, mut keep_empty, mut extractions) = (false, false, vec![]);
+            for part in pair.into_inner().skip(1) {
+                match part.as_str() {
+                    "--strict" => strict = true,
+                    "--keep-empty" => keep_empty = true,
+                    _ => extractions.push(build_label_extraction(part)?),
+                }
+            }
+            Ok(Stage::Logfmt { strict, keep_empty, extractions })
+        }
+        Rule::regexp_parser => Ok(Stage::Regexp(build_string(pair.into_inner().nth(1).unwrap())?)),
+        Rule::pattern_parser => Ok(Stage::Pattern(build_string(pair.into_inner().nth(1).unwrap())?)),
+        Rule::unpack_parser => Ok(Stage::Unpack),
+        Rule::line_format => Ok(Stage::LineFormat(build_string(pair.into_inner().nth(1).unwrap())?)),
+        Rule::label_format => {
+            let operations = pair.into_inner().skip(1).map(|operation| {
+                let mut inner = operation.into_inner();
+                let label = inner.next().unwrap().as_str().to_string();
+                let value = inner.next().unwrap();
+                let value = match value.as_rule() {
+                    Rule::string => LabelFormatValue::Template(build_string(value)?),
+                    _ => LabelFormatValue::Rename(value.as_str().to_string()),
+                };
+                Ok(LabelFormatOperation { label, value })
+            }).collect::<Result<Vec<LabelFormatOperation>, ParseError>>()?;
+            Ok(Stage::LabelFormat(operations))
+        }
+        Rule::drop_stage => Ok(Stage::Drop(build_label_selections(pair)?)),
+        Rule::keep_stage => Ok(Stage::Keep(build_label_selections(pair)?)),
+        Rule::decolorize => Ok(Stage::Decolorize),
+        Rule::distinct => Ok(Stage::Distinct(pair.into_inner().skip(1).map(|label| label.as_str().to_string()).collect())),
+        _ => Ok(Stage::LabelFilter(build_label_filter(pair)?)),
+    }
+}
+
+fn build_label_extraction(pair: Pair<Rule>) -> Result<LabelExtraction, ParseError> {
+    let mut inner = pair.into_inner();
+    let label = inner.next().unwrap().as_str().to_string();
+    let expression = inner.next().map(build_string).transpose()?;
+    Ok(LabelExtraction { label, expression })
+}
+
+fn build_label_selections(pair: Pair<Rule>) -> Result<Vec<LabelSelection>, ParseError> {
+    pair.into_inner().skip(1).map(|selection| match selection.as_rule() {
+        Rule::matcher => Ok(LabelSelection::Matcher(build_matcher(selection)?)),
+        _ => Ok(LabelSelection::Name(selection.as_str().to_string())),
+    }).collect()
+}
+
+fn build_label_filter_term(pair: Pair<Rule>) -> Result<LabelFilter, ParseError> {
+    match pair.as_rule() {
+        Rule::label_filter_parens => Ok(LabelFilter::Parens(Box::new(build_label_filter(pair.into_inner().next().unwrap())?))),
+        Rule::ip_label_filter => {
+            let mut inner = pair.into_inner();
+            let label = inner.next().unwrap().as_str().to_string();
+            let negated = inner.next().unwrap().as_str() == "!=";
+            let cidr = build_ip(inner.next().unwrap())?;
+            Ok(LabelFilter::Ip { label, negated, cidr })
+        }
+        Rule::comparison_label_filter => {
+            let mut inner = pair.into_inner();
+            let label = inner.next().unwrap().as_str().to_string();
+            let op = match inner.next().unwrap().as_str() {
+                "==" | "=" => ComparisonOp::Equal,
+                "!=" => ComparisonOp::NotEqual,
+                ">" => ComparisonOp::GreaterThan,
+                ">=" => ComparisonOp::GreaterThanOrEqual,
+                "<" => ComparisonOp::LessThan,
+                _ => ComparisonOp::LessThanOrEqual,
+            };
+            let value = inner.next().unwrap();
+            let value = match value.as_rule() {
+                Rule::bytes => ComparisonValue::Bytes(value.as_str().to_string()),
+                Rule::duration => ComparisonValue::Duration(value.as_str().to_string()),
+                _ => ComparisonValue::Number(build_number(value)?),
+            };
+            Ok(LabelFilter::Comparison { label, op, value })
+        }
+        _ => Ok(LabelFilter::Matcher(build_matcher(pair)?)),
+    }
+}
+
+/// `and` (or `,`, or no operator at all) binds tighter than `or`
+fn build_label_filter(pair: Pair<Rule>) -> Result<LabelFilter, ParseError> {
+    let mut inner = pair.into_inner();
+    let mut disjunction: Vec<LabelFilter> = vec![];
+    let mut conjunction = build_label_filter_term(inner.next().unwrap())?;
+    let mut is_or = false;
+    for part in inner {
+        if part.as_rule() == Rule::label_filter_operator {
+            is_or = part.as_str() == "or";
+            continue;
+        }
+        let term = build_label_filter_term(part)?;
+        if is_or {
+            disjunction.push(conjunction);
+            conjunction = term;
+        } else {
+            conjunction = LabelFilter::And(Box::new(conjunction), Box::new(term));
+        }
+        is_or = false;
+    }
+    Ok(disjunction.into_iter().rev().fold(conjunction, |rhs, lhs| LabelFilter::Or(Box::new(lhs), Box::new(rhs))))
+}
+
+fn build_unwrap(pair: Pair<Rule>) -> Result<Unwrap, ParseError> {
+    let mut inner = pair.into_inner().skip(1);
+    let target = inner.next().unwrap();
+    let (label, conversion) = match target.as_rule() {
+        Rule::conversion => {
+            let mut conversion = target.into_inner();
+            let function = match conversion.next().unwrap().as_str() {
+                "duration" => Conversion::Duration,
+                "duration_seconds" => Conversion::DurationSeconds,
+                _ => Conversion::Bytes,
+            };
+            (conversion.next().unwrap().as_str().to_string(), Some(function))
+        }
+        _ => (target.as_str().to_string(), None),
+    };
+    let filters = inner.map(build_label_filter).collect::<Result<Vec<LabelFilter>, ParseError>>()?;
+    Ok(Unwrap { label, conversion, filters })
+}
+
+fn build_log_range(pair: Pair<Rule>) -> Result<LogRange, ParseError> {
+    let mut log_range = LogRange { selector: vec![], pipeline: vec![], unwrap: None, range: String::new(), offset: None };
+    for part in pair.into_inner() {
+        match part.as_rule() {
+            Rule::selector => log_range.selector = build_selector(part)?,
+            Rule::pipeline => log_range.pipeline = build_pipeline(part)?,
+            Rule::unwrap => log_range.unwrap = Some(build_unwrap(part)?),
+            Rule::range => log_range.range = part.into_inner().next().unwrap().as_str().to_string(),
+            _ => log_range.offset = Some(part.into_inner().nth(1).unwrap().as_str().to_string()),
+        }
+    }
+    Ok(log_range)
+}
+
+fn build_grouping(pair: Pair<Rule>) -> Grouping {
+    let mut inner = pair.into_inner();
+    let without = inner.next().unwrap().as_rule() == Rule::without_keyword;
+    Grouping { without, labels: build_label_list(inner.next().unwrap()) }
+}
+
+fn build_range_aggregation(pair: Pair<Rule>) -> Result<MetricExpr, ParseError> {
+    let mut inner = pair.into_inner();
+    let op = match inner.next().unwrap().as_str() {
+        "count_over_time" => RangeOp::CountOverTime,
+        "rate" => RangeOp::Rate,
+        "rate_counter" => RangeOp::RateCounter,
+        "bytes_over_time" => RangeOp::BytesOverTime,
+        "bytes_rate" => RangeOp::BytesRate,
+        "avg_over_time" => RangeOp::AvgOverTime,
+        "sum_over_time" => RangeOp::SumOverTime,
+        "min_over_time" => RangeOp::MinOverTime,
+        "max_over_time" => RangeOp::MaxOverTime,
+        "stdvar_over_time" => RangeOp::StdvarOverTime,
+        "stddev_over_time" => RangeOp::StddevOverTime,
+        "quantile_over_time" => RangeOp::QuantileOverTime,
+        "first_over_time" => RangeOp::FirstOverTime,
+        "last_over_time" => RangeOp::LastOverTime,
+        _ => RangeOp::AbsentOverTime,
+    };
+    let mut parameter = None;
+    let mut range = None;
+    let mut grouping = None;
+    for part in inner {
+        match part.as_rule() {
+            Rule::number => parameter = Some(build_number(part)?),
+            Rule::log_range => range = Some(build_log_range(part)?),
+            _ => grouping = Some(build_grouping(part)),
+        }
+    }
+    Ok(MetricExpr::RangeAggregation { op, parameter, range: range.unwrap(), grouping })
+}
+
+fn build_vector_aggregation(pair: Pair<Rule>) -> Result<MetricExpr, ParseError> {
+    let mut inner = pair.into_inner();
+    let op = match inner.next().unwrap().as_str() {
+        "sum" => VectorOp::Sum,
+        "avg" => VectorOp::Avg,
+        "count" => VectorOp::Count,
+        "min" => VectorOp::Min,
+        "max" => VectorOp::Max,
+        "stddev" => VectorOp::Stddev,
+        "stdvar" => VectorOp::Stdvar,
+        "bottomk" => VectorOp::Bottomk,
+        "topk" => VectorOp::Topk,
+        "approx_topk" => VectorOp::ApproxTopk,
+        "sort" => VectorOp::Sort,
+        _ => VectorOp::SortDesc,
+    };
+    let mut parameter = None;
+    let mut expr = None;
+    let mut grouping = None;
+    for part in inner {
+        match part.as_rule() {
+            Rule::number => parameter = Some(build_number(part)?),
+            Rule::metric_expr => expr = Some(build_metric_expr(part)?),
+            _ => grouping = Some(build_grouping(part)),
+        }
+    }
+    Ok(MetricExpr::VectorAggregation { op, parameter, expr: Box::new(expr.unwrap()), grouping })
+}
+
+fn build_binary_modifier(pair: Pair<Rule>) -> BinaryModifier {
+    let mut modifier = BinaryModifier { return_bool: false, matching: None };
+    for part in pair.into_inner() {
+        match part.as_rule() {
+            Rule::bool_keyword => modifier.return_bool = true,
+            _ => {
+                let mut inner = part.into_inner();
+                let ignoring = inner.next().unwrap().as_rule() == Rule::ignoring_keyword;
+                let labels = build_label_list(inner.next().unwrap());
+                let group = inner.next().map(|group_modifier| {
+                    (group_modifier.as_str() == "group_right", inner.next().map(build_label_list).unwrap_or_default())
+                });
+                modifier.matching = Some(VectorMatching { ignoring, labels, group });
+            }
+        }
+    }
+    modifier
+}
+
+fn build_metric_term(pair: Pair<Rule>) -> Result<MetricExpr, ParseError> {
+    match pair.as_rule() {
+        Rule::metric_parens => Ok(MetricExpr::Parens(Box::new(build_metric_expr(pair.into_inner().next().unwrap())?))),
+        Rule::range_aggregation => build_range_aggregation(pair),
+        Rule::vector_aggregation => build_vector_aggregation(pair),
+        Rule::label_replace => {
+            let mut inner = pair.into_inner().skip(1);
+            let expr = build_metric_expr(inner.next().unwrap())?;
+            let mut strings = inner.map(build_string).collect::<Result<Vec<String>, ParseError>>()?.into_iter();
+            Ok(MetricExpr::LabelReplace {
+                expr: Box::new(expr),
+                destination: strings.next().unwrap(),
+                replacement: strings.next().unwrap(),
+                source: strings.next().unwrap(),
+                regex: strings.next().unwrap(),
+            })
+        }
+        Rule::vector => Ok(MetricExpr::Vector(build_number(pair.into_inner().nth(1).unwrap())?)),
+        _ => Ok(MetricExpr::Literal(build_number(pair.into_inner().next().unwrap())?)),
+    }
+}
+
+/// Operators precedence, from the lowest to the highest, as in PromQL
+fn binary_op_parser() -> PrattParser<Rule> {
+    PrattParser::new()
+        .op(Op::infix(Rule::or_op, Assoc::Left))
+        .op(Op::infix(Rule::and_op, Assoc::Left) | Op::infix(Rule::unless_op, Assoc::Left))
+        .op(Op::infix(Rule::eq_op, Assoc::Left) | Op::infix(Rule::neq_op, Assoc::Left)
+            | Op::infix(Rule::gte_op, Assoc::Left) | Op::infix(Rule::gt_op, Assoc::Left)
+            | Op::infix(Rule::lte_op, Assoc::Left) | Op::infix(Rule::lt_op, Assoc::Left))
+        .op(Op::infix(Rule::add_op, Assoc::Left) | Op::infix(Rule::sub_op, Assoc::Left))
+        .op(Op::infix(Rule::mul_op, Assoc::Left) | Op::infix(Rule::div_op, Assoc::Left) | Op::infix(Rule::mod_op, Assoc::Left))
+        .op(Op::infix(Rule::pow_op, Assoc::Right))
+}
+
+fn build_metric_expr(pair: Pair<Rule>) -> Result<MetricExpr, ParseError> {
+    build_binary_expr(pair.into_inner(), &binary_op_parser())
+}
+
+fn build_binary_expr(pairs: Pairs<Rule>, parser: &PrattParser<Rule>) -> Result<MetricExpr, ParseError> {
+    parser
+        .map_primary(build_metric_term)
+        .map_infix(|lhs, op, rhs| {
+            let binary_op = match op.as_rule() {
+                Rule::or_op => BinaryOp::Or,
+                Rule::and_op => BinaryOp::And,
+                Rule::unless_op => BinaryOp::Unless,
+                Rule::eq_op => BinaryOp::Equal,
+                Rule::neq_op => BinaryOp::NotEqual,
+                Rule::gte_op => BinaryOp::GreaterThanOrEqual,
+                Rule::gt_op => BinaryOp::GreaterThan,
+                Rule::lte_op => BinaryOp::LessThanOrEqual,
+                Rule::lt_op => BinaryOp::LessThan,
+                Rule::add_op => BinaryOp::Add,
+                Rule::sub_op => BinaryOp::Sub,
+                Rule::mul_op => BinaryOp::Mul,
+                Rule::div_op => BinaryOp::Div,
+                Rule::mod_op => BinaryOp::Mod,
+                _ => BinaryOp::Pow,
+            };
+            let modifier = op.into_inner().find(|part| part.as_rule() == Rule::binary_modifier).map(build_binary_modifier);
+            Ok(MetricExpr::Binary { op: binary_op, modifier, lhs: Box::new(lhs?), rhs: Box::new(rhs?) })
+        })
+        .parse(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trip(query: &str) {
+        let expr = parse(query).unwrap();
+        assert_eq!(expr.to_string(), query);
+        assert_eq!(parse(&expr.to_string()).unwrap(), expr);
+    }
+
+    fn assert_canonical(query: &str, canonical: &str) {
+        let expr = parse(query).unwrap();
+        assert_eq!(expr.to_string(), canonical);
+        assert_eq!(parse(canonical).unwrap(), expr);
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        let expr = parse("{app=\"foo\", env!=\"dev\", pod=~\"api-.*\", team!~\"a|b\"}").unwrap();
+        assert_eq!(expr, Expr::Log(LogExpr {
+            selector: vec![
+                LabelMatcher { name: "app".to_string(), op: MatchOp::Equal, value: "foo".to_string() },
+                LabelMatcher { name: "env".to_string(), op: MatchOp::NotEqual, value: "dev".to_string() },
+                LabelMatcher { name: "pod".to_string(), op: MatchOp::RegexMatch, value: "api-.*".to_string() },
+                LabelMatcher { name: "team".to_string(), op: MatchOp::RegexNotMatch, value: "a|b".to_string() },
+            ],
+            pipeline: vec![],
+        }));
+        assert!(!expr.is_metric());
+    }
+
+    #[test]
+    fn test_parse_strings() {
+        let expr = parse("{app=\"f\\\"o\\\\o\\n\"} |~ `\\d+`").unwrap();
+        assert_eq!(expr, Expr::Log(LogExpr {
+            selector: vec![LabelMatcher { name: "app".to_string(), op: MatchOp::Equal, value: "f\"o\\o\n".to_string() }],
+            pipeline: vec![Stage::LineFilter { op: LineFilterOp::Match, values: vec![LineFilterValue::String("\\d+".to_string())] }],
+        }));
+        assert_eq!(expr.to_string(), "{app=\"f\\\"o\\\\o\\n\"} |~ \"\\\\d+\"");
+    }
+
+    #[test]
+    fn test_round_trip_line_filters() {
+        assert_round_trip("{app=\"foo\"} |= \"error\" != \"timeout\" |~ \"5..\" !~ \"4..\" |= ip(\"192.168.0.0/16\")");
+        assert_round_trip("{app=\"foo\"} |> \"<_> error <_>\" !> \"<_> debug <_>\"");
+        assert_round_trip("{app=\"foo\"} |= \"error\" or \"fatal\" or ip(\"10.0.0.1\") != \"timeout\" or \"canceled\"");
+        assert_round_trip("count_over_time({app=\"foo\"} |~ \"5..\" or \"4..\" [5m]) or vector(0)");
+    }
+
+    #[test]
+    fn test_parse_parenthesized_log_queries() {
+        assert_canonical("(({app=\"foo\"} |= \"error\"))", "{app=\"foo\"} |= \"error\"");
+        assert!(!parse("({app=\"foo\"})").unwrap().is_metric());
+    }
+
+    #[test]
+    fn test_round_trip_label_selections() {
+        assert_round_trip("{app=\"foo\"} | logfmt | drop level, method=\"GET\", __error__=~\".+\"");
+        assert_round_trip("{app=\"foo\"} | logfmt | keep level, status!=\"200\"");
+        assert_round_trip("{app=\"foo\"} | decolorize | logfmt | distinct app, level");
+        assert!(parse("{app=\"foo\"} | drop").is_err());
+    }
+
+    #[test]
+    fn test_parse_comments() {
+        assert_canonical("{app=\"foo\"} # the app\n|= \"error\" # only errors", "{app=\"foo\"} |= \"error\"");
+        assert_canonical("sum(\n  # per app\n  rate({app=\"foo\"}[5m])\n)", "sum(rate({app=\"foo\"}[5m]))");
+        assert_round_trip("{app=\"foo\"} |= \"#hashtag\"");
+    }
+
+    #[test]
+    fn test_round_trip_parsers() {
+        assert_round_trip("{app=\"foo\"} | json");
+        assert_round_trip("{app=\"foo\"} | json first_server=\"servers[0]\", ua=\"request.headers[\\\"User-Agent\\\"]\"");
+        assert_round_trip("{app=\"foo\"} | logfmt | unpack");
+        assert_round_trip("{app=\"foo\"} | logfmt --strict --keep-empty level, msg=\"message\"");
+        assert_round_trip("{app=\"foo\"} | logfmt --keep-empty");
+        assert!(parse("{app=\"foo\"} | logfmt --lenient").is_err());
+        assert_round_trip("{app=\"foo\"} | regexp \"(?P<method>\\\\w+) (?P<path>[\\\\w|/]+)\"");
+        assert_round_trip("{app=\"foo\"} | pattern \"<ip> - - <_> \\\"<method> <uri> <_>\\\" <status>\"");
+    }
+
+    #[test]
+    fn test_round_trip_label_filters() {
+        assert_round_trip("{app=\"foo\"} | logfmt | level=\"error\" | duration >= 20ms or size > 20KB and status != 200");
+        assert_round_trip("{app=\"foo\"} | logfmt | (status == 500 or status == 503) and method=~\"GET|POST\"");
+        assert_round_trip("{app=\"foo\"} | logfmt | addr = ip(\"10.0.0.0/8\") | client != ip(\"10.0.0.1\")");
+        assert_canonical("{app=\"foo\"} | logfmt | level=\"error\", status=500", "{app=\"foo\"} | logfmt | level=\"error\" and status == 500");
+        assert_canonical("{app=\"foo\"} | logfmt | level=\"error\" status=500 or size > 1kb", "{app=\"foo\"} | logfmt | level=\"error\" and status == 500 or size > 1kb");
+        assert_round_trip("{app=\"foo\"} | logfmt | latency > 5m and size <= 5MiB and count > 5000");
+    }
+
+    #[test]
+    fn test_label_filters_precedence() {
+        let expr = parse("{app=\"foo\"} | a=\"1\" or b=\"2\" and c=\"3\"").unwrap();
+        let matcher = |name: &str, value: &str| Box::new(LabelFilter::Matcher(LabelMatcher { name: name.to_string(), op: MatchOp::Equal, value: value.to_string() }));
+        assert_eq!(expr, Expr::Log(LogExpr {
+            selector: vec![LabelMatcher { name: "app".to_string(), op: MatchOp::Equal, value: "foo".to_string() }],
+            pipeline: vec![Stage::LabelFilter(LabelFilter::Or(matcher("a", "1"), Box::new(LabelFilter::And(matcher("b", "2"), matcher("c", "3")))))],
+        }));
+    }
+
+    #[test]
+    fn test_round_trip_formats() {
+        assert_round_trip("{app=\"foo\"} | logfmt | line_format \"{{.level}}: {{.msg}}\" | label_format dst=src, level=\"{{ToUpper .level}}\"");
+    }
+
+    #[test]
+    fn test_round_trip_range_aggregations() {
+        assert_round_trip("count_over_time({app=\"foo\"} |= \"error\" [5m])");
+        assert_round_trip("rate({app=\"foo\"}[1m] offset 1h)");
+        assert_round_trip("quantile_over_time(0.99, {app=\"foo\"} | json | unwrap duration(latency) | __error__=\"\" [5m]) by (app)");
+        assert_round_trip("sum_over_time({app=\"foo\"} | logfmt | unwrap bytes [1h30m])");
+        assert_canonical("count_over_time({app=\"foo\"}[5m] |= \"error\")", "count_over_time({app=\"foo\"} |= \"error\" [5m])");
+        assert_canonical("count_over_time(({app=\"foo\"} |= \"error\")[5m])", "count_over_time({app=\"foo\"} |= \"error\" [5m])");
+    }
+
+    #[test]
+    fn test_round_trip_vector_aggregations() {
+        assert_round_trip("sum by (app) (rate({app=\"foo\"}[5m]))");
+        assert_round_trip("topk(10, sum without (pod) (rate({app=\"foo\"}[5m])))");
+        assert_round_trip("bottomk by (app) (3, count_over_time({app=\"foo\"}[5m]))");
+        assert_round_trip("approx_topk(10, sum by (app) (rate({app=\"foo\"}[5m])))");
+        assert_canonical("sum(rate({app=\"foo\"}[5m])) by (app)", "sum by (app) (rate({app=\"foo\"}[5m]))");
+    }
+
+    #[test]
+    fn test_round_trip_binary_operations() {
+        assert_round_trip("sum(rate({app=\"foo\"} |= \"error\" [5m])) / sum(rate({app=\"foo\"}[5m])) * 100");
+        assert_round_trip("rate({app=\"foo\"}[5m]) > bool 10");
+        assert_round_trip("sum by (app) (rate({app=\"foo\"}[5m])) / on (app) group_left (team) sum by (app, team) (rate({app=\"bar\"}[5m]))");
+        assert_round_trip("(1 + 2) * vector(3) ^ 2 ^ -1");
+        assert_canonical("+1 - .5 * 2.", "1 - 0.5 * 2");
+        assert_round_trip("label_replace(rate({app=\"foo\"}[5m]), \"dst\", \"$1\", \"app\", \"(.*)\")");
+    }
+
+    #[test]
+    fn test_binary_operations_precedence() {
+        let expr = parse("1 + 2 * 3 ^ 2 ^ 1 > 4 or 5").unwrap();
+        let literal = |value: f64| Box::new(MetricExpr::Literal(value));
+        let binary = |op: BinaryOp, lhs: Box<MetricExpr>, rhs: Box<MetricExpr>| Box::new(MetricExpr::Binary { op, modifier: None, lhs, rhs });
+        assert_eq!(expr, Expr::Metric(*binary(
+            BinaryOp::Or,
+            binary(
+                BinaryOp::GreaterThan,
+                binary(BinaryOp::Add, literal(1.0), binary(BinaryOp::Mul, literal(2.0), binary(BinaryOp::Pow, literal(3.0), binary(BinaryOp::Pow, literal(2.0), literal(1.0))))),
+                literal(4.0),
+            ),
+            literal(5.0),
+        )));
+    }
+
+    #[test]
+    fn test_selectors() {
+        let expr = parse("sum(rate({app=\"foo\"}[5m])) / sum(rate({app=\"bar\"}[5m]))").unwrap();
+        assert!(expr.is_metric());
+        assert_eq!(expr.selectors().iter().map(|selector| selector[0].value.as_str()).collect::<Vec<&str>>(), vec!["foo", "bar"]);
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let error = parse("sum(rate({app=\"foo\"}))").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 21));
+        assert!(error.to_string().starts_with("parse error at line 1, col 21: "));
+    }
+
+    #[test]
+    fn test_parse_error_on_empty_selector() {
+        assert!(parse("{}").is_err());
+    }
+
+    #[test]
+    fn test_parse_error_on_invalid_escape_sequence() {
+        assert_eq!(parse("{app=\"\\q\"}").unwrap_err().to_string(), "parse error at line 1, col 7: invalid escape sequence \\q");
+    }
+}
@@ -7,24 +7,54 @@ use anyhow::Error;
 use log::error;
 use pest::Parser;
 
+pub mod matcher;
+
+pub use matcher::{MatchType, Matcher, parse_selector};
+
 #[derive(Parser)]
 #[grammar = "label_grammar.pest"]
 struct LabelsParser;
 
 
+/// Unescape a label value, `\"`, `\\` and `\n` are the only escape sequences of the text format,
+/// the backslash of any other sequence is kept as is
+pub(crate) fn unescape_label_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c @ ('\\' | '"')) => unescaped.push(c),
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+pub(crate) fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
 /// Parse prometheus labels
 /// "" -> []
 /// "{}" -> []
 /// "{a=\"b\"}" -> [("a", "b")]
 /// "{a=\"b\", c=\"d\"}" -> [("a", "b"), ("c", "d")]
+/// "{a=\"\\\"b\\\"\"}" -> [("a", "\"b\"")]
 pub fn parse_labels(string: String) -> Result<Vec<(String, String)>, Error> {
-    if string.len() == 0 {
-        return Ok(vec![]);
-    }
-    if string == "{}".to_string() {
+    let string = string.trim();
+    if string.is_empty() || string == "{}" {
         return Ok(vec![]);
     }
-    let labels_parser = LabelsParser::parse(Rule::labels, &string);
+    let labels_parser = LabelsParser::parse(Rule::labels, string);
     match labels_parser {
         Ok(mut labels_parser) => {
             Ok(labels_parser
@@ -34,7 +64,7 @@ pub fn parse_labels(string: String) -> Result<Vec<(String, String)>, Error> {
                 .map(|pair| {
                     let mut iter = pair.into_inner();
                     let key = iter.next().unwrap().as_str().to_string();
-                    let value = iter.next().unwrap().as_str().to_string();
+                    let value = unescape_label_value(iter.next().unwrap().as_str());
                     (key, value)
                 })
                 .collect())
@@ -51,6 +81,18 @@ pub fn parse_labels_into_map(string: String) -> Result<HashMap<String, String>,
     Ok(labels.into_iter().collect())
 }
 
+/// Serialize labels into their canonical form, sorted by name, which can be used as a stream fingerprint
+/// [] -> "{}"
+/// [("c", "d"), ("a", "b")] -> "{a=\"b\", c=\"d\"}"
+pub fn labels_to_string(labels: &HashMap<String, String>) -> String {
+    let mut labels: Vec<(&String, &String)> = labels.iter().collect();
+    labels.sort();
+    let labels: Vec<String> = labels.into_iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+        .collect();
+    format!("{{{}}}", labels.join(", "))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -115,6 +157,70 @@ mod tests {
         assert_eq!(labels.to_string(), " --> 1:3\n  |\n1 | { ,baz=qux}\n  |   ^---\n  |\n  = expected labelKey".to_string());
     }
 
+    #[test]
+    fn test_parse_labels_with_digits_in_keys() {
+        let labels = parse_labels("{k8s_cluster=\"eu-west-1\", _1=\"a\"}".to_string()).unwrap();
+        assert_eq!(labels, vec![("k8s_cluster".to_string(), "eu-west-1".to_string()), ("_1".to_string(), "a".to_string())]);
+    }
+
+    #[test]
+    fn test_parse_labels_with_any_character_in_values() {
+        let labels = parse_labels("{path=\"/var/log/app.log\", addr=\"10.0.0.1:3100\", msg=\" hello, world \", city=\"Zürich\"}".to_string()).unwrap();
+        assert_eq!(labels, vec![
+            ("path".to_string(), "/var/log/app.log".to_string()),
+            ("addr".to_string(), "10.0.0.1:3100".to_string()),
+            ("msg".to_string(), " hello, world ".to_string()),
+            ("city".to_string(), "Zürich".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_labels_with_empty_value() {
+        let labels = parse_labels("{foo=\"\"}".to_string()).unwrap();
+        assert_eq!(labels, vec![("foo".to_string(), "".to_string())]);
+    }
+
+    #[test]
+    fn test_parse_labels_with_escape_sequences() {
+        let labels = parse_labels("{foo=\"a \\\"quoted\\\" \\\\ value\\n\"}".to_string()).unwrap();
+        assert_eq!(labels, vec![("foo".to_string(), "a \"quoted\" \\ value\n".to_string())]);
+    }
+
+    #[test]
+    fn test_parse_labels_with_trailing_comma() {
+        let labels = parse_labels("{foo=\"bar\", }".to_string()).unwrap();
+        assert_eq!(labels, vec![("foo".to_string(), "bar".to_string())]);
+    }
+
+    #[test]
+    fn test_parse_labels_throws_when_key_starts_with_digit() {
+        assert!(parse_labels("{1foo=\"bar\"}".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_labels_to_string_empty() {
+        assert_eq!(labels_to_string(&HashMap::new()), "{}".to_string());
+    }
+
+    #[test]
+    fn test_labels_to_string_sorted_and_escaped() {
+        let labels = HashMap::from([
+            ("foo".to_string(), "a \"quoted\" \\ value\n".to_string()),
+            ("baz".to_string(), "qux".to_string()),
+        ]);
+        assert_eq!(labels_to_string(&labels), "{baz=\"qux\", foo=\"a \\\"quoted\\\" \\\\ value\\n\"}".to_string());
+    }
+
+    #[test]
+    fn test_labels_to_string_round_trip() {
+        let labels = HashMap::from([
+            ("k8s_cluster".to_string(), "eu-west-1".to_string()),
+            ("msg".to_string(), "\"\\\n".to_string()),
+            ("empty".to_string(), "".to_string()),
+        ]);
+        assert_eq!(parse_labels_into_map(labels_to_string(&labels)).unwrap(), labels);
+    }
+
     #[test]
     fn test_parse_labels_into_map_one_label() {
         let labels = parse_labels_into_map("{foo=\"bar\"}".to_string()).unwrap();
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use anyhow::{anyhow, Error};
+use log::error;
+use pest::Parser;
+use regex::Regex;
+use crate::{escape_label_value, unescape_label_value, LabelsParser, Rule};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchType {
+    Equal,
+    NotEqual,
+    RegexMatch,
+    RegexNotMatch,
+}
+
+impl Display for MatchType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            MatchType::Equal => "=",
+            MatchType::NotEqual => "!=",
+            MatchType::RegexMatch => "=~",
+            MatchType::RegexNotMatch => "!~",
+        })
+    }
+}
+
+/// Label matcher of a stream selector, e.g. `app=~"api-.*"`
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub name: String,
+    pub match_type: MatchType,
+    pub value: String,
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    /// Compile a matcher, regexes are fully anchored as in Prometheus
+    pub fn new(match_type: MatchType, name: String, value: String) -> Result<Matcher, Error> {
+        let regex = match match_type {
+            MatchType::RegexMatch | MatchType::RegexNotMatch => Some(
+                Regex::new(&format!("^(?:{})$", value)).map_err(|e| anyhow!("Invalid regex {} for label {}: {}", value, name, e))?
+            ),
+            _ => None,
+        };
+        Ok(Matcher { name, match_type, value, regex })
+    }
+
+    /// Whether the value of the label matches
+    pub fn matches(&self, value: &str) -> bool {
+        match (self.match_type, &self.regex) {
+            (MatchType::Equal, _) => self.value == value,
+            (MatchType::NotEqual, _) => self.value != value,
+            (MatchType::RegexMatch, Some(regex)) => regex.is_match(value),
+            (MatchType::RegexNotMatch, Some(regex)) => !regex.is_match(value),
+            _ => false,
+        }
+    }
+
+    /// Whether a label set matches, a missing label is matched as an empty value
+    pub fn matches_labels(&self, labels: &HashMap<String, String>) -> bool {
+        self.matches(labels.get(&self.name).map_or("", |value| value.as_str()))
+    }
+
+    /// Whether a label set matches all the matchers of a selector
+    pub fn matches_all(matchers: &[Matcher], labels: &HashMap<String, String>) -> bool {
+        matchers.iter().all(|matcher| matcher.matches_labels(labels))
+    }
+}
+
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.match_type == other.match_type && self.value == other.value
+    }
+}
+
+impl Display for Matcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}\"{}\"", self.name, self.match_type, escape_label_value(&self.value))
+    }
+}
+
+/// Parse a stream selector into its compiled matchers
+/// "{}" -> []
+/// "{a=\"b\", c=~\"d.*\"}" -> [a="b", c=~"d.*"]
+pub fn parse_selector(string: &str) -> Result<Vec<Matcher>, Error> {
+    let selector = LabelsParser::parse(Rule::selector, string.trim()).map_err(|e| {
+        error!("{}", e);
+        Error::from(e)
+    })?;
+    selector.into_iter().next().unwrap().into_inner()
+        .map(|pair| {
+            let mut iter = pair.into_inner();
+            let name = iter.next().unwrap().as_str().to_string();
+            let match_type = match iter.next().unwrap().as_str() {
+                "=" => MatchType::Equal,
+                "!=" => MatchType::NotEqual,
+                "=~" => MatchType::RegexMatch,
+                _ => MatchType::RegexNotMatch,
+            };
+            let value = unescape_label_value(iter.next().unwrap().as_str());
+            Matcher::new(match_type, name, value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        let matchers = parse_selector("{env=\"prod\", app=~\"api-.*\", team!=\"x\", zone!~\"eu-.*\"}").unwrap();
+        assert_eq!(matchers.iter().map(|matcher| matcher.to_string()).collect::<Vec<String>>(), vec![
+            "env=\"prod\"", "app=~\"api-.*\"", "team!=\"x\"", "zone!~\"eu-.*\"",
+        ]);
+    }
+
+    #[test]
+    fn test_parse_empty_selector() {
+        assert_eq!(parse_selector("{}").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_parse_selector_throws_on_invalid_regex() {
+        assert!(parse_selector("{app=~\"api-(\"}").is_err());
+    }
+
+    #[test]
+    fn test_matches_equal() {
+        let matcher = Matcher::new(MatchType::Equal, "env".to_string(), "prod".to_string()).unwrap();
+        assert!(matcher.matches_labels(&labels(&[("env", "prod")])));
+        assert!(!matcher.matches_labels(&labels(&[("env", "dev")])));
+        assert!(!matcher.matches_labels(&labels(&[])));
+    }
+
+    #[test]
+    fn test_matches_not_equal() {
+        let matcher = Matcher::new(MatchType::NotEqual, "team".to_string(), "x".to_string()).unwrap();
+        assert!(!matcher.matches_labels(&labels(&[("team", "x")])));
+        assert!(matcher.matches_labels(&labels(&[("team", "y")])));
+        assert!(matcher.matches_labels(&labels(&[])));
+    }
+
+    #[test]
+    fn test_matches_anchored_regex() {
+        let matcher = Matcher::new(MatchType::RegexMatch, "app".to_string(), "api-.*|web".to_string()).unwrap();
+        assert!(matcher.matches("api-gateway"));
+        assert!(matcher.matches("web"));
+        assert!(!matcher.matches("my-api-gateway"));
+        assert!(!matcher.matches("webapp"));
+    }
+
+    #[test]
+    fn test_matches_missing_label_as_empty_value() {
+        let matcher = Matcher::new(MatchType::RegexMatch, "app".to_string(), ".*".to_string()).unwrap();
+        assert!(matcher.matches_labels(&labels(&[])));
+        let matcher = Matcher::new(MatchType::RegexNotMatch, "app".to_string(), ".+".to_string()).unwrap();
+        assert!(matcher.matches_labels(&labels(&[])));
+    }
+
+    #[test]
+    fn test_matches_all() {
+        let matchers = parse_selector("{env=\"prod\", app=~\"api-.*\", team!=\"x\"}").unwrap();
+        assert!(Matcher::matches_all(&matchers, &labels(&[("env", "prod"), ("app", "api-1"), ("team", "y")])));
+        assert!(!Matcher::matches_all(&matchers, &labels(&[("env", "prod"), ("app", "api-1"), ("team", "x")])));
+        assert!(!Matcher::matches_all(&matchers, &labels(&[("env", "prod"), ("app", "web")])));
+    }
+}